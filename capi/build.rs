@@ -0,0 +1,156 @@
+// Generate the C header from the declarations in src/lib.rs, into OUT_DIR.
+// include/z80emu.h is a committed copy for C builds, which tests/c_api.rs checks
+// against the generated header; copy it over after changing the interface.
+//
+// This understands only the forms used there, each on one line:
+//
+//   pub const NAME: type = value;
+//   pub type Name = extern "C" fn(args) -> type;
+//   pub struct Name
+//   pub [unsafe] extern "C" fn name(args) -> type
+//
+// A `//` comment immediately above an item is copied to the header.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const SOURCE: &str = "src/lib.rs";
+const HEADER: &str = "include/z80emu.h";
+
+fn main()
+{
+    println!("cargo:rerun-if-changed={}", SOURCE);
+    println!("cargo:rerun-if-changed={}", HEADER);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let source = fs::read_to_string(SOURCE).expect("Could not read the source");
+    let header = generate(&source);
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("No OUT_DIR")).join("z80emu.h");
+    fs::write(&out, &header).expect("Could not write the header");
+    if fs::read_to_string(HEADER).ok().as_ref() != Some(&header) {
+        println!("cargo:warning={} is out of date, copy {} over it", HEADER, out.display());
+    }
+}
+
+fn generate(source: &str) -> String
+{
+    let mut out = String::new();
+    out.push_str("// Generated from src/lib.rs by build.rs.  Do not edit.\n\n");
+    out.push_str("#ifndef Z80EMU_H\n#define Z80EMU_H\n\n");
+    out.push_str("#include <stdbool.h>\n#include <stddef.h>\n#include <stdint.h>\n\n");
+    out.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n");
+
+    let mut comment = vec![];
+    for line in source.lines() {
+        let line = line.trim();
+        if let Some(text) = line.strip_prefix("//") {
+            comment.push(text.to_string());
+            continue;
+        }
+        if line.starts_with("#[") {
+            continue;
+        }
+        let decl = declaration(line);
+        if let Some(ref decl) = decl {
+            if !comment.is_empty() || !is_define(decl) {
+                out.push('\n');
+            }
+            for text in &comment {
+                out.push_str(&format!("//{}\n", text));
+            }
+            out.push_str(decl);
+            out.push('\n');
+        }
+        if decl.is_some() || line.is_empty() || !line.starts_with("pub ") {
+            comment.clear();
+        }
+    }
+
+    out.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n#endif\n");
+    out
+}
+
+fn is_define(decl: &str) -> bool
+{
+    decl.starts_with("#define")
+}
+
+// The C declaration for a line of Rust, if it declares anything.
+
+fn declaration(line: &str) -> Option<String>
+{
+    if let Some(rest) = line.strip_prefix("pub const ") {
+        let (name, rest) = rest.split_at(rest.find(':')?);
+        let value = rest[rest.find('=')? + 1..].trim().trim_end_matches(';');
+        return Some(format!("#define {} {}", name, value));
+    }
+    if let Some(rest) = line.strip_prefix("pub type ") {
+        let (name, rest) = rest.split_at(rest.find('=')?);
+        let (args, ret) = signature(rest.strip_prefix("= extern \"C\" fn")?.trim_end_matches(';'));
+        return Some(format!("typedef {} (*{})({});", ret, name.trim(), args));
+    }
+    if let Some(name) = line.strip_prefix("pub struct ") {
+        return Some(format!("typedef struct {} {};", name, name));
+    }
+    let rest = line.strip_prefix("pub unsafe extern \"C\" fn ")
+        .or_else(|| line.strip_prefix("pub extern \"C\" fn "))?;
+    let (name, rest) = rest.split_at(rest.find('(')?);
+    let (args, ret) = signature(rest);
+    Some(format!("{}({});", declare(&ret, name), args))
+}
+
+// The C parameter list and return type for "(args) -> type".
+
+fn signature(sig: &str) -> (String, String)
+{
+    let sig = sig.trim();
+    let close = sig.rfind(')').expect("Bad signature");
+    let args = sig[1..close].split(", ")
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| {
+            let (name, ty) = arg.split_at(arg.find(':').expect("Bad parameter"));
+            declare(&c_type(&ty[1..]), name)
+        })
+        .collect::<Vec<_>>();
+    let ret = match sig[close + 1..].trim().strip_prefix("->") {
+        Some(ty) => c_type(ty),
+        None => "void".to_string()
+    };
+    (if args.is_empty() { "void".to_string() } else { args.join(", ") }, ret)
+}
+
+fn declare(ty: &str, name: &str) -> String
+{
+    if ty.ends_with('*') {
+        format!("{}{}", ty, name)
+    } else {
+        format!("{} {}", ty, name)
+    }
+}
+
+fn c_type(ty: &str) -> String
+{
+    let ty = ty.trim();
+    if let Some(t) = ty.strip_prefix("*mut ") {
+        return format!("{} *", c_type(t));
+    }
+    if let Some(t) = ty.strip_prefix("*const ") {
+        return format!("const {} *", c_type(t));
+    }
+    if let Some(t) = ty.strip_prefix("Option<") {
+        // Nullable function pointer
+        return c_type(t.trim_end_matches('>'));
+    }
+    match ty {
+        "u8" => "uint8_t",
+        "u16" => "uint16_t",
+        "u32" => "uint32_t",
+        "u64" => "uint64_t",
+        "usize" => "size_t",
+        "bool" => "bool",
+        "c_char" => "char",
+        "c_void" => "void",
+        _ => ty
+    }.to_string()
+}
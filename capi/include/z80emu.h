@@ -0,0 +1,118 @@
+// Generated from src/lib.rs by build.rs.  Do not edit.
+
+#ifndef Z80EMU_H
+#define Z80EMU_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+// Reasons for z80_run() to return.
+#define Z80_STOP_HALT 0
+#define Z80_STOP_BUDGET 1
+#define Z80_STOP_ILLEGAL 2
+#define Z80_STOP_TRAP 3
+#define Z80_STOP_ERROR 4
+
+// Register names for z80_get_reg() and z80_set_reg().  Pairs are 16 bits, the
+// rest 8 bits; IFF1 and IFF2 are 0 or 1.
+#define Z80_REG_PC 0
+#define Z80_REG_SP 1
+#define Z80_REG_IX 2
+#define Z80_REG_IY 3
+#define Z80_REG_AF 4
+#define Z80_REG_BC 5
+#define Z80_REG_DE 6
+#define Z80_REG_HL 7
+#define Z80_REG_AF_ALT 8
+#define Z80_REG_BC_ALT 9
+#define Z80_REG_DE_ALT 10
+#define Z80_REG_HL_ALT 11
+#define Z80_REG_I 12
+#define Z80_REG_R 13
+#define Z80_REG_IM 14
+#define Z80_REG_IFF1 15
+#define Z80_REG_IFF2 16
+
+// Returns the value for an IN from `port`.
+typedef uint8_t (*Z80InFn)(void *ctx, uint8_t port);
+
+// Receives the value of an OUT to `port`.
+typedef void (*Z80OutFn)(void *ctx, uint8_t port, uint8_t value);
+
+// An emulated machine; opaque to C.
+typedef struct Z80Machine Z80Machine;
+
+// Create a machine with zeroed memory and registers, starting at `pc`, and no
+// devices.
+Z80Machine *z80_create(uint16_t pc);
+
+// Destroy a machine.  A null pointer is ignored.
+void z80_destroy(Z80Machine *m);
+
+// Set the I/O callbacks, either of which may be null.  `ctx` is passed to them.
+void z80_set_io(Z80Machine *m, Z80InFn input, Z80OutFn output, void *ctx);
+
+// Attach the disk image in the file `filename` as drive `drive`, 0-15 for A:
+// to P:, replacing any disk there.  The geometry is as for the emulator's disk
+// controller: 1-255 heads, 1-65535 tracks and sectors per track, and sectors of
+// 128, 256, 512, or 1024 bytes.  With `read_only` the file is opened read-only
+// and the disk is write protected.  Returns false if the drive or the geometry
+// is bad or the file cannot be opened.
+bool z80_attach_disk(Z80Machine *m, uint8_t drive, const char *filename, uint8_t heads, uint16_t tracks, uint16_t sectors, size_t sector_size, bool read_only);
+
+// Attach a RAM disk as drive `drive`, as z80_attach_disk() does.  The disk
+// holds the `len` bytes at `image`, zero-filled past their end; `image` may be
+// null if `len` is 0.  Returns false if the drive or the geometry is bad or the
+// image is larger than the disk.
+bool z80_attach_ram_disk(Z80Machine *m, uint8_t drive, const uint8_t *image, size_t len, uint8_t heads, uint16_t tracks, uint16_t sectors, size_t sector_size);
+
+// Attach the block device image in the file `filename`, with `blocks` blocks of
+// 512 bytes, replacing any block device.  With `read_only` the file is opened
+// read-only and the device is write protected.  Returns false if there are no
+// blocks or the file cannot be opened.
+bool z80_attach_block_device(Z80Machine *m, const char *filename, uint32_t blocks, bool read_only);
+
+// Run until the CPU halts, executes an illegal instruction or a trap, or has
+// run at least `budget` T-states.  Returns one of the Z80_STOP_ values.  After
+// a trap, z80_trap_number() gives its number; run again to resume.
+uint32_t z80_run(Z80Machine *m, uint64_t budget);
+
+// Make the instruction ED `opcode` n a trap that stops z80_run() with
+// Z80_STOP_TRAP, or disable traps.  The opcode must be one the CPU does not
+// implement; ED FEh is a good choice.
+void z80_set_trap(Z80Machine *m, bool enable, uint8_t opcode);
+
+// The operand n of the trap that last stopped z80_run(), or 0 if it did not
+// stop for a trap.
+uint8_t z80_trap_number(const Z80Machine *m);
+
+// T-states executed since the machine was created.
+uint64_t z80_cycles(const Z80Machine *m);
+
+// Read a register, see the Z80_REG_ values.  Unknown registers read as 0.
+uint16_t z80_get_reg(const Z80Machine *m, uint32_t reg);
+
+// Write a register, see the Z80_REG_ values.  8-bit registers take the low
+// byte of `value`.  Unknown registers are ignored.
+void z80_set_reg(Z80Machine *m, uint32_t reg, uint16_t value);
+
+// Copy `len` bytes of memory starting at `addr` to `buf`; addresses wrap.
+void z80_read_mem(const Z80Machine *m, uint16_t addr, uint8_t *buf, size_t len);
+
+// Copy `len` bytes from `data` to memory starting at `addr`; addresses wrap.
+void z80_write_mem(Z80Machine *m, uint16_t addr, const uint8_t *data, size_t len);
+
+// Signal a maskable interrupt with `data` on the bus.  Returns true if the CPU
+// accepted it, ie interrupts were enabled.
+bool z80_interrupt(Z80Machine *m, uint8_t data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
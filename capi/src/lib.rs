@@ -0,0 +1,418 @@
+// C interface to the emulator, built as a shared library.
+//
+// A Z80Machine is a CPU and the devices of a machine::Machine.  Until a device
+// is attached its I/O goes to callbacks supplied by the embedder: IN calls the
+// input callback for the value to load into A, and OUT calls the output
+// callback.  Without callbacks, IN reads FFh and OUT is ignored.
+//
+// Once a disk, a RAM disk, or the block device is attached, z80_run() runs a
+// machine::Machine with the devices on the ports of its port map, see
+// src/machine.rs.  The console ports are the Machine's TTY, which is the
+// callbacks: CHAR_IN is an IN from port 00h, CHAR_AVAIL one from port 01h, and
+// CHAR_OUT an OUT to port 00h.  I/O to any other port, or to a drive with no
+// disk, is an error; it is reported on stderr and stops z80_run() with
+// Z80_STOP_ERROR.
+//
+// build.rs generates the header from this file into OUT_DIR.  include/z80emu.h
+// is a copy of it for C builds, which tests/c_api.rs checks is up to date; the
+// tests also build and run test/c_api_test.c against it.
+//
+// All functions taking a Z80Machine pointer require a pointer returned by
+// z80_create() and not yet passed to z80_destroy().  Buffers must be valid for
+// the given length, and file names must be null-terminated.
+
+#![allow(clippy::missing_safety_doc)]
+
+extern crate z80emu;
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use z80emu::devices::{ByteReader, ByteWriter, DeviceState, SpinningDisk, TTY, SECTOR_SIZES};
+use z80emu::disk_image::{self, DiskImage};
+use z80emu::file_backed_block_device::{self, FileBackedBlockDevice};
+use z80emu::machine::{self, DISKS};
+use z80emu::z80::{self, StopReason, Z80};
+use z80emu::{file_backed_spinning_disk, ram_disk};
+
+// Number of instructions between checks of the cycle budget.
+
+const TIMESLICE : usize = 1000;
+
+// Reasons for z80_run() to return.
+pub const Z80_STOP_HALT: u32 = 0;
+pub const Z80_STOP_BUDGET: u32 = 1;
+pub const Z80_STOP_ILLEGAL: u32 = 2;
+pub const Z80_STOP_TRAP: u32 = 3;
+pub const Z80_STOP_ERROR: u32 = 4;
+
+// Register names for z80_get_reg() and z80_set_reg().  Pairs are 16 bits, the
+// rest 8 bits; IFF1 and IFF2 are 0 or 1.
+pub const Z80_REG_PC: u32 = 0;
+pub const Z80_REG_SP: u32 = 1;
+pub const Z80_REG_IX: u32 = 2;
+pub const Z80_REG_IY: u32 = 3;
+pub const Z80_REG_AF: u32 = 4;
+pub const Z80_REG_BC: u32 = 5;
+pub const Z80_REG_DE: u32 = 6;
+pub const Z80_REG_HL: u32 = 7;
+pub const Z80_REG_AF_ALT: u32 = 8;
+pub const Z80_REG_BC_ALT: u32 = 9;
+pub const Z80_REG_DE_ALT: u32 = 10;
+pub const Z80_REG_HL_ALT: u32 = 11;
+pub const Z80_REG_I: u32 = 12;
+pub const Z80_REG_R: u32 = 13;
+pub const Z80_REG_IM: u32 = 14;
+pub const Z80_REG_IFF1: u32 = 15;
+pub const Z80_REG_IFF2: u32 = 16;
+
+// Returns the value for an IN from `port`.
+pub type Z80InFn = extern "C" fn(ctx: *mut c_void, port: u8) -> u8;
+
+// Receives the value of an OUT to `port`.
+pub type Z80OutFn = extern "C" fn(ctx: *mut c_void, port: u8, value: u8);
+
+// An emulated machine; opaque to C.
+pub struct Z80Machine
+{
+    cpu: Z80,
+    input: Option<Z80InFn>,
+    output: Option<Z80OutFn>,
+    ctx: *mut c_void,
+    disks: Vec<Option<Box<dyn SpinningDisk>>>,
+    selected_disk: usize,
+    block_device: Option<FileBackedBlockDevice>,
+}
+
+// The callbacks as the Machine's TTY.
+
+struct CallbackTty
+{
+    input: Option<Z80InFn>,
+    output: Option<Z80OutFn>,
+    ctx: *mut c_void,
+}
+
+impl ByteReader for CallbackTty {
+    fn poll_nonblocking(&mut self) -> u8 {
+        self.input.map_or(0xFF, |input| input(self.ctx, 0x01))
+    }
+
+    fn get_nonblocking(&mut self) -> u8 {
+        self.input.map_or(0xFF, |input| input(self.ctx, 0x00))
+    }
+}
+
+impl ByteWriter for CallbackTty {
+    fn put_nonblocking(&mut self, c: u8) {
+        if let Some(output) = self.output {
+            output(self.ctx, 0x00, c);
+        }
+    }
+}
+
+impl DeviceState for CallbackTty {}
+impl TTY for CallbackTty {}
+
+// Create a machine with zeroed memory and registers, starting at `pc`, and no
+// devices.
+#[no_mangle]
+pub extern "C" fn z80_create(pc: u16) -> *mut Z80Machine
+{
+    let m = Z80Machine {
+        cpu: z80::make(pc),
+        input: None,
+        output: None,
+        ctx: ptr::null_mut(),
+        disks: (0..DISKS).map(|_| None).collect(),
+        selected_disk: 0,
+        block_device: None,
+    };
+    Box::into_raw(Box::new(m))
+}
+
+// Destroy a machine.  A null pointer is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn z80_destroy(m: *mut Z80Machine)
+{
+    if !m.is_null() {
+        drop(Box::from_raw(m));
+    }
+}
+
+// Set the I/O callbacks, either of which may be null.  `ctx` is passed to them.
+#[no_mangle]
+pub unsafe extern "C" fn z80_set_io(m: *mut Z80Machine, input: Option<Z80InFn>, output: Option<Z80OutFn>, ctx: *mut c_void)
+{
+    let m = &mut *m;
+    m.input = input;
+    m.output = output;
+    m.ctx = ctx;
+}
+
+// Attach the disk image in the file `filename` as drive `drive`, 0-15 for A:
+// to P:, replacing any disk there.  The geometry is as for the emulator's disk
+// controller: 1-255 heads, 1-65535 tracks and sectors per track, and sectors of
+// 128, 256, 512, or 1024 bytes.  With `read_only` the file is opened read-only
+// and the disk is write protected.  Returns false if the drive or the geometry
+// is bad or the file cannot be opened.
+#[no_mangle]
+pub unsafe extern "C" fn z80_attach_disk(m: *mut Z80Machine, drive: u8, filename: *const c_char, heads: u8, tracks: u16, sectors: u16, sector_size: usize, read_only: bool) -> bool
+{
+    let m = &mut *m;
+    if !is_good_disk(drive, heads, tracks, sectors, sector_size) {
+        return false;
+    }
+    match open(filename, read_only) {
+        Some(image) => {
+            let dsk = file_backed_spinning_disk::make_from_image(image, heads, tracks, sectors, sector_size);
+            m.disks[drive as usize] = Some(Box::new(dsk));
+            true
+        }
+        None => false
+    }
+}
+
+// Attach a RAM disk as drive `drive`, as z80_attach_disk() does.  The disk
+// holds the `len` bytes at `image`, zero-filled past their end; `image` may be
+// null if `len` is 0.  Returns false if the drive or the geometry is bad or the
+// image is larger than the disk.
+#[no_mangle]
+pub unsafe extern "C" fn z80_attach_ram_disk(m: *mut Z80Machine, drive: u8, image: *const u8, len: usize, heads: u8, tracks: u16, sectors: u16, sector_size: usize) -> bool
+{
+    let m = &mut *m;
+    if !is_good_disk(drive, heads, tracks, sectors, sector_size) {
+        return false;
+    }
+    let size = heads as u64 * tracks as u64 * sectors as u64 * sector_size as u64;
+    if len as u64 > size {
+        return false;
+    }
+    let image = if len == 0 { &[][..] } else { slice::from_raw_parts(image, len) };
+    let dsk = ram_disk::make_from_image(image, heads, tracks, sectors, sector_size);
+    m.disks[drive as usize] = Some(Box::new(dsk));
+    true
+}
+
+// Attach the block device image in the file `filename`, with `blocks` blocks of
+// 512 bytes, replacing any block device.  With `read_only` the file is opened
+// read-only and the device is write protected.  Returns false if there are no
+// blocks or the file cannot be opened.
+#[no_mangle]
+pub unsafe extern "C" fn z80_attach_block_device(m: *mut Z80Machine, filename: *const c_char, blocks: u32, read_only: bool) -> bool
+{
+    if blocks == 0 {
+        return false;
+    }
+    match open(filename, read_only) {
+        Some(image) => {
+            (*m).block_device = Some(file_backed_block_device::make_from_image(image, blocks));
+            true
+        }
+        None => false
+    }
+}
+
+// Run until the CPU halts, executes an illegal instruction or a trap, or has
+// run at least `budget` T-states.  Returns one of the Z80_STOP_ values.  After
+// a trap, z80_trap_number() gives its number; run again to resume.
+#[no_mangle]
+pub unsafe extern "C" fn z80_run(m: *mut Z80Machine, budget: u64) -> u32
+{
+    let m = &mut *m;
+    let limit = m.cpu.cycles.saturating_add(budget);
+    if m.disks.iter().any(Option::is_some) || m.block_device.is_some() {
+        return run_machine(m, limit);
+    }
+    while m.cpu.cycles < limit {
+        z80::run(&mut m.cpu, TIMESLICE);
+        match m.cpu.stop_reason {
+            StopReason::Halt => { return Z80_STOP_HALT; }
+            StopReason::Illegal => { return Z80_STOP_ILLEGAL; }
+            StopReason::Trap(_) => { return Z80_STOP_TRAP; }
+            StopReason::Poll => {}
+            StopReason::ExecCheck => {
+                // Not available through this interface
+            }
+            StopReason::In => {
+                let port = m.cpu.port_addr;
+                m.cpu.a = match m.input {
+                    Some(input) => input(m.ctx, port),
+                    None => 0xFF
+                };
+            }
+            StopReason::Out => {
+                if let Some(output) = m.output {
+                    output(m.ctx, m.cpu.port_addr, m.cpu.a);
+                }
+            }
+        }
+    }
+    Z80_STOP_BUDGET
+}
+
+// Make the instruction ED `opcode` n a trap that stops z80_run() with
+// Z80_STOP_TRAP, or disable traps.  The opcode must be one the CPU does not
+// implement; ED FEh is a good choice.
+#[no_mangle]
+pub unsafe extern "C" fn z80_set_trap(m: *mut Z80Machine, enable: bool, opcode: u8)
+{
+    (*m).cpu.trap = if enable { Some(opcode) } else { None };
+}
+
+// The operand n of the trap that last stopped z80_run(), or 0 if it did not
+// stop for a trap.
+#[no_mangle]
+pub unsafe extern "C" fn z80_trap_number(m: *const Z80Machine) -> u8
+{
+    match (*m).cpu.stop_reason {
+        StopReason::Trap(n) => n,
+        _ => 0
+    }
+}
+
+// T-states executed since the machine was created.
+#[no_mangle]
+pub unsafe extern "C" fn z80_cycles(m: *const Z80Machine) -> u64
+{
+    (*m).cpu.cycles
+}
+
+// Read a register, see the Z80_REG_ values.  Unknown registers read as 0.
+#[no_mangle]
+pub unsafe extern "C" fn z80_get_reg(m: *const Z80Machine, reg: u32) -> u16
+{
+    let cpu = &(*m).cpu;
+    match reg {
+        Z80_REG_PC => cpu.pc,
+        Z80_REG_SP => cpu.sp,
+        Z80_REG_IX => cpu.ix,
+        Z80_REG_IY => cpu.iy,
+        Z80_REG_AF => pair(cpu.a, cpu.f),
+        Z80_REG_BC => pair(cpu.b, cpu.c),
+        Z80_REG_DE => pair(cpu.d, cpu.e),
+        Z80_REG_HL => pair(cpu.h, cpu.l),
+        Z80_REG_AF_ALT => pair(cpu.a_alt, cpu.f_alt),
+        Z80_REG_BC_ALT => pair(cpu.b_alt, cpu.c_alt),
+        Z80_REG_DE_ALT => pair(cpu.d_alt, cpu.e_alt),
+        Z80_REG_HL_ALT => pair(cpu.h_alt, cpu.l_alt),
+        Z80_REG_I => cpu.i as u16,
+        Z80_REG_R => cpu.r as u16,
+        Z80_REG_IM => cpu.im as u16,
+        Z80_REG_IFF1 => cpu.iff1 as u16,
+        Z80_REG_IFF2 => cpu.iff2 as u16,
+        _ => 0
+    }
+}
+
+// Write a register, see the Z80_REG_ values.  8-bit registers take the low
+// byte of `value`.  Unknown registers are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn z80_set_reg(m: *mut Z80Machine, reg: u32, value: u16)
+{
+    let cpu = &mut (*m).cpu;
+    let (hi, lo) = ((value >> 8) as u8, value as u8);
+    match reg {
+        Z80_REG_PC => { cpu.pc = value; }
+        Z80_REG_SP => { cpu.sp = value; }
+        Z80_REG_IX => { cpu.ix = value; }
+        Z80_REG_IY => { cpu.iy = value; }
+        Z80_REG_AF => { cpu.a = hi; cpu.f = lo; }
+        Z80_REG_BC => { cpu.b = hi; cpu.c = lo; }
+        Z80_REG_DE => { cpu.d = hi; cpu.e = lo; }
+        Z80_REG_HL => { cpu.h = hi; cpu.l = lo; }
+        Z80_REG_AF_ALT => { cpu.a_alt = hi; cpu.f_alt = lo; }
+        Z80_REG_BC_ALT => { cpu.b_alt = hi; cpu.c_alt = lo; }
+        Z80_REG_DE_ALT => { cpu.d_alt = hi; cpu.e_alt = lo; }
+        Z80_REG_HL_ALT => { cpu.h_alt = hi; cpu.l_alt = lo; }
+        Z80_REG_I => { cpu.i = lo; }
+        Z80_REG_R => { cpu.r = lo; }
+        Z80_REG_IM => { cpu.im = lo; }
+        Z80_REG_IFF1 => { cpu.iff1 = lo != 0; }
+        Z80_REG_IFF2 => { cpu.iff2 = lo != 0; }
+        _ => {}
+    }
+}
+
+// Copy `len` bytes of memory starting at `addr` to `buf`; addresses wrap.
+#[no_mangle]
+pub unsafe extern "C" fn z80_read_mem(m: *const Z80Machine, addr: u16, buf: *mut u8, len: usize)
+{
+    let mem = &(*m).cpu.mem;
+    let buf = slice::from_raw_parts_mut(buf, len);
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = mem[(addr as usize + i) & 0xFFFF];
+    }
+}
+
+// Copy `len` bytes from `data` to memory starting at `addr`; addresses wrap.
+#[no_mangle]
+pub unsafe extern "C" fn z80_write_mem(m: *mut Z80Machine, addr: u16, data: *const u8, len: usize)
+{
+    let mem = &mut (*m).cpu.mem;
+    let data = slice::from_raw_parts(data, len);
+    for (i, &b) in data.iter().enumerate() {
+        mem[(addr as usize + i) & 0xFFFF] = b;
+    }
+}
+
+// Signal a maskable interrupt with `data` on the bus.  Returns true if the CPU
+// accepted it, ie interrupts were enabled.
+#[no_mangle]
+pub unsafe extern "C" fn z80_interrupt(m: *mut Z80Machine, data: u8) -> bool
+{
+    z80::interrupt(&mut (*m).cpu, data)
+}
+
+// Run a machine::Machine on the attached devices until the CPU's cycle count
+// reaches `limit`, see z80_run().  A panic for a bad port must not unwind into
+// C, so it is caught here and is an error.
+
+fn run_machine(m: &mut Z80Machine, limit: u64) -> u32
+{
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut tty = CallbackTty { input: m.input, output: m.output, ctx: m.ctx };
+        let mut builder = machine::builder().tty(&mut tty).select_disk(m.selected_disk);
+        for (n, dsk) in m.disks.iter_mut().enumerate() {
+            if let Some(ref mut dsk) = *dsk {
+                builder = builder.disk(n, &mut **dsk);
+            }
+        }
+        if let Some(ref mut dev) = m.block_device {
+            builder = builder.block_device(dev);
+        }
+        let mut machine = builder.build();
+        let stop = machine.run(&mut m.cpu, limit);
+        m.selected_disk = machine.selected_disk();
+        stop
+    }));
+    match result {
+        Ok(Ok(StopReason::Halt)) => Z80_STOP_HALT,
+        Ok(Ok(StopReason::Illegal)) => Z80_STOP_ILLEGAL,
+        Ok(Ok(StopReason::Trap(_))) => Z80_STOP_TRAP,
+        Ok(Ok(_)) => Z80_STOP_BUDGET,
+        Ok(Err(_)) | Err(_) => Z80_STOP_ERROR
+    }
+}
+
+fn is_good_disk(drive: u8, heads: u8, tracks: u16, sectors: u16, sector_size: usize) -> bool
+{
+    (drive as usize) < DISKS && heads > 0 && tracks > 0 && sectors > 0 && SECTOR_SIZES.contains(&sector_size)
+}
+
+// The disk image in the file named by `filename`, if it can be opened.
+
+unsafe fn open(filename: *const c_char, read_only: bool) -> Option<DiskImage>
+{
+    let filename = CStr::from_ptr(filename).to_str().ok()?;
+    let image = if read_only { disk_image::open_read_only(filename) } else { disk_image::open(filename) };
+    image.ok()
+}
+
+fn pair(hi: u8, lo: u8) -> u16
+{
+    ((hi as u16) << 8) | (lo as u16)
+}
@@ -0,0 +1,293 @@
+// Exercise the C interface.  Run with `make test` in capi/.
+
+#define _POSIX_C_SOURCE 200809L
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <unistd.h>
+
+#include "z80emu.h"
+
+static int failures = 0;
+
+#define CHECK(cond)                                                     \
+    do {                                                                \
+        if (!(cond)) {                                                  \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+            failures++;                                                 \
+        }                                                               \
+    } while (0)
+
+struct io {
+    uint8_t last_port;
+    uint8_t last_value;
+    int outputs;
+};
+
+static uint8_t input(void *ctx, uint8_t port)
+{
+    (void)ctx;
+    return port == 1 ? 21 : 0;
+}
+
+static void output(void *ctx, uint8_t port, uint8_t value)
+{
+    struct io *io = ctx;
+    io->last_port = port;
+    io->last_value = value;
+    io->outputs++;
+}
+
+static void test_io(void)
+{
+    // IN A,(1); ADD A,A; OUT (2),A; HALT
+    static const uint8_t code[] = { 0xDB, 0x01, 0x87, 0xD3, 0x02, 0x76 };
+    struct io io = { 0, 0, 0 };
+
+    Z80Machine *m = z80_create(0x100);
+    z80_write_mem(m, 0x100, code, sizeof(code));
+    z80_set_io(m, input, output, &io);
+
+    CHECK(z80_run(m, 1000) == Z80_STOP_HALT);
+    CHECK(io.outputs == 1);
+    CHECK(io.last_port == 2);
+    CHECK(io.last_value == 42);
+    CHECK(z80_get_reg(m, Z80_REG_AF) >> 8 == 42);
+    CHECK(z80_get_reg(m, Z80_REG_PC) == 0x106);
+    CHECK(z80_cycles(m) == 11 + 4 + 11 + 4);
+    z80_destroy(m);
+}
+
+static void test_budget(void)
+{
+    // Memory is all NOPs
+    Z80Machine *m = z80_create(0);
+    CHECK(z80_run(m, 100) == Z80_STOP_BUDGET);
+    CHECK(z80_cycles(m) >= 100);
+    z80_destroy(m);
+}
+
+static void test_registers_and_memory(void)
+{
+    static const uint8_t data[] = { 1, 2, 3, 4 };
+    uint8_t buf[4] = { 0 };
+
+    Z80Machine *m = z80_create(0);
+    z80_set_reg(m, Z80_REG_BC, 0x1234);
+    z80_set_reg(m, Z80_REG_HL_ALT, 0xBEEF);
+    z80_set_reg(m, Z80_REG_I, 0x3F);
+    CHECK(z80_get_reg(m, Z80_REG_BC) == 0x1234);
+    CHECK(z80_get_reg(m, Z80_REG_HL_ALT) == 0xBEEF);
+    CHECK(z80_get_reg(m, Z80_REG_I) == 0x3F);
+
+    // Writes and reads wrap around the top of memory
+    z80_write_mem(m, 0xFFFE, data, sizeof(data));
+    z80_read_mem(m, 0, buf, 2);
+    CHECK(buf[0] == 3 && buf[1] == 4);
+    z80_read_mem(m, 0xFFFE, buf, sizeof(buf));
+    CHECK(buf[0] == 1 && buf[3] == 4);
+    z80_destroy(m);
+}
+
+static void test_interrupt(void)
+{
+    Z80Machine *m = z80_create(0x1234);
+    z80_set_reg(m, Z80_REG_SP, 0x8000);
+    CHECK(!z80_interrupt(m, 0xFF));
+
+    z80_set_reg(m, Z80_REG_IFF1, 1);
+    z80_set_reg(m, Z80_REG_IM, 1);
+    CHECK(z80_interrupt(m, 0xFF));
+    CHECK(z80_get_reg(m, Z80_REG_PC) == 0x38);
+    CHECK(z80_get_reg(m, Z80_REG_SP) == 0x7FFE);
+    CHECK(z80_get_reg(m, Z80_REG_IFF1) == 0);
+    z80_destroy(m);
+}
+
+static void test_trap(void)
+{
+    // LD A,1; ED FE 2A; LD A,2; HALT
+    static const uint8_t code[] = { 0x3E, 0x01, 0xED, 0xFE, 0x2A, 0x3E, 0x02, 0x76 };
+
+    Z80Machine *m = z80_create(0);
+    z80_write_mem(m, 0, code, sizeof(code));
+    CHECK(z80_run(m, 1000) == Z80_STOP_ILLEGAL);
+
+    z80_destroy(m);
+
+    m = z80_create(0);
+    z80_write_mem(m, 0, code, sizeof(code));
+    z80_set_trap(m, true, 0xFE);
+    CHECK(z80_run(m, 1000) == Z80_STOP_TRAP);
+    CHECK(z80_trap_number(m) == 0x2A);
+    CHECK(z80_get_reg(m, Z80_REG_PC) == 5);
+    CHECK(z80_get_reg(m, Z80_REG_AF) >> 8 == 1);
+    CHECK(z80_run(m, 1000) == Z80_STOP_HALT);
+    CHECK(z80_trap_number(m) == 0);
+    CHECK(z80_get_reg(m, Z80_REG_AF) >> 8 == 2);
+    z80_destroy(m);
+}
+
+// Console output of a machine with devices, from OUT (0),A.
+
+struct console {
+    uint8_t out[16];
+    int n;
+};
+
+static void console_output(void *ctx, uint8_t port, uint8_t value)
+{
+    struct console *console = ctx;
+    if (port == 0 && console->n < (int)sizeof(console->out)) {
+        console->out[console->n++] = value;
+    }
+}
+
+// Select drive `drive`, read its sector 0 to 8000h, and print the result and
+// the first byte.  Then write the sector back and print the result.
+
+static void disk_program(Z80Machine *m, uint8_t drive)
+{
+    const uint8_t code[] = {
+        0x3E, drive, 0xD3, 0x16,                                // LD A,drive; OUT (SELECT_DISK),A
+        0x3E, 0x00, 0xD3, 0x10, 0xD3, 0x11, 0xD3, 0x12,         // Head, track, sector 0
+        0xD3, 0x13, 0x3E, 0x80, 0xD3, 0x14,                     // DMA 8000h
+        0x3E, 0x03, 0xD3, 0x15, 0x3E, 0x00, 0xD3, 0x15,         // CLEAR, SEEK
+        0x3E, 0x03, 0xD3, 0x15, 0x3E, 0x01, 0xD3, 0x15,         // CLEAR, READ
+        0xDB, 0x10, 0xD3, 0x00,                                 // IN A,(DISK_RESULT); OUT (0),A
+        0x21, 0x00, 0x80, 0x3E, 0x00, 0x86,                     // LD HL,8000h; LD A,0; ADD A,(HL)
+        0xD3, 0x00,                                             // OUT (0),A
+        0x3E, 0x03, 0xD3, 0x15, 0x3E, 0x00, 0xD3, 0x15,         // CLEAR, SEEK
+        0x3E, 0x03, 0xD3, 0x15, 0x3E, 0x02, 0xD3, 0x15,         // CLEAR, WRITE
+        0xDB, 0x10, 0xD3, 0x00,                                 // IN A,(DISK_RESULT); OUT (0),A
+        0x76,                                                   // HALT
+    };
+    z80_write_mem(m, 0, code, sizeof(code));
+}
+
+// A temporary file holding `len` copies of `c`, whose name is put in `name`.
+
+static void temp_file(char *name, uint8_t c, size_t len)
+{
+    uint8_t buf[512];
+    int fd;
+
+    strcpy(name, "/tmp/z80emu_c_api_XXXXXX");
+    fd = mkstemp(name);
+    CHECK(fd >= 0);
+    memset(buf, c, sizeof(buf));
+    CHECK(len <= sizeof(buf) && write(fd, buf, len) == (ssize_t)len);
+    close(fd);
+}
+
+static void test_ram_disk(void)
+{
+    static const uint8_t image[] = { 'R' };
+    struct console console = { { 0 }, 0 };
+
+    Z80Machine *m = z80_create(0);
+    z80_set_io(m, NULL, console_output, &console);
+    CHECK(z80_attach_ram_disk(m, 2, image, sizeof(image), 1, 1, 1, 128));
+    disk_program(m, 2);
+    CHECK(z80_run(m, 10000) == Z80_STOP_HALT);
+    CHECK(console.n == 3);
+    CHECK(console.out[0] == 0x01 && console.out[1] == 'R' && console.out[2] == 0x01);
+    z80_destroy(m);
+}
+
+static void test_read_only_disk(void)
+{
+    char name[32];
+    uint8_t c = 0;
+    struct console console = { { 0 }, 0 };
+    FILE *f;
+
+    temp_file(name, 'F', 128);
+    Z80Machine *m = z80_create(0);
+    z80_set_io(m, NULL, console_output, &console);
+    CHECK(z80_attach_disk(m, 1, name, 1, 1, 1, 128, true));
+    disk_program(m, 1);
+    CHECK(z80_run(m, 10000) == Z80_STOP_HALT);
+    CHECK(console.n == 3);
+    CHECK(console.out[0] == 0x01 && console.out[1] == 'F' && console.out[2] == 0xFB);
+    z80_destroy(m);
+
+    f = fopen(name, "rb");
+    CHECK(f != NULL && fread(&c, 1, 1, f) == 1 && c == 'F');
+    if (f) {
+        fclose(f);
+    }
+    unlink(name);
+}
+
+static void test_block_device(void)
+{
+    // LBA 0, count 1, DMA 8000h; CLEAR, SEEK, CLEAR, READ; print the result and
+    // the first byte
+    static const uint8_t code[] = {
+        0x3E, 0x00, 0xD3, 0x20, 0xD3, 0x21, 0xD3, 0x22, 0xD3, 0x23, 0xD3, 0x25,
+        0x3E, 0x80, 0xD3, 0x26, 0x3E, 0x01, 0xD3, 0x24,
+        0x3E, 0x03, 0xD3, 0x27, 0x3E, 0x00, 0xD3, 0x27,
+        0x3E, 0x03, 0xD3, 0x27, 0x3E, 0x01, 0xD3, 0x27,
+        0xDB, 0x20, 0xD3, 0x00, 0x21, 0x00, 0x80, 0x3E, 0x00, 0x86, 0xD3, 0x00,
+        0x76,
+    };
+    char name[32];
+    struct console console = { { 0 }, 0 };
+
+    temp_file(name, 'K', 512);
+    Z80Machine *m = z80_create(0);
+    z80_set_io(m, NULL, console_output, &console);
+    CHECK(z80_attach_block_device(m, name, 4, false));
+    z80_write_mem(m, 0, code, sizeof(code));
+    CHECK(z80_run(m, 10000) == Z80_STOP_HALT);
+    CHECK(console.n == 2);
+    CHECK(console.out[0] == 0x01 && console.out[1] == 'K');
+    z80_destroy(m);
+    unlink(name);
+}
+
+static void test_machine_errors(void)
+{
+    static const uint8_t image[129] = { 0 };
+    // OUT (5),A; HALT
+    static const uint8_t code[] = { 0xD3, 0x05, 0x76 };
+
+    Z80Machine *m = z80_create(0);
+    CHECK(!z80_attach_ram_disk(m, 16, NULL, 0, 1, 1, 1, 128));
+    CHECK(!z80_attach_ram_disk(m, 0, NULL, 0, 0, 1, 1, 128));
+    CHECK(!z80_attach_ram_disk(m, 0, NULL, 0, 1, 1, 1, 100));
+    CHECK(!z80_attach_ram_disk(m, 0, image, sizeof(image), 1, 1, 1, 128));
+    CHECK(!z80_attach_disk(m, 0, "/nonexistent/disk.bin", 1, 1, 1, 128, false));
+    CHECK(!z80_attach_block_device(m, "/nonexistent/block.bin", 4, false));
+
+    // Without devices the port goes to the callbacks, of which there are none
+    z80_write_mem(m, 0, code, sizeof(code));
+    CHECK(z80_run(m, 1000) == Z80_STOP_HALT);
+
+    // With devices it is not on the port map
+    z80_set_reg(m, Z80_REG_PC, 0);
+    CHECK(z80_attach_ram_disk(m, 0, NULL, 0, 1, 1, 1, 128));
+    CHECK(z80_run(m, 1000) == Z80_STOP_ERROR);
+    z80_destroy(m);
+}
+
+int main(void)
+{
+    test_io();
+    test_budget();
+    test_registers_and_memory();
+    test_interrupt();
+    test_trap();
+    test_ram_disk();
+    test_read_only_disk();
+    test_block_device();
+    test_machine_errors();
+    if (failures) {
+        fprintf(stderr, "%d failures\n", failures);
+        return EXIT_FAILURE;
+    }
+    printf("C API tests passed\n");
+    return EXIT_SUCCESS;
+}
@@ -0,0 +1,51 @@
+// Tests of the C interface: the committed header must be the one build.rs
+// generates, and the C test program, compiled against the header and linked
+// with the library, must pass.  The C compiler is $CC, or cc.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn read(path: &str) -> String
+{
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path);
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("Could not read `{}`: {}", path.display(), e))
+}
+
+#[test]
+fn header_is_up_to_date()
+{
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/z80emu.h"));
+    assert!(read("include/z80emu.h") == generated,
+            "include/z80emu.h is out of date, copy {}/z80emu.h over it", env!("OUT_DIR"));
+}
+
+#[test]
+fn c_test_program()
+{
+    // The test binary is in the same directory as the library
+    let exe = env::current_exe().unwrap();
+    let lib_dir = exe.parent().unwrap();
+    let program = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("c_api_test");
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(&cc)
+        .args(["-Wall", "-Wextra", "-Werror", "-std=c99"])
+        .arg("-I").arg(manifest_dir.join("include"))
+        .arg("-o").arg(&program)
+        .arg(manifest_dir.join("test/c_api_test.c"))
+        .arg("-L").arg(lib_dir).arg("-lz80emu_capi")
+        .status()
+        .unwrap_or_else(|e| panic!("Could not run the C compiler `{}`, set CC: {}", cc, e));
+    assert!(status.success(), "The C test program did not compile");
+
+    let output = Command::new(&program)
+        .env("LD_LIBRARY_PATH", lib_dir)
+        .env("DYLD_LIBRARY_PATH", lib_dir)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "C API tests passed\n");
+}
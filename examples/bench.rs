@@ -0,0 +1,69 @@
+// Benchmark of the CPU core, reporting the emulated clock speed.
+//
+// Usage: cargo run --release --example bench [--block-cache] [millions of T-states]
+//
+// The workload is a loop of arithmetic, logic, and branch instructions that
+// never stops; it is run for the given number of T-states (default 1000),
+// either by z80::run or through a block cache.
+
+extern crate z80emu;
+
+use std::env;
+use std::process;
+use std::time::Instant;
+
+use z80emu::{block_cache, z80};
+
+const TIMESLICE : usize = 10000;
+
+const CODE : &[u8] = &[
+    0x37, 0x01,                 // 0000  LD A,1
+    0x87,                       // 0002  ADD A,A
+    0x88,                       // 0003  ADC A,B
+    0x8E,                       // 0004  ADC A,(HL)
+    0xE6, 0x7F,                 // 0005  AND 7Fh
+    0x09,                       // 0007  ADD HL,BC
+    0xED, 0x5A,                 // 0008  ADC HL,DE
+    0xDD, 0x86, 0x05,           // 000A  ADD A,(IX+5)
+    0xCB, 0x46,                 // 000D  BIT 0,(HL)
+    0xCA, 0x02, 0x00,           // 000F  JP Z,0002
+    0xD2, 0x02, 0x00,           // 0012  JP NC,0002
+    0xC3, 0x02, 0x00,           // 0015  JP 0002
+];
+
+fn main()
+{
+    let mut cache = None;
+    let mut millions = 1000;
+    for arg in env::args().skip(1) {
+        if arg == "--block-cache" {
+            cache = Some(block_cache::make());
+        } else {
+            millions = arg.parse::<u64>().unwrap_or_else(|_| {
+                eprintln!("Usage: bench [--block-cache] [millions of T-states]");
+                process::exit(1);
+            });
+        }
+    }
+    let limit = millions * 1_000_000;
+
+    let mut cpu = z80::make(0);
+    cpu.mem[..CODE.len()].copy_from_slice(CODE);
+    cpu.b = 0x12;
+    cpu.c = 0x34;
+    cpu.e = 0x56;
+
+    let start = Instant::now();
+    while cpu.cycles < limit {
+        match cache {
+            Some(ref mut cache) => block_cache::run(&mut cpu, cache, TIMESLICE),
+            None => z80::run(&mut cpu, TIMESLICE)
+        }
+        if cpu.stop_reason != z80::StopReason::Poll {
+            panic!("Benchmark stopped: {:?}", cpu.stop_reason);
+        }
+    }
+    let secs = start.elapsed().as_secs_f64();
+
+    println!("{} T-states in {:.3} s: {:.1} MHz", cpu.cycles, secs, cpu.cycles as f64 / secs / 1e6);
+}
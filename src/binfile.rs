@@ -0,0 +1,57 @@
+// Helpers for reading our binary file formats (save states, recordings).  All
+// multi-byte values are little-endian.
+
+use std::io;
+
+pub struct Reader<'a>
+{
+    data: &'a [u8],
+    pos:  usize,
+}
+
+pub fn make_reader(data: &[u8]) -> Reader<'_>
+{
+    Reader { data, pos: 0 }
+}
+
+impl<'a> Reader<'a>
+{
+    pub fn at_end(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File is truncated"));
+        }
+        let bytes = &self.data[self.pos .. self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    // A u32 length followed by that many bytes.
+    pub fn section(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+}
@@ -0,0 +1,524 @@
+// A basic-block cache for the CPU.
+//
+// `run` has the same interface and the same results as z80::run, but decodes
+// each basic block once, into a list of pre-decoded instructions that are then
+// executed without looking at the opcodes again.  A block ends after a jump,
+// or at an instruction the cache does not pre-decode; such an instruction is
+// executed by z80::run, one instruction at a time, so every instruction the CPU
+// supports works with the cache.
+//
+// A block keeps a copy of the bytes it was decoded from and is checked against
+// memory each time it is entered; if its memory has been written, by anyone,
+// it is decoded again.  Pre-decoded instructions never write memory, and any
+// instruction that may write memory is executed by z80::run and ends its block,
+// so a block cannot be changed while it is running and self-modifying code
+// works.
+//
+// The blocks are not invalidated from the write path, eg with generation
+// counters per page bumped by the CPU's writes, because the CPU is not the only
+// writer: devices by DMA, replays, save states, snapshots, and embedders all
+// write `mem` directly, and a counter that missed one of them would run stale
+// code.  Comparing a block's bytes costs about 7% on the benchmark in
+// examples/bench.rs, whose blocks are short, and less on longer blocks.
+//
+// With coverage, an exec_check, or a bus trace enabled the cache is bypassed,
+// and z80::run does all the work, as pre-decoded instructions do not read
+// memory through it.
+//
+// A cache made with make_differential() runs the existing interpreter side by
+// side with the cache, on a copy of the machine, and panics with a description
+// of the first difference in registers or memory after each timeslice.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use z80::{self, StopReason, Z80, add8_flags, and8_flags, bit_flags, adc16_flags, add16_flags};
+
+// Blocks are ended after this many instructions.
+
+const MAX_BLOCK_LENGTH : usize = 64;
+
+pub struct BlockCache
+{
+    blocks: Vec<Option<Box<Block>>>, // Indexed by start address
+    differential: bool,
+}
+
+pub fn make() -> BlockCache
+{
+    BlockCache { blocks: (0..65536).map(|_| None).collect(), differential: false }
+}
+
+pub fn make_differential() -> BlockCache
+{
+    BlockCache { differential: true, ..make() }
+}
+
+struct Block
+{
+    code: Vec<u8>,              // The memory the instructions were decoded from
+    insns: Vec<Insn>,
+    end: u16,                   // Address after the last instruction
+    cycles: u64,                // Totals for all the instructions
+    fetches: u8,
+    fallback: bool,             // The instruction at `end` must be run by z80::run
+}
+
+struct Insn
+{
+    op: Op,
+    len: u8,
+    cycles: u8,
+    fetches: u8,                // Opcode fetches, for R
+}
+
+#[derive(Clone, Copy)]
+enum Reg8 { A, B, C, D, E, H, L }
+
+#[derive(Clone, Copy)]
+enum Reg16 { BC, DE, HL, IX, IY, SP }
+
+#[derive(Clone, Copy)]
+enum Operand { Reg(Reg8), Imm(u8), AtHL, AtIX(u8), AtIY(u8) }
+
+#[derive(Clone, Copy)]
+enum Alu { Add, Adc, And }
+
+#[derive(Clone, Copy)]
+enum Cond { NZ, Z, NC, C, PO, PE, P, M }
+
+enum Op
+{
+    Nop,
+    Ld8(Reg8, u8),
+    Ld16(Reg16, u16),
+    Alu(Alu, Operand),
+    AddRR(Reg16, Reg16),
+    AdcHL(Reg16),
+    BitHL(u8),
+    ExAF,
+    Exx,
+    ExDEHL,
+    Di,
+    Ei,
+    Im(u8),
+    Jp(u16),
+    JpCc(Cond, u16),
+}
+
+impl BlockCache
+{
+    // Forget all blocks.  This is never necessary for correctness, but frees
+    // the memory they use.
+    pub fn clear(&mut self) {
+        for b in &mut self.blocks {
+            *b = None;
+        }
+    }
+}
+
+// Run the CPU like z80::run.
+
+pub fn run(z80: &mut Z80, cache: &mut BlockCache, timeslice: usize)
+{
+    if z80.coverage.is_some() || z80.exec_check.is_some() || z80.bus_trace.is_some() {
+        z80::run(z80, timeslice);
+    } else if cache.differential {
+        run_differential(z80, cache, timeslice);
+    } else {
+        run_cached(z80, cache, timeslice);
+    }
+}
+
+fn run_differential(z80: &mut Z80, cache: &mut BlockCache, timeslice: usize)
+{
+    let mut reference = copy_cpu(z80);
+    z80::run(&mut reference, timeslice);
+    run_cached(z80, cache, timeslice);
+    if let Some(diff) = difference(&reference, z80) {
+        panic!("Block cache diverged from z80::run: {}", diff);
+    }
+}
+
+fn run_cached(z80: &mut Z80, cache: &mut BlockCache, timeslice: usize)
+{
+    // As in z80::run, a timeslice of n runs n-1 instructions.
+    let mut remaining = timeslice - 1;
+    loop {
+        let pc = z80.pc as usize;
+        let valid = match cache.blocks[pc] {
+            Some(ref block) => z80.mem[pc..pc + block.code.len()] == block.code[..],
+            None => false
+        };
+        if !valid {
+            cache.blocks[pc] = Some(Box::new(decode_block(&z80.mem, z80.pc)));
+        }
+        let block = cache.blocks[pc].as_ref().unwrap();
+
+        if remaining >= block.insns.len() {
+            // Pre-decoded instructions do not look at pc, R, or the cycle
+            // count, so when the whole block runs those are updated once.  Only
+            // the last instruction can jump.
+            z80.pc = block.end;
+            for insn in &block.insns {
+                execute(z80, &insn.op);
+            }
+            z80.r = (z80.r & 0x80) | (z80.r.wrapping_add(block.fetches) & 0x7F);
+            z80.cycles += block.cycles;
+            remaining -= block.insns.len();
+        } else {
+            for insn in &block.insns[..remaining] {
+                z80.pc = z80.pc.wrapping_add(insn.len as u16);
+                z80.r = (z80.r & 0x80) | (z80.r.wrapping_add(insn.fetches) & 0x7F);
+                z80.cycles += insn.cycles as u64;
+                execute(z80, &insn.op);
+            }
+            remaining = 0;
+        }
+
+        if remaining == 0 {
+            z80.stop_reason = StopReason::Poll;
+            return;
+        }
+        if block.fallback {
+            z80::step(z80);
+            if z80.stop_reason != StopReason::Poll {
+                return;
+            }
+            remaining -= 1;
+        }
+    }
+}
+
+// Execute an instruction whose pc has already been advanced.
+
+#[inline(always)]
+fn execute(z80: &mut Z80, op: &Op)
+{
+    match *op {
+        Op::Nop => {}
+        Op::Ld8(r, n) => { set8(z80, r, n); }
+        Op::Ld16(rr, nn) => { set16(z80, rr, nn); }
+        Op::Alu(alu, operand) => {
+            let n = match operand {
+                Operand::Reg(r) => get8(z80, r),
+                Operand::Imm(n) => n,
+                Operand::AtHL => z80.mem[get16(z80, Reg16::HL) as usize],
+                Operand::AtIX(d) => z80.mem[z80.ix.wrapping_add(d as i8 as u16) as usize],
+                Operand::AtIY(d) => z80.mem[z80.iy.wrapping_add(d as i8 as u16) as usize],
+            };
+            let a = z80.a;
+            match alu {
+                Alu::Add => {
+                    let result = (a as usize).wrapping_add(n as usize);
+                    z80.f = add8_flags(z80.f, a, n, result);
+                    z80.a = result as u8;
+                }
+                Alu::Adc => {
+                    let result = (a as usize).wrapping_add(n as usize).wrapping_add((z80.f & 1) as usize);
+                    z80.f = add8_flags(z80.f, a, n, result);
+                    z80.a = result as u8;
+                }
+                Alu::And => {
+                    let result = (a as usize) & (n as usize);
+                    z80.f = and8_flags(z80.f, result);
+                    z80.a = result as u8;
+                }
+            }
+        }
+        Op::AddRR(rr, ss) => {
+            let rrval = get16(z80, rr);
+            let ssval = get16(z80, ss);
+            let result = (rrval as usize).wrapping_add(ssval as usize);
+            z80.f = add16_flags(z80.f, rrval, ssval, result);
+            set16(z80, rr, result as u16);
+        }
+        Op::AdcHL(ss) => {
+            let hlval = get16(z80, Reg16::HL);
+            let ssval = get16(z80, ss);
+            let result = (hlval as usize).wrapping_add(ssval as usize).wrapping_add((z80.f & 1) as usize);
+            z80.f = adc16_flags(z80.f, hlval, ssval, result);
+            set16(z80, Reg16::HL, result as u16);
+        }
+        Op::BitHL(bit) => {
+            let v = z80.mem[get16(z80, Reg16::HL) as usize] as u16;
+            z80.f = bit_flags(z80.f, v & (1 << bit));
+        }
+        Op::ExAF => {
+            ::core::mem::swap(&mut z80.a, &mut z80.a_alt);
+            ::core::mem::swap(&mut z80.f, &mut z80.f_alt);
+        }
+        Op::Exx => {
+            ::core::mem::swap(&mut z80.b, &mut z80.b_alt);
+            ::core::mem::swap(&mut z80.c, &mut z80.c_alt);
+            ::core::mem::swap(&mut z80.d, &mut z80.d_alt);
+            ::core::mem::swap(&mut z80.e, &mut z80.e_alt);
+            ::core::mem::swap(&mut z80.h, &mut z80.h_alt);
+            ::core::mem::swap(&mut z80.l, &mut z80.l_alt);
+        }
+        Op::ExDEHL => {
+            ::core::mem::swap(&mut z80.d, &mut z80.h);
+            ::core::mem::swap(&mut z80.e, &mut z80.l);
+        }
+        Op::Di => { z80.iff1 = false; z80.iff2 = false; }
+        Op::Ei => { z80.iff1 = true; z80.iff2 = true; }
+        Op::Im(n) => { z80.im = n; }
+        Op::Jp(addr) => { z80.pc = addr; }
+        Op::JpCc(cond, addr) => {
+            let f = z80.f;
+            let taken = match cond {
+                Cond::NZ => f & 0x40 == 0,
+                Cond::Z => f & 0x40 != 0,
+                Cond::NC => f & 0x01 == 0,
+                Cond::C => f & 0x01 != 0,
+                Cond::PO => f & 0x04 == 0,
+                Cond::PE => f & 0x04 != 0,
+                Cond::P => f & 0x80 == 0,
+                Cond::M => f & 0x80 != 0,
+            };
+            if taken {
+                z80.pc = addr;
+            }
+        }
+    }
+}
+
+// Decode the block starting at `start`.  The block ends after a jump, before
+// an instruction that is not pre-decoded or that would run past the end of
+// memory, or after MAX_BLOCK_LENGTH instructions.
+
+fn decode_block(mem: &[u8], start: u16) -> Block
+{
+    let mut insns = vec![];
+    let mut pc = start as usize;
+    let mut fallback = false;
+    while insns.len() < MAX_BLOCK_LENGTH {
+        let insn = match if pc + 4 <= mem.len() { decode(&mem[pc..pc + 4]) } else { None } {
+            Some(insn) => insn,
+            None => {
+                fallback = true;
+                break;
+            }
+        };
+        let jump = matches!(insn.op, Op::Jp(_) | Op::JpCc(_, _));
+        pc += insn.len as usize;
+        insns.push(insn);
+        if jump {
+            break;
+        }
+    }
+    Block {
+        code: mem[start as usize..pc].to_vec(),
+        cycles: insns.iter().map(|insn| insn.cycles as u64).sum(),
+        fetches: insns.iter().fold(0u8, |n, insn| n.wrapping_add(insn.fetches)),
+        insns,
+        end: pc as u16,
+        fallback
+    }
+}
+
+// Decode the instruction at the start of `code`, which holds at least four
+// bytes, if it is pre-decoded.  This follows the instruction set of z80::run,
+// with the same cycle counts.
+
+fn decode(code: &[u8]) -> Option<Insn>
+{
+    let word = (code[1] as u16) | ((code[2] as u16) << 8);
+    let insn = |op, len, cycles| Some(Insn { op, len, cycles, fetches: 1 });
+    let prefixed = |op, len, cycles| Some(Insn { op, len, cycles, fetches: 2 });
+    match code[0] {
+        0x00 => insn(Op::Nop, 1, 4),
+        0x01 => insn(Op::Ld16(Reg16::BC, word), 3, 10),
+        0x06 => insn(Op::Ld8(Reg8::B, code[1]), 2, 7),
+        0x08 => insn(Op::ExAF, 1, 4),
+        0x09 => insn(Op::AddRR(Reg16::HL, Reg16::BC), 1, 11),
+        0x0E => insn(Op::Ld8(Reg8::C, code[1]), 2, 7),
+        0x11 => insn(Op::Ld16(Reg16::DE, word), 3, 10),
+        0x16 => insn(Op::Ld8(Reg8::D, code[1]), 2, 7),
+        0x19 => insn(Op::AddRR(Reg16::HL, Reg16::DE), 1, 11),
+        0x1E => insn(Op::Ld8(Reg8::E, code[1]), 2, 7),
+        0x21 => insn(Op::Ld16(Reg16::HL, word), 3, 10),
+        0x26 => insn(Op::Ld8(Reg8::H, code[1]), 2, 7),
+        0x29 => insn(Op::AddRR(Reg16::HL, Reg16::HL), 1, 11),
+        0x2E => insn(Op::Ld8(Reg8::L, code[1]), 2, 7),
+        0x31 => insn(Op::Ld16(Reg16::SP, word), 3, 10),
+        0x37 => insn(Op::Ld8(Reg8::A, code[1]), 2, 7),
+        0x39 => insn(Op::AddRR(Reg16::HL, Reg16::SP), 1, 11),
+        0x3E => insn(Op::Ld8(Reg8::A, code[1]), 2, 7),
+        0x80..=0x8F | 0xA0..=0xA7 => {
+            let alu = match code[0] & 0xF8 {
+                0x80 => Alu::Add,
+                0x88 => Alu::Adc,
+                _ => Alu::And
+            };
+            let operand = match code[0] & 7 {
+                0 => Operand::Reg(Reg8::B),
+                1 => Operand::Reg(Reg8::C),
+                2 => Operand::Reg(Reg8::D),
+                3 => Operand::Reg(Reg8::E),
+                4 => Operand::Reg(Reg8::H),
+                5 => Operand::Reg(Reg8::L),
+                6 => Operand::AtHL,
+                _ => Operand::Reg(Reg8::A),
+            };
+            let cycles = if code[0] & 7 == 6 { 7 } else { 4 };
+            insn(Op::Alu(alu, operand), 1, cycles)
+        }
+        0xC2 => insn(Op::JpCc(Cond::NZ, word), 3, 10),
+        0xC3 => insn(Op::Jp(word), 3, 10),
+        0xC6 => insn(Op::Alu(Alu::Add, Operand::Imm(code[1])), 2, 7),
+        0xCA => insn(Op::JpCc(Cond::Z, word), 3, 10),
+        0xCB => match code[1] {
+            0x46 | 0x4E | 0x56 | 0x5E | 0x66 | 0x6E | 0x76 | 0x7E =>
+                prefixed(Op::BitHL((code[1] >> 3) & 7), 2, 12),
+            _ => None
+        },
+        0xCE => insn(Op::Alu(Alu::Adc, Operand::Imm(code[1])), 2, 7),
+        0xD2 => insn(Op::JpCc(Cond::NC, word), 3, 10),
+        0xD9 => insn(Op::Exx, 1, 4),
+        0xDA => insn(Op::JpCc(Cond::C, word), 3, 10),
+        0xDD | 0xFD => {
+            let ix = code[0] == 0xDD;
+            let rr = if ix { Reg16::IX } else { Reg16::IY };
+            let at = if ix { Operand::AtIX(code[2]) } else { Operand::AtIY(code[2]) };
+            match code[1] {
+                0x09 => prefixed(Op::AddRR(rr, Reg16::BC), 2, 15),
+                0x19 => prefixed(Op::AddRR(rr, Reg16::DE), 2, 15),
+                0x29 => prefixed(Op::AddRR(rr, rr), 2, 15),
+                0x39 => prefixed(Op::AddRR(rr, Reg16::SP), 2, 15),
+                0x86 => prefixed(Op::Alu(Alu::Add, at), 3, 19),
+                0x8E => prefixed(Op::Alu(Alu::Adc, at), 3, 19),
+                0xA6 => prefixed(Op::Alu(Alu::And, at), 3, 19),
+                _ => None
+            }
+        }
+        0xE2 => insn(Op::JpCc(Cond::PO, word), 3, 10),
+        0xE6 => insn(Op::Alu(Alu::And, Operand::Imm(code[1])), 2, 7),
+        0xEA => insn(Op::JpCc(Cond::PE, word), 3, 10),
+        0xEB => insn(Op::ExDEHL, 1, 4),
+        0xED => match code[1] {
+            0x46 => prefixed(Op::Im(0), 2, 8),
+            0x4A => prefixed(Op::AdcHL(Reg16::BC), 2, 15),
+            0x56 => prefixed(Op::Im(1), 2, 8),
+            0x5A => prefixed(Op::AdcHL(Reg16::DE), 2, 15),
+            0x5E => prefixed(Op::Im(2), 2, 8),
+            0x6A => prefixed(Op::AdcHL(Reg16::HL), 2, 15),
+            0x7A => prefixed(Op::AdcHL(Reg16::SP), 2, 15),
+            _ => None
+        },
+        0xF2 => insn(Op::JpCc(Cond::P, word), 3, 10),
+        0xF3 => insn(Op::Di, 1, 4),
+        0xFA => insn(Op::JpCc(Cond::M, word), 3, 10),
+        0xFB => insn(Op::Ei, 1, 4),
+        _ => None
+    }
+}
+
+#[inline(always)]
+fn get8(z80: &Z80, r: Reg8) -> u8
+{
+    match r {
+        Reg8::A => z80.a,
+        Reg8::B => z80.b,
+        Reg8::C => z80.c,
+        Reg8::D => z80.d,
+        Reg8::E => z80.e,
+        Reg8::H => z80.h,
+        Reg8::L => z80.l,
+    }
+}
+
+#[inline(always)]
+fn set8(z80: &mut Z80, r: Reg8, v: u8)
+{
+    match r {
+        Reg8::A => { z80.a = v; }
+        Reg8::B => { z80.b = v; }
+        Reg8::C => { z80.c = v; }
+        Reg8::D => { z80.d = v; }
+        Reg8::E => { z80.e = v; }
+        Reg8::H => { z80.h = v; }
+        Reg8::L => { z80.l = v; }
+    }
+}
+
+#[inline(always)]
+fn get16(z80: &Z80, rr: Reg16) -> u16
+{
+    match rr {
+        Reg16::BC => ((z80.b as u16) << 8) | (z80.c as u16),
+        Reg16::DE => ((z80.d as u16) << 8) | (z80.e as u16),
+        Reg16::HL => ((z80.h as u16) << 8) | (z80.l as u16),
+        Reg16::IX => z80.ix,
+        Reg16::IY => z80.iy,
+        Reg16::SP => z80.sp,
+    }
+}
+
+#[inline(always)]
+fn set16(z80: &mut Z80, rr: Reg16, v: u16)
+{
+    let (hi, lo) = ((v >> 8) as u8, v as u8);
+    match rr {
+        Reg16::BC => { z80.b = hi; z80.c = lo; }
+        Reg16::DE => { z80.d = hi; z80.e = lo; }
+        Reg16::HL => { z80.h = hi; z80.l = lo; }
+        Reg16::IX => { z80.ix = v; }
+        Reg16::IY => { z80.iy = v; }
+        Reg16::SP => { z80.sp = v; }
+    }
+}
+
+// A copy of the CPU without its instrumentation, which run() never has to
+// copy as it bypasses the cache when there is any.
+
+fn copy_cpu(z80: &Z80) -> Z80
+{
+    Z80 {
+        mem: z80.mem, stop_reason: z80.stop_reason, port_addr: z80.port_addr,
+        pc: z80.pc, sp: z80.sp, ix: z80.ix, iy: z80.iy,
+        a: z80.a, f: z80.f, b: z80.b, c: z80.c, d: z80.d, e: z80.e, h: z80.h, l: z80.l,
+        i: z80.i, r: z80.r, im: z80.im, iff1: z80.iff1, iff2: z80.iff2,
+        cycles: z80.cycles,
+        a_alt: z80.a_alt, f_alt: z80.f_alt, b_alt: z80.b_alt, c_alt: z80.c_alt,
+        d_alt: z80.d_alt, e_alt: z80.e_alt, h_alt: z80.h_alt, l_alt: z80.l_alt,
+        coverage: None, exec_check: None, bus_trace: None, trap: z80.trap
+    }
+}
+
+// A description of the first difference between `expected` and `actual`, if
+// any.
+
+fn difference(expected: &Z80, actual: &Z80) -> Option<String>
+{
+    if expected.stop_reason != actual.stop_reason {
+        return Some(format!("stop reason {:?}, expected {:?}", actual.stop_reason, expected.stop_reason));
+    }
+    let regs = |z: &Z80| [
+        ("pc", z.pc), ("sp", z.sp), ("ix", z.ix), ("iy", z.iy),
+        ("af", pair(z.a, z.f)), ("bc", pair(z.b, z.c)), ("de", pair(z.d, z.e)), ("hl", pair(z.h, z.l)),
+        ("af'", pair(z.a_alt, z.f_alt)), ("bc'", pair(z.b_alt, z.c_alt)),
+        ("de'", pair(z.d_alt, z.e_alt)), ("hl'", pair(z.h_alt, z.l_alt)),
+        ("ir", pair(z.i, z.r)), ("im", z.im as u16), ("iff", pair(z.iff1 as u8, z.iff2 as u8)),
+        ("port", z.port_addr as u16),
+    ];
+    for (&(name, e), &(_, a)) in regs(expected).iter().zip(regs(actual).iter()) {
+        if e != a {
+            return Some(format!("{} is {:04X}h, expected {:04X}h", name, a, e));
+        }
+    }
+    if expected.cycles != actual.cycles {
+        return Some(format!("cycles is {}, expected {}", actual.cycles, expected.cycles));
+    }
+    if let Some(addr) = (0..65536).find(|&i| expected.mem[i] != actual.mem[i]) {
+        return Some(format!("memory at {:04X}h is {:02X}h, expected {:02X}h",
+                            addr, actual.mem[addr], expected.mem[addr]));
+    }
+    None
+}
+
+fn pair(hi: u8, lo: u8) -> u16
+{
+    ((hi as u16) << 8) | (lo as u16)
+}
@@ -0,0 +1,58 @@
+// A trace of the CPU's memory accesses.
+//
+// When a BusTrace is attached to the CPU, z80::run() and z80::interrupt()
+// record every byte they read or write, in the order of the bus cycles that
+// would do it: instruction fetches, operand reads, stack accesses, and data
+// reads and writes.  I/O is not recorded, as the CPU stops for the embedder to
+// perform it.
+//
+// The trace is for tests that compare the CPU with bus-level reference data;
+// it grows without limit, so it is not for long runs.
+
+use alloc::vec::Vec;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Access {
+    pub kind: Kind,
+    pub addr: u16,
+    pub data: u8,
+}
+
+pub struct BusTrace
+{
+    accesses: Vec<Access>,
+}
+
+pub fn make() -> BusTrace
+{
+    BusTrace { accesses: vec![] }
+}
+
+impl BusTrace
+{
+    // The accesses so far, oldest first.
+    pub fn accesses(&self) -> &[Access] {
+        &self.accesses
+    }
+
+    // Forget the accesses so far.
+    pub fn clear(&mut self) {
+        self.accesses.clear();
+    }
+
+    // Called by the CPU for every byte it reads.
+    pub fn read(&mut self, addr: u16, data: u8) {
+        self.accesses.push(Access { kind: Kind::Read, addr, data });
+    }
+
+    // Called by the CPU for every byte it writes.
+    pub fn write(&mut self, addr: u16, data: u8) {
+        self.accesses.push(Access { kind: Kind::Write, addr, data });
+    }
+}
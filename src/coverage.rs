@@ -0,0 +1,162 @@
+// Code coverage for guest programs.
+//
+// When a Coverage object is attached to the CPU, z80::run() records the
+// address of every instruction it executes, and for every conditional branch
+// whether the branch was taken or not.
+//
+// With the `std` feature, the coverage map can be written as an lcov trace
+// file.  Addresses are attributed to source lines through a source map, as
+// written by z80asm's create_source_map().  The map has one line per
+// assembled instruction:
+//
+//   <address in hex> <tab> <line number> <tab> <file name>
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader, Write};
+
+pub struct Coverage
+{
+    // Execution count of the instruction at each address
+    hits: Vec<u32>,
+
+    // Taken and not-taken counts of the conditional branch at each address
+    branches: BTreeMap<u16, (u32, u32)>,
+}
+
+pub fn make() -> Coverage
+{
+    Coverage {
+        hits: vec![0; 65536],
+        branches: BTreeMap::new(),
+    }
+}
+
+impl Coverage
+{
+    // Called by the CPU at the start of every instruction.
+    pub fn execute(&mut self, addr: u16) {
+        let n = &mut self.hits[addr as usize];
+        *n = n.saturating_add(1);
+    }
+
+    // Called by the CPU for every conditional branch.
+    pub fn branch(&mut self, addr: u16, taken: bool) {
+        let counts = self.branches.entry(addr).or_insert((0, 0));
+        if taken {
+            counts.0 = counts.0.saturating_add(1);
+        } else {
+            counts.1 = counts.1.saturating_add(1);
+        }
+    }
+
+    // The number of times the instruction at `addr` was executed.
+    pub fn hits(&self, addr: u16) -> u32 {
+        self.hits[addr as usize]
+    }
+
+    // The (taken, not taken) counts of the branch at `addr`, if it was executed.
+    pub fn branch_counts(&self, addr: u16) -> Option<(u32, u32)> {
+        self.branches.get(&addr).cloned()
+    }
+
+    // Write an lcov trace file covering the instructions in the source map.
+    // Instructions that are not in the map are not reported.
+    #[cfg(feature = "std")]
+    pub fn write_lcov(&self, out: &mut dyn Write, map: &SourceMap) -> io::Result<()> {
+        let mut files: BTreeMap<&str, BTreeMap<u32, LineCoverage>> = BTreeMap::new();
+        for (addr, &(ref file, line)) in &map.lines {
+            let lc = files.entry(file).or_default().entry(line).or_default();
+            lc.hits = lc.hits.max(self.hits(*addr));
+            if let Some((taken, not_taken)) = self.branch_counts(*addr) {
+                lc.branches.push((*addr, taken, not_taken));
+            }
+        }
+
+        writeln!(out, "TN:")?;
+        for (file, lines) in &files {
+            writeln!(out, "SF:{}", file)?;
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+            for (line, lc) in lines {
+                for &(addr, taken, not_taken) in &lc.branches {
+                    writeln!(out, "BRDA:{},{},0,{}", line, addr, taken)?;
+                    writeln!(out, "BRDA:{},{},1,{}", line, addr, not_taken)?;
+                    branches_found += 2;
+                    branches_hit += (taken > 0) as u32 + (not_taken > 0) as u32;
+                }
+            }
+            if branches_found > 0 {
+                writeln!(out, "BRF:{}", branches_found)?;
+                writeln!(out, "BRH:{}", branches_hit)?;
+            }
+            for (line, lc) in lines {
+                writeln!(out, "DA:{},{}", line, lc.hits)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", lines.values().filter(|lc| lc.hits > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+// Coverage of one source line, which may span several instructions.
+
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct LineCoverage
+{
+    hits: u32,                       // Max hits of any instruction on the line
+    branches: Vec<(u16, u32, u32)>,  // (address, taken, not taken)
+}
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// Source maps map instruction addresses to source lines.
+
+#[cfg(feature = "std")]
+pub struct SourceMap
+{
+    lines: BTreeMap<u16, (String, u32)>,
+}
+
+#[cfg(feature = "std")]
+pub fn make_source_map() -> SourceMap
+{
+    SourceMap { lines: BTreeMap::new() }
+}
+
+#[cfg(feature = "std")]
+impl SourceMap
+{
+    // Add the entries from a map file written by z80asm.  Later entries for an
+    // address replace earlier ones.
+    pub fn load(&mut self, filename: &str) -> io::Result<()> {
+        let reader = BufReader::new(File::open(filename)?);
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(3, '\t');
+            let addr = fields.next().and_then(|s| u16::from_str_radix(s, 16).ok());
+            let lineno = fields.next().and_then(|s| s.parse::<u32>().ok());
+            let file = fields.next();
+            match (addr, lineno, file) {
+                (Some(addr), Some(lineno), Some(file)) => {
+                    self.lines.insert(addr, (file.to_string(), lineno));
+                }
+                _ => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("Bad source map line in `{}`: {}", filename, line)));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+// A minimal CP/M environment for running .COM programs, enough for the classic
+// instruction exercisers (ZEXDOC, ZEXALL, and the 8080 exercisers).
+//
+// The program is loaded at 0100h and started with a return address of 0000h
+// on the stack.  The BDOS entry at 0005h jumps to a HALT at BDOS_ADDR, which
+// the runner traps: it performs the BDOS function in C and returns to the
+// caller.  Only the console output functions are provided:
+//
+//   C=2   Output the character in E
+//   C=9   Output the string at DE, terminated by '$', at most 64 KB of it
+//
+// A jump to 0000h (warm boot), which is also a HALT, ends the program.  The
+// word at 0006h holds BDOS_ADDR, the top of the program area, as programs
+// expect.
+
+use devices::ByteWriter;
+use z80::{self, StopReason, Z80};
+
+pub const LOAD_ADDR : u16 = 0x0100;
+pub const BDOS_ADDR : u16 = 0xFE00;
+
+const HALT : u8 = 0x76;
+const JP : u8 = 0xC3;
+const TIMESLICE : usize = 10000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Exit {
+    WarmBoot,                   // The program jumped to 0000h
+    Halt(u16),                  // HALT at the address
+    Illegal(u16),               // Illegal instruction before the address
+    UnsupportedBdos(u8),        // BDOS function number
+    Trap(u8),                   // Trap number, see z80::StopReason::Trap
+    ExecCheck(u16),             // Finding before the address, see exec_check
+    CycleLimit,
+}
+
+// Make a CPU with `program` loaded and ready to run.  Panics if the program
+// does not fit below the BDOS.
+
+pub fn make(program: &[u8]) -> Z80
+{
+    assert!(LOAD_ADDR as usize + program.len() <= BDOS_ADDR as usize - 2, "Program is too large");
+
+    let mut cpu = z80::make(LOAD_ADDR);
+    cpu.mem[LOAD_ADDR as usize..LOAD_ADDR as usize + program.len()].copy_from_slice(program);
+    cpu.mem[0x0000] = HALT;
+    cpu.mem[0x0005] = JP;
+    cpu.mem[0x0006] = BDOS_ADDR as u8;
+    cpu.mem[0x0007] = (BDOS_ADDR >> 8) as u8;
+    cpu.mem[BDOS_ADDR as usize] = HALT;
+
+    // Return address 0000h
+    cpu.sp = BDOS_ADDR - 2;
+    cpu
+}
+
+// Run the program until it exits or the cycle count reaches `cycle_limit`,
+// sending console output to `out`.  IN reads FFh and OUT is ignored.  If the
+// CPU has a trap opcode, a trap stops the run; after servicing it, run again to
+// resume.
+
+pub fn run(cpu: &mut Z80, cycle_limit: u64, out: &mut dyn ByteWriter) -> Exit
+{
+    while cpu.cycles < cycle_limit {
+        z80::run(cpu, TIMESLICE);
+        match cpu.stop_reason {
+            StopReason::Halt if cpu.pc == BDOS_ADDR + 1 => {
+                if !bdos(cpu, out) {
+                    return Exit::UnsupportedBdos(cpu.c);
+                }
+                ret(cpu);
+            }
+            StopReason::Halt if cpu.pc == 0x0001 => { return Exit::WarmBoot; }
+            StopReason::Halt => { return Exit::Halt(cpu.pc.wrapping_sub(1)); }
+            StopReason::Illegal => { return Exit::Illegal(cpu.pc); }
+            StopReason::Trap(n) => { return Exit::Trap(n); }
+            StopReason::ExecCheck => { return Exit::ExecCheck(cpu.pc); }
+            StopReason::In => { cpu.a = 0xFF; }
+            StopReason::Out | StopReason::Poll => {}
+        }
+    }
+    Exit::CycleLimit
+}
+
+// Perform the BDOS function in C; returns false if it is not supported.
+
+fn bdos(cpu: &mut Z80, out: &mut dyn ByteWriter) -> bool
+{
+    match cpu.c {
+        2 => {
+            out.put_nonblocking(cpu.e);
+        }
+        9 => {
+            // At most all of memory, in case there is no '$'
+            let addr = ((cpu.d as u16) << 8) | (cpu.e as u16);
+            for i in 0..=0xFFFF {
+                let c = cpu.mem[addr.wrapping_add(i) as usize];
+                if c == b'$' {
+                    break;
+                }
+                out.put_nonblocking(c);
+            }
+        }
+        _ => { return false; }
+    }
+    true
+}
+
+fn ret(cpu: &mut Z80)
+{
+    let lo = cpu.mem[cpu.sp as usize] as u16;
+    let hi = cpu.mem[cpu.sp.wrapping_add(1) as usize] as u16;
+    cpu.sp = cpu.sp.wrapping_add(2);
+    cpu.pc = (hi << 8) | lo;
+    cpu.cycles += 10;
+}
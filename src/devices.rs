@@ -1,5 +1,23 @@
 // Device traits.
 
+use alloc::vec::Vec;
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// Devices that have state visible to the guest can save it to and restore it
+// from an opaque byte string, for machine save states.  The default is for
+// devices that have no such state.
+
+pub trait DeviceState
+{
+    fn save_state(&self, _out: &mut Vec<u8>) {}
+
+    // Returns false if the state is malformed.
+    fn restore_state(&mut self, state: &[u8]) -> bool {
+        state.is_empty()
+    }
+}
+
 ///////////////////////////////////////////////////////////////////////////////
 //
 // Serial devices provide byte-by-byte input and output.
@@ -21,7 +39,8 @@ pub trait ByteWriter
 // A teletype has a serial typewriter and a serial keyboard but is otherwise not
 // very interesting.
 
-pub trait TTY : ByteReader + ByteWriter {}
+#[allow(clippy::upper_case_acronyms)]
+pub trait TTY : ByteReader + ByteWriter + DeviceState {}
 
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -33,7 +52,7 @@ pub trait TTY : ByteReader + ByteWriter {}
 //
 // For simplicity's sake we have common status values for all spinning disks.
 
-pub trait SpinningDisk
+pub trait SpinningDisk : DeviceState
 {
     // Get the disk status.  The status is set by disk_operation().
     fn get_status(&mut self) -> SpinningDiskStatus;
@@ -43,6 +62,17 @@ pub trait SpinningDisk
     fn set_track(&mut self, n: u8);
     fn set_sector(&mut self, n: u8);
 
+    // Set the high bytes of the track and sector numbers, for disks with more
+    // than 256 tracks or sectors per track.  set_track() and set_sector() set
+    // only the low bytes, and the high bytes are zero until they are set.
+    // Disks without 16-bit numbers ignore these.
+    fn set_track_high(&mut self, _n: u8) {}
+    fn set_sector_high(&mut self, _n: u8) {}
+
+    // Set the number of sectors for multi-sector transfers, for disks that have
+    // them.  Disks without them ignore this.
+    fn set_sector_count(&mut self, _n: u8) {}
+
     // Set the data transfer address.
     fn set_dma_high(&mut self, n: u8);
     fn set_dma_low(&mut self, n: u8);
@@ -64,20 +94,106 @@ pub trait SpinningDisk
     //
     // WRITE.  If the status is not Ready this does nothing.  Otherwise, write the
     // selected (seeked-to) sector from the bytes in memory at the selected DMA
-    // address (with wraparound).  Sets status to WriteError if the write fails;
-    // once the write is completed the status becomes Done.
+    // address (with wraparound).  Sets status to WriteProtected if the disk is
+    // write protected and to WriteError if the write fails; once the write is
+    // completed the status becomes Done.
     //
     // If the operation is not known or is issued when the device is not Ready
     // then status is set to OpError.
     fn disk_operation(&mut self, op: u8, mem: &mut [u8]);
+
+    // The memory written by the last operation, as its address and length, if
+    // any.  This is for tools that track memory writes, see exec_check.
+    fn written(&self) -> Option<(u16, usize)> {
+        None
+    }
+
+    // Tell the disk the CPU's cycle count, for disks whose operations take
+    // time.  While an operation is in progress the status is Busy.  The
+    // machine calls this before every disk access and between timeslices.
+    fn clock(&mut self, _cycles: u64) {}
+
+    // The interrupt data if the disk is interrupting the CPU to signal that an
+    // operation has completed.
+    fn interrupt(&self) -> Option<u8> {
+        None
+    }
+
+    // The cycle from which the disk interrupts, if it will interrupt for the
+    // current operation.  The machine stops the CPU then, so that the
+    // interrupt is not late.
+    fn interrupt_at(&self) -> Option<u64> {
+        None
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// Block devices, like CF and SD cards, address their blocks by a logical block
+// address (LBA) rather than by head, track, and sector.  The LBA is 32 bits and
+// is set a byte at a time; a guest that uses 28-bit addressing, as for ATA,
+// leaves the top byte zero.  A transfer moves a number of consecutive blocks
+// to or from consecutive memory.
+//
+// The status values and the status protocol are those of spinning disks.
+
+pub trait BlockDevice : DeviceState
+{
+    // Get the device status.  The status is set by block_operation().
+    fn get_status(&mut self) -> SpinningDiskStatus;
+
+    // Set byte `n` of the LBA, 0-3 from least to most significant.  This takes
+    // effect at the next seek.
+    fn set_lba(&mut self, n: usize, value: u8);
+
+    // Set the number of blocks to transfer, 1-255, or 0 for 256.  This takes
+    // effect at the next seek.
+    fn set_count(&mut self, n: u8);
+
+    // Set the data transfer address.
+    fn set_dma_high(&mut self, n: u8);
+    fn set_dma_low(&mut self, n: u8);
+
+    // The command set is specific to the particular device implementation, but
+    // the typical operations are as for SpinningDisk::disk_operation(), with
+    // SEEK validating all the blocks of the transfer and READ and WRITE
+    // transferring all of them.
+    fn block_operation(&mut self, op: u8, mem: &mut [u8]);
+
+    // The memory written by the last operation, as for SpinningDisk.
+    fn written(&self) -> Option<(u16, usize)> {
+        None
+    }
 }
 
-#[derive(PartialEq,Clone,Copy)]
+// The sector sizes of spinning disks, in bytes.
+pub const SECTOR_SIZES : [usize; 4] = [128, 256, 512, 1024];
+
+#[derive(PartialEq,Clone,Copy,Debug)]
 pub enum SpinningDiskStatus {
     Ready = 0x00,
     Done = 0x01,
+    Busy = 0x02,
     OpError = 0xFF,
     SeekError = 0xFE,
     ReadError = 0xFD,
-    WriteError = 0xFC
+    WriteError = 0xFC,
+    WriteProtected = 0xFB
+}
+
+impl SpinningDiskStatus
+{
+    pub fn from_u8(n: u8) -> Option<SpinningDiskStatus> {
+        match n {
+            0x00 => Some(SpinningDiskStatus::Ready),
+            0x01 => Some(SpinningDiskStatus::Done),
+            0x02 => Some(SpinningDiskStatus::Busy),
+            0xFF => Some(SpinningDiskStatus::OpError),
+            0xFE => Some(SpinningDiskStatus::SeekError),
+            0xFD => Some(SpinningDiskStatus::ReadError),
+            0xFC => Some(SpinningDiskStatus::WriteError),
+            0xFB => Some(SpinningDiskStatus::WriteProtected),
+            _    => None
+        }
+    }
 }
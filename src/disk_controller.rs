@@ -0,0 +1,456 @@
+// The controller of a spinning disk, generic over the storage that holds the
+// disk's contents.  A file backed spinning disk is a controller on a disk
+// image, and a RAM disk is one on a vector, see file_backed_spinning_disk and
+// ram_disk.
+//
+// Sectors are 128, 256, 512, or 1024 bytes, and are stored in order of head,
+// track, and sector, ie the byte offset of a sector is
+//
+//   ((head * tracks + track) * sectors + sector) * sector_size
+//
+// Floppy disks have up to 255 tracks and 255 sectors per track, as their
+// geometry is given in u8s.  Hard disks have up to 65535 of each, and the guest
+// sets the high bytes of the track and sector numbers as well, see
+// devices::SpinningDisk.  A hard disk with 16 heads, 1024 tracks, 64 sectors,
+// and 512-byte sectors holds 512 MB.
+//
+// Commands:
+//   0x00 = SEEK
+//   0x01 = READ
+//   0x02 = WRITE
+//   0x03 = CLEAR
+//   0x04 = READ_MULTI
+//   0x05 = WRITE_MULTI
+//
+// READ_MULTI and WRITE_MULTI transfer the number of sectors in the sector count
+// register, 1-255 or 0 for 256, starting at the seeked-to sector and moving on
+// to the next track and the next head as needed, to or from consecutive memory
+// from the DMA address.  As the sectors are in that order in the storage, this
+// is one contiguous transfer.  If the disk ends before the last sector the
+// status is set to SeekError and nothing is transferred.
+//
+// A disk can be write protected with set_write_protect(), and a disk whose
+// storage cannot be written is always write protected.  Writing to a write
+// protected disk sets the status to WriteProtected and leaves the disk as it
+// is.
+//
+// By default every operation completes at once.  With a Timing, see
+// set_timing(), operations that succeed take time, measured in CPU cycles as
+// told by SpinningDisk::clock(): SEEK steps the heads from the track they are
+// on, and READ and WRITE wait for the sector to come around and then transfer
+// it.  Sector 0 of every track passes the heads at cycle 0, and the sectors are
+// evenly spaced.  Until the operation completes the status is Busy and further
+// commands are ignored; the memory transfer itself happens when the command is
+// issued.  Operations that fail complete at once.  Either way, the disk can
+// then interrupt the CPU, until the guest reads the status or issues another
+// command.
+
+use alloc::vec::Vec;
+
+use devices::{DeviceState, SpinningDisk, SpinningDiskStatus, SECTOR_SIZES};
+
+// Operation times, in CPU cycles.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timing
+{
+    pub step:      u64,         // Moving the heads by one track
+    pub rotation:  u64,         // One revolution of the disk
+    pub transfer:  u64,         // Reading or writing one sector
+    pub interrupt: Option<u8>,  // Interrupt data on completion, if the disk interrupts
+}
+
+// The contents of a disk, read and written at byte offsets.  Reads and writes
+// return false on an I/O error.  The contents are part of the disk's saved state
+// if they are not configuration, as a file is.
+
+pub trait Storage
+{
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> bool;
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> bool;
+    fn is_writable(&self) -> bool;
+
+    fn save_contents(&self, _out: &mut Vec<u8>) {}
+
+    // Returns false if the contents are malformed.  Empty contents leave the
+    // storage as it is, as for states that predate the contents.
+    fn restore_contents(&mut self, contents: &[u8]) -> bool {
+        contents.is_empty()
+    }
+}
+
+// A vector holds the whole disk, so the contents are part of the state.
+
+impl Storage for Vec<u8>
+{
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        let end = offset.saturating_add(buf.len() as u64);
+        if end > self.len() as u64 {
+            return false;
+        }
+        buf.copy_from_slice(&self[offset as usize..end as usize]);
+        true
+    }
+
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> bool {
+        let end = offset.saturating_add(buf.len() as u64);
+        if end > self.len() as u64 {
+            return false;
+        }
+        self[offset as usize..end as usize].copy_from_slice(buf);
+        true
+    }
+
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    fn save_contents(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn restore_contents(&mut self, contents: &[u8]) -> bool {
+        if contents.len() == self.len() {
+            self.copy_from_slice(contents);
+        }
+        contents.is_empty() || contents.len() == self.len()
+    }
+}
+
+pub struct DiskController<S>
+{
+    // Currently selected disk controller parameters
+    head:   u8,
+    track:  u16,
+    sector: u16,
+    dma_lo: u8,
+    dma_hi: u8,
+    count:  u8,
+
+    // Computed seek offset
+    offset: u64,
+
+    // Intermediate storage, one transfer
+    buf: Vec<u8>,
+
+    // Result of operation
+    status: SpinningDiskStatus,
+
+    // Memory written by the operation
+    written: Option<(u16, usize)>,
+
+    // Disk geometry
+    heads:      u16,
+    tracks:     u16,
+    sectors:    u16,
+    sector_size: usize,
+
+    // Write protection, set by the embedder
+    write_protect: bool,
+
+    // Timing model, the CPU's cycle count, the completion time of the current
+    // operation, the track the heads are on, and whether the operation
+    // interrupts on completion
+    timing:     Option<Timing>,
+    now:        u64,
+    busy_until: u64,
+    position:   u16,
+    irq:        bool,
+
+    // Backing store
+    pub(crate) storage: S,
+}
+
+// A disk on `storage`.  Panics if `sector_size` is not one of SECTOR_SIZES, or
+// if the disk has no heads, tracks, or sectors.
+
+pub fn make<S: Storage>(storage: S, heads: u8, tracks: u16, sectors: u16, sector_size: usize) -> DiskController<S>
+{
+    assert!(SECTOR_SIZES.contains(&sector_size), "Bad sector size {}", sector_size);
+    assert!(heads > 0 && tracks > 0 && sectors > 0, "Bad geometry {}/{}/{}", heads, tracks, sectors);
+
+    DiskController {
+        head:       0,
+        track:      0,
+        sector:     0,
+        dma_lo:     0,
+        dma_hi:     0,
+        count:      1,
+        offset:     0,
+        buf:        vec![],
+        status:     SpinningDiskStatus::Done,
+        written:    None,
+        heads:      heads as u16,
+        tracks,
+        sectors,
+        sector_size,
+        write_protect: false,
+        timing:     None,
+        now:        0,
+        busy_until: 0,
+        position:   0,
+        irq:        false,
+        storage }
+}
+
+impl<S: Storage> SpinningDisk for DiskController<S>
+{
+    fn get_status(&mut self) -> SpinningDiskStatus {
+        if self.now < self.busy_until {
+            return SpinningDiskStatus::Busy;
+        }
+        self.irq = false;
+        self.status
+    }
+
+    fn set_head(&mut self, n: u8) { self.head = n; }
+    fn set_track(&mut self, n: u8) { self.track = (self.track & 0xFF00) | n as u16; }
+    fn set_sector(&mut self, n: u8) { self.sector = (self.sector & 0xFF00) | n as u16; }
+    fn set_track_high(&mut self, n: u8) { self.track = ((n as u16) << 8) | (self.track & 0xFF); }
+    fn set_sector_high(&mut self, n: u8) { self.sector = ((n as u16) << 8) | (self.sector & 0xFF); }
+
+    fn set_dma_high(&mut self, n: u8) { self.dma_hi = n; }
+    fn set_dma_low(&mut self, n: u8) { self.dma_lo = n; }
+    fn set_sector_count(&mut self, n: u8) { self.count = n; }
+
+    fn disk_operation(&mut self, op: u8, mem: &mut [u8]) {
+        if self.now < self.busy_until {
+            return;
+        }
+        self.written = None;
+        self.irq = false;
+        let latency = self.timing.map_or(0, |t| self.latency(op, &t));
+        match op {
+            0x00 => { self.seek(); }
+            0x01 => { self.read_sectors(mem, Some(1)); }
+            0x02 => { self.write_sectors(mem, Some(1)); }
+            0x03 => { self.clear(); }
+            0x04 => { let n = self.multi_count(); self.read_sectors(mem, n); }
+            0x05 => { let n = self.multi_count(); self.write_sectors(mem, n); }
+            _    => { self.status = SpinningDiskStatus::OpError }
+        }
+        if let Some(t) = self.timing {
+            if op != 0x03 {
+                if self.status == SpinningDiskStatus::Done {
+                    self.busy_until = self.now + latency;
+                }
+                self.irq = t.interrupt.is_some();
+            }
+        }
+    }
+
+    fn written(&self) -> Option<(u16, usize)> { self.written }
+
+    fn clock(&mut self, cycles: u64) { self.now = cycles; }
+
+    fn interrupt(&self) -> Option<u8> {
+        if self.irq && self.now >= self.busy_until { self.timing.and_then(|t| t.interrupt) } else { None }
+    }
+
+    fn interrupt_at(&self) -> Option<u64> {
+        if self.irq { Some(self.busy_until) } else { None }
+    }
+}
+
+// The saved state is the controller registers, the seek offset, the status,
+// the high bytes of the track and sector, the sector count, and the timing
+// state, followed by the storage's contents if they are state.  The geometry,
+// the timing, and a file are configuration, not state.  Older states without
+// the high bytes restore them as zero, without the sector count restore it as
+// 1, and without the timing state restore the disk as idle on track 0; none of
+// them have contents.
+
+impl<S: Storage> DeviceState for DiskController<S>
+{
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.head, self.track as u8, self.sector as u8, self.dma_lo, self.dma_hi]);
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.push(self.status as u8);
+        out.extend_from_slice(&[(self.track >> 8) as u8, (self.sector >> 8) as u8, self.count]);
+        out.extend_from_slice(&self.busy_until.to_le_bytes());
+        out.extend_from_slice(&self.position.to_le_bytes());
+        out.push(self.irq as u8);
+        self.storage.save_contents(out);
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> bool {
+        let (state, contents) = if [14, 16, 17].contains(&state.len()) {
+            (state, &[][..])
+        } else if state.len() >= 28 {
+            state.split_at(28)
+        } else {
+            return false;
+        };
+        let status = match SpinningDiskStatus::from_u8(state[13]) {
+            Some(status) => status,
+            None => { return false; }
+        };
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&state[5..13]);
+        let offset = u64::from_le_bytes(offset);
+        if offset >= self.capacity() || offset % self.sector_size as u64 != 0 {
+            return false;
+        }
+        if !self.storage.restore_contents(contents) {
+            return false;
+        }
+        let (track_hi, sector_hi) = if state.len() >= 16 { (state[14], state[15]) } else { (0, 0) };
+        self.count = if state.len() >= 17 { state[16] } else { 1 };
+        self.head = state[0];
+        self.track = ((track_hi as u16) << 8) | state[1] as u16;
+        self.sector = ((sector_hi as u16) << 8) | state[2] as u16;
+        self.dma_lo = state[3];
+        self.dma_hi = state[4];
+        self.offset = offset;
+        self.status = status;
+        if state.len() >= 28 {
+            let mut busy_until = [0; 8];
+            busy_until.copy_from_slice(&state[17..25]);
+            self.busy_until = u64::from_le_bytes(busy_until);
+            self.position = u16::from_le_bytes([state[25], state[26]]);
+            self.irq = state[27] != 0;
+        } else {
+            self.busy_until = 0;
+            self.position = 0;
+            self.irq = false;
+        }
+        true
+    }
+}
+
+impl<S: Storage> DiskController<S>
+{
+    // Write protect the disk, or remove the protection.  A disk whose storage
+    // cannot be written stays protected.
+    pub fn set_write_protect(&mut self, protect: bool) {
+        self.write_protect = protect;
+    }
+
+    pub fn is_write_protected(&self) -> bool {
+        self.write_protect || !self.storage.is_writable()
+    }
+
+    // Make operations take time, see above.  Panics if the rotation time is 0.
+    pub fn set_timing(&mut self, timing: Timing) {
+        assert!(timing.rotation > 0, "Bad rotation time");
+        self.timing = Some(timing);
+    }
+
+    // The size of the disk in bytes.
+    fn capacity(&self) -> u64 {
+        self.heads as u64 * self.tracks as u64 * self.sectors as u64 * self.sector_size as u64
+    }
+
+    // The time operation `op` takes if it succeeds, from now.
+    fn latency(&self, op: u8, t: &Timing) -> u64 {
+        let sectors = match op {
+            0x00 => { return (self.track as i64 - self.position as i64).unsigned_abs() * t.step; }
+            0x01 | 0x02 => 1,
+            0x04 | 0x05 => self.multi_count().unwrap_or(0) as u64,
+            _ => { return 0; }
+        };
+        let sector = self.offset / self.sector_size as u64 % self.sectors as u64;
+        let arrives = sector * t.rotation / self.sectors as u64;
+        let wait = (arrives + t.rotation - self.now % t.rotation) % t.rotation;
+        wait + sectors * t.transfer
+    }
+
+    fn validate_params(&self) -> bool {
+        (self.head as u16) < self.heads && self.track < self.tracks && self.sector < self.sectors
+    }
+
+    // The byte offset of the selected sector in the storage.
+    fn translate(&self) -> u64 {
+        let sectors_per_track = self.sectors as u64;
+        let tracks_per_head = self.tracks as u64;
+        let sectors_per_head = sectors_per_track * tracks_per_head;
+        let sector_size = self.sector_size as u64;
+
+        let n = self.head as u64 * sectors_per_head + self.track as u64 * sectors_per_track + self.sector as u64;
+        n * sector_size
+    }
+
+    fn clear(&mut self) {
+        self.status = SpinningDiskStatus::Ready;
+    }
+
+    fn seek(&mut self) {
+        if self.status != SpinningDiskStatus::Ready {
+            self.status = SpinningDiskStatus::OpError;
+            return;
+        }
+        if !self.validate_params() {
+            self.status = SpinningDiskStatus::SeekError;
+            return;
+        }
+        self.offset = self.translate();
+        self.position = self.track;
+        self.status = SpinningDiskStatus::Done;
+    }
+
+    // The number of sectors READ_MULTI and WRITE_MULTI transfer, or None if the
+    // disk ends before the last of them.
+    fn multi_count(&self) -> Option<usize> {
+        let count = if self.count == 0 { 256 } else { self.count as u64 };
+        let first = self.offset / self.sector_size as u64;
+        let total = self.heads as u64 * self.tracks as u64 * self.sectors as u64;
+        if first + count <= total { Some(count as usize) } else { None }
+    }
+
+    // Read `n` sectors from the seeked-to sector; None is a transfer past the
+    // end of the disk.
+    fn read_sectors(&mut self, mem: &mut [u8], n: Option<usize>) {
+        if self.status != SpinningDiskStatus::Ready {
+            self.status = SpinningDiskStatus::OpError;
+            return;
+        }
+        let n = match n {
+            Some(n) => n,
+            None => { self.status = SpinningDiskStatus::SeekError; return; }
+        };
+        self.buf.resize(n * self.sector_size, 0);
+
+        // Read to intermediate buffer to handle wraparound addresses
+        if !self.storage.read_at(self.offset, &mut self.buf) {
+            self.status = SpinningDiskStatus::ReadError;
+            return;
+        }
+        let mut dma = ((self.dma_hi as u16) << 8) | (self.dma_lo as u16);
+        self.written = Some((dma, self.buf.len()));
+        for &b in &self.buf {
+            mem[dma as usize] = b;
+            dma = dma.wrapping_add(1);
+        }
+        self.status = SpinningDiskStatus::Done
+    }
+
+    // Write `n` sectors, as read_sectors().
+    fn write_sectors(&mut self, mem: &mut [u8], n: Option<usize>) {
+        if self.status != SpinningDiskStatus::Ready {
+            self.status = SpinningDiskStatus::OpError;
+            return;
+        }
+        if self.is_write_protected() {
+            self.status = SpinningDiskStatus::WriteProtected;
+            return;
+        }
+        let n = match n {
+            Some(n) => n,
+            None => { self.status = SpinningDiskStatus::SeekError; return; }
+        };
+        self.buf.resize(n * self.sector_size, 0);
+
+        // Copy to intermediate buffer to handle wraparound addresses
+        let mut dma = ((self.dma_hi as u16) << 8) | (self.dma_lo as u16);
+        for b in &mut self.buf {
+            *b = mem[dma as usize];
+            dma = dma.wrapping_add(1);
+        }
+
+        if self.storage.write_at(self.offset, &self.buf) {
+            self.status = SpinningDiskStatus::Done
+        } else {
+            self.status = SpinningDiskStatus::WriteError
+        }
+    }
+}
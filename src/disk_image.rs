@@ -0,0 +1,161 @@
+// A disk image is the file behind a file backed disk or block device, read and
+// written at byte offsets.
+//
+// The file may be shorter than the disk.  Past the end of the file the image
+// reads as zeroes, and writing there extends the file, zero-filling any gap.
+// A file that cannot be opened for writing is opened read-only, as is any file
+// opened with open_read_only(), and a read-only image cannot be written.
+//
+// An image can have a copy-on-write overlay, so that the file is not modified:
+// reads come from the file, and writes go to an in-memory delta that later
+// reads see.  The delta can be committed to the file or discarded; dropping the
+// image discards it.  An image with an overlay can be written even if its file
+// is read-only, but committing its delta fails.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// The overlay holds whole chunks of the image.  This is the smallest sector.
+const CHUNK : usize = 128;
+
+pub struct DiskImage
+{
+    file: File,
+    read_only: bool,
+
+    // Modified chunks by chunk number, if there is an overlay
+    overlay: Option<BTreeMap<u64, Vec<u8>>>,
+}
+
+// Open a disk image, read-write if possible and otherwise read-only.  The
+// permission bits do not say whether we may write, for example root may write
+// to any file and nobody to one on a read-only filesystem, so this tries.
+
+pub fn open(filename: &str) -> io::Result<DiskImage>
+{
+    match OpenOptions::new().read(true).write(true).open(filename) {
+        Ok(file) => Ok(DiskImage { file, read_only: false, overlay: None }),
+        Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied
+                      || e.kind() == io::ErrorKind::ReadOnlyFilesystem => open_read_only(filename),
+        Err(e) => Err(e)
+    }
+}
+
+// Open a disk image read-only, even if the file is writable.
+
+pub fn open_read_only(filename: &str) -> io::Result<DiskImage>
+{
+    Ok(DiskImage { file: File::open(filename)?, read_only: true, overlay: None })
+}
+
+impl DiskImage
+{
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // True if writes are possible, to the file or to the overlay.
+    pub fn is_writable(&self) -> bool {
+        !self.read_only || self.overlay.is_some()
+    }
+
+    // Send writes to an overlay from now on.  Does nothing if there is one.
+    pub fn add_overlay(&mut self) {
+        if self.overlay.is_none() {
+            self.overlay = Some(BTreeMap::new());
+        }
+    }
+
+    pub fn has_overlay(&self) -> bool {
+        self.overlay.is_some()
+    }
+
+    // True if the overlay holds writes that are not committed.
+    pub fn is_modified(&self) -> bool {
+        self.overlay.as_ref().is_some_and(|o| !o.is_empty())
+    }
+
+    // Write the overlay's delta to the file and empty the overlay, which stays
+    // in place.  Each chunk leaves the overlay once it is written, so after an
+    // error the overlay holds what was not committed, and reads still see the
+    // whole delta.  If the file is read-only this fails without writing.
+    pub fn commit(&mut self) -> io::Result<()> {
+        if self.read_only && self.is_modified() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "The disk image is read-only"));
+        }
+        if let Some(ref mut overlay) = self.overlay {
+            while let Some((&n, chunk)) = overlay.first_key_value() {
+                self.file.seek(SeekFrom::Start(n * CHUNK as u64))?;
+                self.file.write_all(chunk)?;
+                overlay.remove(&n);
+            }
+        }
+        Ok(())
+    }
+
+    // Empty the overlay, which stays in place.
+    pub fn discard(&mut self) {
+        if let Some(ref mut overlay) = self.overlay {
+            overlay.clear();
+        }
+    }
+
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let n = read_fully(&mut self.file, buf)?;
+        for b in &mut buf[n..] { *b = 0; }
+
+        if let Some(ref overlay) = self.overlay {
+            let end = offset + buf.len() as u64;
+            for (&n, chunk) in overlay.range(offset / CHUNK as u64..end.div_ceil(CHUNK as u64)) {
+                let start = n * CHUNK as u64;
+                let from = offset.max(start);
+                let to = end.min(start + CHUNK as u64);
+                buf[(from - offset) as usize..(to - offset) as usize]
+                    .copy_from_slice(&chunk[(from - start) as usize..(to - start) as usize]);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if self.overlay.is_none() {
+            self.file.seek(SeekFrom::Start(offset))?;
+            return self.file.write_all(buf);
+        }
+
+        let end = offset + buf.len() as u64;
+        for n in offset / CHUNK as u64..end.div_ceil(CHUNK as u64) {
+            let start = n * CHUNK as u64;
+            let mut chunk = match self.overlay.as_mut().unwrap().remove(&n) {
+                Some(chunk) => chunk,
+                None => {
+                    let mut chunk = vec![0; CHUNK];
+                    self.read_at(start, &mut chunk)?;
+                    chunk
+                }
+            };
+            let from = offset.max(start);
+            let to = end.min(start + CHUNK as u64);
+            chunk[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&buf[(from - offset) as usize..(to - offset) as usize]);
+            self.overlay.as_mut().unwrap().insert(n, chunk);
+        }
+        Ok(())
+    }
+}
+
+// Read as much of `buf` as the file holds, returning the number of bytes read.
+
+fn read_fully(file: &mut File, buf: &mut [u8]) -> io::Result<usize>
+{
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read(&mut buf[n..])? {
+            0 => { break; }
+            k => { n += k; }
+        }
+    }
+    Ok(n)
+}
@@ -0,0 +1,142 @@
+// Detection of self-modifying code and of execution from memory that was never
+// written.
+//
+// When an ExecCheck is attached to the CPU, z80::run() tells it about every
+// instruction byte it fetches and every byte it writes, and reports:
+//
+//   SelfModified   An instruction byte was modified since it was last executed
+//   NeverWritten   Execution entered memory that was never written or loaded,
+//                  such as the zeroed memory below the boot ROM
+//
+// A modified instruction is reported once, at its first modified byte.
+// Execution from memory that was never written is reported once on entering
+// it, not for every instruction, so that running into a sled of NOPs gives one
+// finding.
+//
+// Memory that is not written by the CPU must be reported by the embedder with
+// load(), or with changed(), which compares memory before and after a write.
+// The Machine reports the memory written by its devices.
+//
+// A check made with make() only records its findings.  One made with
+// make_stopping() also stops the CPU with StopReason::ExecCheck after an
+// instruction that has a finding; running the CPU again resumes it.
+
+use alloc::vec::Vec;
+
+const WRITTEN : u8 = 1;
+const EXECUTED : u8 = 2;
+const MODIFIED : u8 = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    SelfModified,
+    NeverWritten,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Finding {
+    pub kind: Kind,
+    pub pc: u16,                // Address of the instruction
+    pub addr: u16,              // Address of the offending byte
+}
+
+pub struct ExecCheck
+{
+    // WRITTEN, EXECUTED, and MODIFIED bits for each address
+    flags: Vec<u8>,
+
+    findings: Vec<Finding>,
+    stopping: bool,
+    pending: bool,              // A finding the CPU has not stopped for
+
+    insn: u16,                  // Address of the current instruction
+    insn_modified: bool,        // The current instruction was modified
+    in_unwritten: bool,         // The previous instruction was never written
+    insn_unwritten: bool,       //   and the current one
+}
+
+pub fn make() -> ExecCheck
+{
+    ExecCheck {
+        flags: vec![0; 65536],
+        findings: vec![],
+        stopping: false,
+        pending: false,
+        insn: 0,
+        insn_modified: false,
+        in_unwritten: false,
+        insn_unwritten: false,
+    }
+}
+
+pub fn make_stopping() -> ExecCheck
+{
+    ExecCheck { stopping: true, ..make() }
+}
+
+impl ExecCheck
+{
+    // The findings so far, in the order they were made.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    // Mark `len` bytes from `addr` as loaded, with wraparound.  Loading over
+    // code that has been executed modifies it.
+    pub fn load(&mut self, addr: u16, len: usize) {
+        for i in 0..len {
+            self.write(addr.wrapping_add(i as u16));
+        }
+    }
+
+    // Mark the bytes that differ between `before` and `after`, both all of
+    // memory, as written.
+    pub fn changed(&mut self, before: &[u8], after: &[u8]) {
+        for (addr, (b, a)) in before.iter().zip(after).enumerate() {
+            if b != a {
+                self.write(addr as u16);
+            }
+        }
+    }
+
+    // Called by the CPU at the start of every instruction.  Returns true if the
+    // CPU should stop before the instruction for a finding in the previous one.
+    pub fn instruction(&mut self, pc: u16) -> bool {
+        if self.pending && self.stopping {
+            self.pending = false;
+            return true;
+        }
+        self.insn = pc;
+        self.insn_modified = false;
+        self.in_unwritten = self.insn_unwritten;
+        self.insn_unwritten = false;
+        false
+    }
+
+    // Called by the CPU for every instruction byte it fetches.
+    pub fn fetch(&mut self, addr: u16) {
+        let flags = &mut self.flags[addr as usize];
+        if *flags & WRITTEN == 0 {
+            if !self.in_unwritten && !self.insn_unwritten {
+                self.findings.push(Finding { kind: Kind::NeverWritten, pc: self.insn, addr });
+                self.pending = true;
+            }
+            self.insn_unwritten = true;
+        }
+        if *flags & MODIFIED != 0 && !self.insn_modified {
+            self.insn_modified = true;
+            self.findings.push(Finding { kind: Kind::SelfModified, pc: self.insn, addr });
+            self.pending = true;
+        }
+        *flags = (*flags | EXECUTED) & !MODIFIED;
+    }
+
+    // Called by the CPU for every byte it writes.
+    pub fn write(&mut self, addr: u16) {
+        let flags = &mut self.flags[addr as usize];
+        if *flags & EXECUTED != 0 {
+            *flags |= MODIFIED;
+        }
+        *flags |= WRITTEN;
+    }
+}
@@ -0,0 +1,233 @@
+// A file backed block device represents a CF or SD card as a single file on
+// the host system, with 512-byte blocks stored in the file in LBA order.
+//
+// As for file backed spinning disks, the file may be shorter than the device.
+// Blocks past the end of the file read as zeroes, and writing one extends the
+// file, zero-filling any gap.  Overlays, write protection, and read-only files
+// are also as for file backed spinning disks.
+//
+// Commands:
+//   0x00 = SEEK    Validate the LBA and count, see devices::BlockDevice
+//   0x01 = READ    Read the blocks to memory at the DMA address
+//   0x02 = WRITE   Write the blocks from memory at the DMA address
+//   0x03 = CLEAR
+//
+// A transfer of more than 128 blocks is larger than memory, and wraps around it
+// more than once.
+
+use devices::{BlockDevice, DeviceState, SpinningDiskStatus};
+use disk_image::{self, DiskImage};
+
+pub const BLOCK_SIZE : usize = 512;
+
+pub struct FileBackedBlockDevice
+{
+    // Currently selected controller parameters
+    lba:    u32,
+    count:  u8,
+    dma_lo: u8,
+    dma_hi: u8,
+
+    // Computed seek offset and number of blocks
+    offset: u64,
+    blocks: u16,
+
+    // Intermediate storage, one transfer
+    buf: Vec<u8>,
+
+    // Result of operation
+    status: SpinningDiskStatus,
+
+    // Memory written by the operation
+    written: Option<(u16, usize)>,
+
+    // Number of blocks on the device
+    capacity: u32,
+
+    // Write protection, set by the embedder
+    write_protect: bool,
+
+    // Backing store
+    image: DiskImage,
+}
+
+// A device with `capacity` blocks.  Panics if the file cannot be opened, or if
+// the device has no blocks.
+
+pub fn make(filename: &str, capacity: u32) -> FileBackedBlockDevice
+{
+    let image = disk_image::open(filename).unwrap_or_else(|e| panic!("Could not open `{}`: {}", filename, e));
+    make_from_image(image, capacity)
+}
+
+// A device on an image that is already open.  Panics if it has no blocks.
+
+pub fn make_from_image(image: DiskImage, capacity: u32) -> FileBackedBlockDevice
+{
+    assert!(capacity > 0, "Bad capacity {}", capacity);
+
+    FileBackedBlockDevice {
+        lba:      0,
+        count:    1,
+        dma_lo:   0,
+        dma_hi:   0,
+        offset:   0,
+        blocks:   0,
+        buf:      vec![],
+        status:   SpinningDiskStatus::Done,
+        written:  None,
+        capacity,
+        write_protect: false,
+        image }
+}
+
+impl BlockDevice for FileBackedBlockDevice
+{
+    fn get_status(&mut self) -> SpinningDiskStatus { self.status }
+
+    fn set_lba(&mut self, n: usize, value: u8) {
+        assert!(n < 4, "No LBA byte {}", n);
+        let shift = 8 * n;
+        self.lba = (self.lba & !(0xFF << shift)) | ((value as u32) << shift);
+    }
+
+    fn set_count(&mut self, n: u8) { self.count = n; }
+
+    fn set_dma_high(&mut self, n: u8) { self.dma_hi = n; }
+    fn set_dma_low(&mut self, n: u8) { self.dma_lo = n; }
+
+    fn block_operation(&mut self, op: u8, mem: &mut [u8]) {
+        self.written = None;
+        match op {
+            0x00 => { self.seek(); }
+            0x01 => { self.read_blocks(mem); }
+            0x02 => { self.write_blocks(mem); }
+            0x03 => { self.clear(); }
+            _    => { self.status = SpinningDiskStatus::OpError }
+        }
+    }
+
+    fn written(&self) -> Option<(u16, usize)> { self.written }
+}
+
+// The saved state is the controller registers, the seek offset and number of
+// blocks, and the status.  The capacity and the file are configuration.
+
+impl DeviceState for FileBackedBlockDevice
+{
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.lba.to_le_bytes());
+        out.extend_from_slice(&[self.count, self.dma_lo, self.dma_hi]);
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&self.blocks.to_le_bytes());
+        out.push(self.status as u8);
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != 18 {
+            return false;
+        }
+        let status = match SpinningDiskStatus::from_u8(state[17]) {
+            Some(status) => status,
+            None => { return false; }
+        };
+        let mut lba = [0; 4];
+        lba.copy_from_slice(&state[0..4]);
+        self.lba = u32::from_le_bytes(lba);
+        self.count = state[4];
+        self.dma_lo = state[5];
+        self.dma_hi = state[6];
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&state[7..15]);
+        self.offset = u64::from_le_bytes(offset);
+        self.blocks = u16::from_le_bytes([state[15], state[16]]);
+        self.status = status;
+        true
+    }
+}
+
+impl FileBackedBlockDevice
+{
+    // As for FileBackedSpinningDisk.
+    pub fn set_write_protect(&mut self, protect: bool) {
+        self.write_protect = protect;
+    }
+
+    pub fn is_write_protected(&self) -> bool {
+        self.write_protect || !self.image.is_writable()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.image.is_read_only()
+    }
+
+    pub fn image(&mut self) -> &mut DiskImage {
+        &mut self.image
+    }
+
+    fn clear(&mut self) {
+        self.status = SpinningDiskStatus::Ready;
+    }
+
+    fn seek(&mut self) {
+        if self.status != SpinningDiskStatus::Ready {
+            self.status = SpinningDiskStatus::OpError;
+            return;
+        }
+        let blocks = if self.count == 0 { 256 } else { self.count as u16 };
+        if self.lba as u64 + blocks as u64 > self.capacity as u64 {
+            self.status = SpinningDiskStatus::SeekError;
+            return;
+        }
+        self.offset = self.lba as u64 * BLOCK_SIZE as u64;
+        self.blocks = blocks;
+        self.status = SpinningDiskStatus::Done;
+    }
+
+    fn read_blocks(&mut self, mem: &mut [u8]) {
+        if self.status != SpinningDiskStatus::Ready {
+            self.status = SpinningDiskStatus::OpError;
+            return;
+        }
+        self.buf.resize(self.blocks as usize * BLOCK_SIZE, 0);
+
+        match self.image.read_at(self.offset, &mut self.buf) {
+            Ok(_) => {
+                let mut dma = self.dma();
+                self.written = Some((dma, self.buf.len()));
+                for &b in &self.buf {
+                    mem[dma as usize] = b;
+                    dma = dma.wrapping_add(1);
+                }
+                self.status = SpinningDiskStatus::Done;
+            }
+            _ => { self.status = SpinningDiskStatus::ReadError; }
+        }
+    }
+
+    fn write_blocks(&mut self, mem: &mut [u8]) {
+        if self.status != SpinningDiskStatus::Ready {
+            self.status = SpinningDiskStatus::OpError;
+            return;
+        }
+        if self.is_write_protected() {
+            self.status = SpinningDiskStatus::WriteProtected;
+            return;
+        }
+        self.buf.resize(self.blocks as usize * BLOCK_SIZE, 0);
+
+        let mut dma = self.dma();
+        for b in &mut self.buf {
+            *b = mem[dma as usize];
+            dma = dma.wrapping_add(1);
+        }
+        match self.image.write_at(self.offset, &self.buf) {
+            Ok(_) => { self.status = SpinningDiskStatus::Done }
+            _     => { self.status = SpinningDiskStatus::WriteError }
+        }
+    }
+
+    fn dma(&self) -> u16 {
+        ((self.dma_hi as u16) << 8) | (self.dma_lo as u16)
+    }
+}
@@ -1,154 +1,89 @@
 // A file backed spinning disk represents a spinning disk as a single file on
-// the host system.
+// the host system.  It is a disk_controller::DiskController on the file, which
+// describes the sector layout, the commands, write protection, and timing.
 //
-// Commands:
-//   0x00 = SEEK
-//   0x01 = READ
-//   0x02 = WRITE
-//   0x03 = CLEAR
-
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom};
+// The file may be shorter than the disk.  Sectors past the end of the file read
+// as zeroes, and writing one extends the file, zero-filling any gap.  The file
+// is accessed through a disk_image::DiskImage, which can have a copy-on-write
+// overlay so that the guest's writes do not modify the file, see image().
+//
+// Floppy disks, made with make() and make_with_sector_size(), have up to 255
+// tracks and 255 sectors per track.  Hard disks, made with make_hard_disk() or
+// make_from_image(), have up to 65535 of each.
+//
+// A disk whose file is read-only, or whose image was opened with
+// disk_image::open_read_only() and given to make_from_image(), is write
+// protected unless its image has an overlay.
+//
+// The file is configuration, so the disk's saved state is only the
+// controller's.
 
-use devices::{SpinningDisk, SpinningDiskStatus};
+use disk_controller::{self, DiskController, Storage};
+use disk_image::{self, DiskImage};
 
-pub struct FileBackedSpinningDisk
-{
-    // Currently selected disk controller parameters
-    head:   u8,
-    track:  u8,
-    sector: u8,
-    dma_lo: u8,
-    dma_hi: u8,
+pub use devices::SECTOR_SIZES;
+pub use disk_controller::Timing;
 
-    // Computed seek offset
-    offset: u64,
+pub type FileBackedSpinningDisk = DiskController<DiskImage>;
 
-    // Intermediate storage
-    buf: [u8; 128],
+// A disk with 128-byte sectors.
 
-    // Result of operation
-    status: SpinningDiskStatus,
+pub fn make(filename:&str, heads: u8, tracks: u8, sectors: u8) -> FileBackedSpinningDisk
+{
+    make_with_sector_size(filename, heads, tracks, sectors, 128)
+}
 
-    // Disk geometry
-    max_head:   u8,
-    max_track:  u8,
-    max_sector: u8,
+// Panics if the file cannot be opened, if `sector_size` is not one of
+// SECTOR_SIZES, or if the disk has no heads, tracks, or sectors.
 
-    // Seekable/readable/writable fixed-size backing store
-    the_disk:   File,
+pub fn make_with_sector_size(filename:&str, heads: u8, tracks: u8, sectors: u8, sector_size: usize)
+                             -> FileBackedSpinningDisk
+{
+    make_hard_disk(filename, heads, tracks as u16, sectors as u16, sector_size)
 }
 
-pub fn make(filename:&str, heads: u8, tracks: u8, sectors: u8) -> FileBackedSpinningDisk
+// A hard disk, with up to 65535 tracks and sectors per track.  Panics as
+// make_with_sector_size() does.
+
+pub fn make_hard_disk(filename:&str, heads: u8, tracks: u16, sectors: u16, sector_size: usize)
+                      -> FileBackedSpinningDisk
 {
-    let the_disk = OpenOptions::new().read(true).write(true).open(filename)
-        .expect(&format!("Could not open `{}`", filename));
-
-    FileBackedSpinningDisk {
-        head:       0,
-        track:      0,
-        sector:     0,
-        dma_lo:     0,
-        dma_hi:     0,
-        offset:     0,
-        buf:        [0; 128],
-        status:     SpinningDiskStatus::Done,
-        max_head:   heads-1,
-        max_track:  tracks-1,
-        max_sector: sectors-1,
-        the_disk:   the_disk }
+    let image = disk_image::open(filename).unwrap_or_else(|e| panic!("Could not open `{}`: {}", filename, e));
+    make_from_image(image, heads, tracks, sectors, sector_size)
 }
 
-impl SpinningDisk for FileBackedSpinningDisk
+// A hard disk on an image that is already open, for example read-only.  Panics
+// on a bad sector size or geometry, as make_with_sector_size() does.
+
+pub fn make_from_image(image: DiskImage, heads: u8, tracks: u16, sectors: u16, sector_size: usize)
+                       -> FileBackedSpinningDisk
 {
-    fn get_status(&mut self) -> SpinningDiskStatus { self.status }
-
-    fn set_head(&mut self, n: u8) { self.head = n; }
-    fn set_track(&mut self, n: u8) { self.track = n; }
-    fn set_sector(&mut self, n: u8) { self.sector = n; }
-
-    fn set_dma_high(&mut self, n: u8) { self.dma_hi = n; }
-    fn set_dma_low(&mut self, n: u8) { self.dma_lo = n; }
-
-    fn disk_operation(&mut self, op: u8, mem: &mut [u8]) {
-        match op {
-            0x00 => { self.seek(); }
-            0x01 => { self.read_sector(mem); }
-            0x02 => { self.write_sector(mem); }
-            0x03 => { self.clear(); }
-            _    => { self.status = SpinningDiskStatus::OpError }
-        }
-    }
+    disk_controller::make(image, heads, tracks, sectors, sector_size)
 }
 
-impl FileBackedSpinningDisk
+impl Storage for DiskImage
 {
-    fn validate_params(&self) -> bool {
-        self.head <= self.max_head && self.track <= self.max_track && self.sector <= self.max_sector
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> bool {
+        DiskImage::read_at(self, offset, buf).is_ok()
     }
 
-    fn translate(&self) -> u32 {
-        let sectors_per_track = self.max_sector as u32 + 1;
-        let tracks_per_head = self.max_track as u32 + 1;
-        let sectors_per_head = sectors_per_track * tracks_per_head;
-        let sector_size = 128;
-        
-        self.head as u32 * sectors_per_head + self.track as u32 * sectors_per_track + self.sector as u32 * sector_size
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> bool {
+        DiskImage::write_at(self, offset, buf).is_ok()
     }
 
-    fn clear(&mut self) {
-        self.status = SpinningDiskStatus::Ready;
-    }
-    
-    fn seek(&mut self) {
-        if self.status != SpinningDiskStatus::Ready {
-            self.status = SpinningDiskStatus::OpError;
-            return;
-        }
-        if !self.validate_params() {
-            self.status = SpinningDiskStatus::SeekError;
-            return;
-        }
-        self.offset = self.translate() as u64;
-        self.status = SpinningDiskStatus::Done;
-    }
-    
-    fn read_sector(&mut self, mem: &mut [u8]) {
-        if self.status != SpinningDiskStatus::Ready {
-            self.status = SpinningDiskStatus::OpError;
-            return;
-        }
-
-        // We do the actual seek here, and not in seek(), since we may issue
-        // multiple read operations for the same sector to different DMA
-        // addresses, and we'd need to seek the file every time.
-
-        match self.the_disk.seek(SeekFrom::Start(self.offset)) {
-            Ok(_) => {
-                let mut dma = ((self.dma_hi as u16) << 8) | (self.dma_lo as u16);
-                // Read to intermediate buffer to handle wraparound addresses
-                match self.the_disk.read(&mut self.buf) {
-                    Ok(_) => { self.status = SpinningDiskStatus::Done }
-                    _     => { self.status = SpinningDiskStatus::ReadError }
-                }
-                for i in 0..128 {
-                    mem[dma as usize] = self.buf[i];
-                    dma = dma.wrapping_add(1);
-                }
-            }
-            _ => { self.status = SpinningDiskStatus::ReadError; }
-        }
+    fn is_writable(&self) -> bool {
+        DiskImage::is_writable(self)
     }
+}
 
-    fn write_sector(&mut self, mem: &mut [u8]) {
-        if self.status != SpinningDiskStatus::Ready {
-            self.status = SpinningDiskStatus::OpError;
-            return;
-        }
+impl FileBackedSpinningDisk
+{
+    pub fn is_read_only(&self) -> bool {
+        self.storage.is_read_only()
+    }
 
-        // IMPLEMENTME
-        // NOTE wraparound addresses
-        self.status = SpinningDiskStatus::WriteError;
+    // The disk's image, to add an overlay and to commit or discard it.
+    pub fn image(&mut self) -> &mut DiskImage {
+        &mut self.storage
     }
 }
-
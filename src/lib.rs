@@ -0,0 +1,49 @@
+// A Z80 emulator library.
+//
+// The CPU is in `z80`; it stops for I/O, and the embedder services that with
+// devices implementing the traits in `devices`.  A `machine::Machine` is a
+// ready-made set of devices with a port map, made with `machine::builder()`.
+// The rest is tooling: save states, snapshots, record and replay, coverage,
+// checks for self-modifying code, bus traces, and a seedable random number
+// generator for undefined power-on state.
+//
+// Without the default `std` feature the crate is `no_std` and only the CPU,
+// the device traits, coverage counting, the RAM disk, and the tools that need
+// neither files nor a console are available.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+extern crate core;
+#[macro_use]
+extern crate alloc;
+
+pub mod z80;
+pub mod devices;
+pub mod coverage;
+pub mod exec_check;
+pub mod bus_trace;
+pub mod block_cache;
+pub mod cpm;
+pub mod rng;
+pub mod disk_controller;
+pub mod ram_disk;
+
+#[cfg(feature = "std")]
+pub mod machine;
+#[cfg(feature = "std")]
+pub mod rust_console_io;
+#[cfg(feature = "std")]
+pub mod disk_image;
+#[cfg(feature = "std")]
+pub mod file_backed_spinning_disk;
+#[cfg(feature = "std")]
+pub mod file_backed_block_device;
+#[cfg(feature = "std")]
+pub mod save_state;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod record_replay;
+#[cfg(feature = "std")]
+mod binfile;
@@ -0,0 +1,401 @@
+// A machine is a CPU's physical devices and the port map that connects them
+// to the CPU.  The devices are borrowed, so that the embedder can inspect them
+// after a run.
+//
+// Port map:
+//
+//   Out 0x00  CHAR_OUT       TTY output
+//   In  0x00  CHAR_IN        TTY input
+//   In  0x01  CHAR_AVAIL     TTY input status, 00h or FFh
+//
+//   Out 0x10  SET_HEAD       Selected disk, see devices::SpinningDisk
+//   Out 0x11  SET_TRACK
+//   Out 0x12  SET_SECTOR
+//   Out 0x13  SET_DMA_LOW
+//   Out 0x14  SET_DMA_HIGH
+//   Out 0x15  DISK_OP
+//   Out 0x16  SELECT_DISK    Select disk 0-15, ie A: to P:
+//   Out 0x17  SET_TRACK_HIGH Selected disk
+//   Out 0x18  SET_SECTOR_HIGH
+//   Out 0x19  SET_SECTOR_COUNT
+//   In  0x10  DISK_RESULT
+//   In  0x11  DISK_PRESENT   Selected disk is present, 00h or FFh
+//   In  0x16  SELECTED_DISK
+//
+//   Out 0x20  SET_LBA_0      Block device, see devices::BlockDevice
+//   Out 0x21  SET_LBA_1
+//   Out 0x22  SET_LBA_2
+//   Out 0x23  SET_LBA_3
+//   Out 0x24  SET_BLOCK_COUNT
+//   Out 0x25  SET_BLOCK_DMA_LOW
+//   Out 0x26  SET_BLOCK_DMA_HIGH
+//   Out 0x27  BLOCK_OP
+//   In  0x20  BLOCK_RESULT
+//
+// The disks share one controller, and the disk ports other than SELECT_DISK
+// act on the selected disk, which is A: after reset.  Each disk keeps its own
+// parameters, so the guest can switch between them without setting them
+// again.  Selecting a disk with a number of 16 or more selects the disk with
+// the number modulo 16.
+//
+// Disks are clocked with the CPU's cycle count before every port access and
+// between timeslices.  A disk that interrupts on completion, see
+// devices::SpinningDisk::interrupt(), is checked between timeslices, and the
+// timeslice is cut short to end when the operation completes, so the CPU takes
+// the interrupt after the instruction during which it completed.  If the CPU
+// has interrupts disabled then, it takes the interrupt up to a timeslice after
+// enabling them.
+//
+// Accessing a port that is not assigned, or whose device is not present, is a
+// fatal error, except DISK_PRESENT.
+//
+// Machines are made with a Builder:
+//
+//   let mut m = machine::builder().tty(&mut tty).disk(0, &mut dsk).build();
+//   let mut cpu = z80::make(0);
+//   ...
+//   m.run(&mut cpu, u64::MAX)?;
+//
+// The CPU is run by z80::run, or through a block cache if the machine has one,
+// see block_cache.
+
+use std::io;
+
+use block_cache::{self, BlockCache};
+use devices::{BlockDevice, TTY, SpinningDisk};
+use record_replay::{Recorder, Replayer};
+use z80::{self, StopReason, Z80};
+
+// Number of instructions between checks for interrupts and the cycle limit.
+
+const TIMESLICE : usize = 10000;
+
+// The most cycles an instruction takes, for stopping the CPU at a given cycle
+// with a timeslice counted in instructions.
+
+const MAX_INSTRUCTION_CYCLES : u64 = 23;
+
+// Number of disks
+pub const DISKS : usize = 16;
+
+pub struct Machine<'a>
+{
+    pub(crate) tty:   Option<&'a mut dyn TTY>,
+    pub(crate) disks: Vec<Option<&'a mut dyn SpinningDisk>>,
+    pub(crate) selected_disk: u8,
+    pub(crate) block_device: Option<&'a mut dyn BlockDevice>,
+
+    block_cache: Option<BlockCache>,
+    recorder: Option<Recorder>,
+    replayer: Option<Replayer>,
+}
+
+pub struct Builder<'a>
+{
+    m: Machine<'a>,
+}
+
+pub fn builder<'a>() -> Builder<'a>
+{
+    Builder {
+        m: Machine {
+            tty: None,
+            disks: (0..DISKS).map(|_| None).collect(),
+            selected_disk: 0,
+            block_device: None,
+            block_cache: None,
+            recorder: None,
+            replayer: None
+        }
+    }
+}
+
+impl<'a> Builder<'a>
+{
+    pub fn tty(mut self, tty: &'a mut dyn TTY) -> Builder<'a> {
+        self.m.tty = Some(tty);
+        self
+    }
+
+    // Attach disk `n`, 0-15 for A: to P:.
+    pub fn disk(mut self, n: usize, dsk: &'a mut dyn SpinningDisk) -> Builder<'a> {
+        assert!(n < DISKS, "No disk {}", n);
+        self.m.disks[n] = Some(dsk);
+        self
+    }
+
+    pub fn disk_a(self, dsk: &'a mut dyn SpinningDisk) -> Builder<'a> {
+        self.disk(0, dsk)
+    }
+
+    // Select disk `n` as SELECT_DISK does, for a machine that continues one
+    // that had a disk selected.  A: is selected otherwise.
+    pub fn select_disk(mut self, n: usize) -> Builder<'a> {
+        assert!(n < DISKS, "No disk {}", n);
+        self.m.selected_disk = n as u8;
+        self
+    }
+
+    pub fn block_device(mut self, dev: &'a mut dyn BlockDevice) -> Builder<'a> {
+        self.m.block_device = Some(dev);
+        self
+    }
+
+    pub fn block_cache(mut self, cache: BlockCache) -> Builder<'a> {
+        self.m.block_cache = Some(cache);
+        self
+    }
+
+    pub fn build(self) -> Machine<'a> {
+        self.m
+    }
+}
+
+impl<'a> Machine<'a>
+{
+    // Record all input to the machine from now on, see record_replay.
+    pub fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    // Take all input to the machine from a recording from now on, see
+    // record_replay.  Output then goes nowhere, and the machine needs no
+    // devices.
+    pub fn replay(&mut self, replayer: Replayer) {
+        self.replayer = Some(replayer);
+    }
+
+    // The disk selected with SELECT_DISK, 0-15 for A: to P:.
+    pub fn selected_disk(&self) -> usize {
+        self.selected_disk as usize
+    }
+
+    // Run the CPU, servicing its I/O, until it halts, executes an illegal
+    // instruction or a trap, or its cycle count reaches `cycle_limit`.  The
+    // limit is checked between timeslices, so it may be overshot a little.
+    // Returns the reason for stopping: Halt, Illegal, Trap, ExecCheck, or Poll
+    // if the limit was reached.  After a trap the embedder services it and
+    // calls run again to resume.
+    //
+    // Memory written by devices is reported to the CPU's exec_check, if any.
+    //
+    // Errors are failures to write the recording, and divergence from the
+    // recording that is being replayed.
+    pub fn run(&mut self, cpu: &mut Z80, cycle_limit: u64) -> io::Result<StopReason> {
+        while cpu.cycles < cycle_limit {
+            if let Some(ref mut rp) = self.replayer {
+                while let Some(data) = rp.interrupt(cpu).map_err(divergence)? {
+                    z80::interrupt(cpu, data);
+                }
+            }
+            self.clock(cpu.cycles);
+            if let Some(data) = self.disks.iter().flatten().find_map(|dsk| dsk.interrupt()) {
+                self.interrupt(cpu, data)?;
+            }
+            let deadline = self.disks.iter().flatten().filter_map(|dsk| dsk.interrupt_at())
+                .chain(self.replayer.as_ref().and_then(|rp| rp.next_interrupt()))
+                .filter(|&cycles| cycles > cpu.cycles)
+                .min();
+            let timeslice = match deadline {
+                Some(deadline) => timeslice_until(cpu.cycles, deadline),
+                None => TIMESLICE
+            };
+            match self.block_cache {
+                Some(ref mut cache) => block_cache::run(cpu, cache, timeslice),
+                None => z80::run(cpu, timeslice)
+            }
+            match cpu.stop_reason {
+                StopReason::Halt | StopReason::Illegal | StopReason::Trap(_) | StopReason::ExecCheck => {
+                    return Ok(cpu.stop_reason);
+                }
+                StopReason::Poll => {
+                    // Do nothing, yet
+                }
+                StopReason::In => {
+                    self.input(cpu)?;
+                }
+                StopReason::Out => {
+                    self.output(cpu)?;
+                }
+            }
+        }
+        Ok(StopReason::Poll)
+    }
+
+    // Signal a maskable interrupt to the CPU, see z80::interrupt(), and record
+    // it.  While replaying, interrupts come from the recording and this does
+    // nothing.  Returns true if the interrupt was accepted.
+    pub fn interrupt(&mut self, cpu: &mut Z80, data: u8) -> io::Result<bool> {
+        if self.replayer.is_some() {
+            return Ok(false);
+        }
+        if let Some(ref mut rec) = self.recorder {
+            rec.interrupt(cpu, data)?;
+        }
+        Ok(z80::interrupt(cpu, data))
+    }
+
+    // Flush the recording, if any, and check that a replay, if any, has used
+    // the whole recording.
+    pub fn finish(&mut self, cpu: &Z80) -> io::Result<()> {
+        if let Some(rec) = self.recorder.take() {
+            rec.finish()?;
+        }
+        if let Some(ref rp) = self.replayer {
+            rp.finish(cpu).map_err(divergence)?;
+        }
+        Ok(())
+    }
+
+    fn clock(&mut self, cycles: u64) {
+        for dsk in self.disks.iter_mut().flatten() {
+            dsk.clock(cycles);
+        }
+    }
+
+    fn input(&mut self, cpu: &mut Z80) -> io::Result<()> {
+        self.clock(cpu.cycles);
+        cpu.a = match self.replayer {
+            Some(ref mut rp) => rp.port_in(cpu).map_err(divergence)?,
+            None => self.port_in(cpu.port_addr)
+        };
+        if let Some(ref mut rec) = self.recorder {
+            rec.port_in(cpu, cpu.port_addr, cpu.a)?;
+        }
+        Ok(())
+    }
+
+    fn output(&mut self, cpu: &mut Z80) -> io::Result<()> {
+        self.clock(cpu.cycles);
+        let port = cpu.port_addr;
+        if let Some(ref mut rp) = self.replayer {
+            // The output goes nowhere, so that the replay needs no devices.
+            // The recording has the memory it wrote, but only the bytes that
+            // changed.
+            let before = match cpu.exec_check {
+                Some(_) if port_writes_memory(port) => Some(cpu.mem.to_vec()),
+                _ => None
+            };
+            rp.port_out(cpu).map_err(divergence)?;
+            if let (Some(before), Some(chk)) = (before, cpu.exec_check.as_mut()) {
+                chk.changed(&before, &cpu.mem);
+            }
+            return Ok(());
+        }
+
+        let before = match self.recorder {
+            Some(_) if port_writes_memory(port) => Some(cpu.mem.to_vec()),
+            _ => None
+        };
+        self.port_out(port, cpu.a, &mut cpu.mem);
+        if let Some(ref mut rec) = self.recorder {
+            rec.port_out(cpu, port, cpu.a, before.as_ref().map(|v| &v[..]))?;
+        }
+        if let Some(ref mut chk) = cpu.exec_check {
+            if let Some((addr, len)) = self.written(port) {
+                chk.load(addr, len);
+            }
+        }
+        Ok(())
+    }
+
+    // The memory written by the device behind an output port, see
+    // SpinningDisk::written().
+    fn written(&mut self, port: u8) -> Option<(u16, usize)> {
+        match port {
+            0x15 => self.disk(port).written(),
+            0x27 => self.block_device(port).written(),
+            _ => None
+        }
+    }
+
+    fn port_out(&mut self, port: u8, value: u8, mem: &mut [u8]) {
+        match port {
+            0x00 => /* CHAR_OUT (n) */ { self.tty(port).put_nonblocking(value); }
+
+            // The drives are spinning disks
+            0x10 => /* SET_HEAD (n) */ { self.disk(port).set_head(value); }
+            0x11 => /* SET_TRACK (n) */ { self.disk(port).set_track(value); }
+            0x12 => /* SET_SECTOR (n) */ { self.disk(port).set_sector(value); }
+            0x13 => /* SET_DMA_LOW (n) */ { self.disk(port).set_dma_low(value); }
+            0x14 => /* SET_DMA_HIGH (n) */ { self.disk(port).set_dma_high(value); }
+            0x15 => /* DISK_OP (n) */ { self.disk(port).disk_operation(value, mem); }
+            0x16 => /* SELECT_DISK (n) */ { self.selected_disk = value % DISKS as u8; }
+            0x17 => /* SET_TRACK_HIGH (n) */ { self.disk(port).set_track_high(value); }
+            0x18 => /* SET_SECTOR_HIGH (n) */ { self.disk(port).set_sector_high(value); }
+            0x19 => /* SET_SECTOR_COUNT (n) */ { self.disk(port).set_sector_count(value); }
+
+            // The block device
+            0x20..=0x23 => /* SET_LBA_n (n) */ { self.block_device(port).set_lba((port - 0x20) as usize, value); }
+            0x24 => /* SET_BLOCK_COUNT (n) */ { self.block_device(port).set_count(value); }
+            0x25 => /* SET_BLOCK_DMA_LOW (n) */ { self.block_device(port).set_dma_low(value); }
+            0x26 => /* SET_BLOCK_DMA_HIGH (n) */ { self.block_device(port).set_dma_high(value); }
+            0x27 => /* BLOCK_OP (n) */ { self.block_device(port).block_operation(value, mem); }
+
+            _ => /* Unknown */ { panic!("Unassigned output port {}", port); }
+        }
+    }
+
+    fn port_in(&mut self, port: u8) -> u8 {
+        match port {
+            0x00 => /* CHAR_IN */ { self.tty(port).get_nonblocking() }
+            0x01 => /* CHAR_AVAIL => 00h or FFh */ { self.tty(port).poll_nonblocking() }
+
+            // The drives are spinning disks
+            0x10 => /* DISK_RESULT */ { self.disk(port).get_status() as u8 }
+            0x11 => /* DISK_PRESENT => 00h or FFh */ {
+                if self.disks[self.selected_disk as usize].is_some() { 0xFF } else { 0x00 }
+            }
+            0x16 => /* SELECTED_DISK */ { self.selected_disk }
+
+            // The block device
+            0x20 => /* BLOCK_RESULT */ { self.block_device(port).get_status() as u8 }
+
+            _ => /* Unknown */ { panic!("Unassigned input port {}", port); }
+        }
+    }
+
+    fn tty(&mut self, port: u8) -> &mut dyn TTY {
+        match self.tty {
+            Some(ref mut tty) => &mut **tty,
+            None => { panic!("No TTY for port {}", port); }
+        }
+    }
+
+    fn disk(&mut self, port: u8) -> &mut dyn SpinningDisk {
+        let n = self.selected_disk;
+        match self.disks[n as usize] {
+            Some(ref mut dsk) => &mut **dsk,
+            None => { panic!("No disk {}: for port {}", (b'A' + n) as char, port); }
+        }
+    }
+
+    fn block_device(&mut self, port: u8) -> &mut dyn BlockDevice {
+        match self.block_device {
+            Some(ref mut dev) => &mut **dev,
+            None => { panic!("No block device for port {}", port); }
+        }
+    }
+}
+
+// True if the device behind an output port may write memory.
+
+fn port_writes_memory(port: u8) -> bool
+{
+    port == 0x15 || port == 0x27 // DISK_OP, BLOCK_OP
+}
+
+// A timeslice of the instructions that surely end by cycle `deadline`, and at
+// least one.  Repeated, this stops the CPU exactly at `deadline` if an
+// instruction ends there.
+
+fn timeslice_until(cycles: u64, deadline: u64) -> usize
+{
+    let instructions = (deadline.saturating_sub(cycles) / MAX_INSTRUCTION_CYCLES).clamp(1, TIMESLICE as u64 - 1);
+    instructions as usize + 1
+}
+
+fn divergence(msg: String) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
@@ -1,15 +1,15 @@
-mod z80;
-mod devices;
-mod rust_console_io;
-mod file_backed_spinning_disk;
+extern crate z80emu;
 
-use std::fs::OpenOptions;
+use std::env;
+use std::fs::{File, OpenOptions};
 use std::io::Read;
+use std::process;
 
-use z80::StopReason;
-use devices::{TTY, SpinningDisk};
+use z80emu::{block_cache, coverage, cpm, disk_image, exec_check, file_backed_block_device, file_backed_spinning_disk,
+             machine, ram_disk, record_replay, rng, rust_console_io, save_state, snapshot, z80};
+use z80emu::file_backed_spinning_disk::Timing;
+use z80emu::z80::StopReason;
 
-const TIMESLICE : usize = 10000;
 const ROM_SIZE : usize = 128;
 const ROM_ADDR : usize = 0x10000 - ROM_SIZE;
 
@@ -19,88 +19,463 @@ const A_HEADS   : u8 = 1;       // Single sector
 const A_TRACKS  : u8 = 1;       //   disk for
 const A_SECTORS : u8 = 1;       //     testing
 
-// Container for physical devices
+// Command line options
 
-struct Machine<'a> {
-    tty:   &'a mut TTY,
-    dsk_a: &'a mut SpinningDisk,
-    // Many more physical devices here
+#[derive(Default)]
+struct Options {
+    coverage:    Option<String>,  // lcov output file
+    source_maps: Vec<String>,     // z80asm source maps, for coverage
+    load_state:  Option<String>,  // Save state to resume from
+    save_state:  Option<String>,  // Save state to write on halt
+    load_snapshot: Option<String>,  // .sna or .z80 snapshot to load
+    save_snapshot: Option<String>,  // .sna or .z80 snapshot to write on halt
+    snapshot_128k: bool,          //   and write .sna snapshots as 128K
+    record:      Option<String>,  // Log of all input to write
+    replay:      Option<String>,  // Log of all input to replay
+    block_cache: bool,            // Run with a block cache
+    block_cache_check: bool,      //   and check it against the interpreter
+    cpm:         Option<String>,  // CP/M .COM program to run instead of booting
+    power_on:    Option<u64>,     // Seed for random power-on registers
+    random_memory: bool,          //   and memory
+    exec_check:  bool,            // Report self-modifying code and execution of unwritten memory
+    exec_check_stop: bool,        //   and stop at the first
+    trap:        Option<u8>,      // Trap opcode, see z80::Z80::trap
+    disks:       Vec<DiskSpec>,   // Disks other than the default A:
+    ram_disks:   Vec<DiskSpec>,   // RAM disks, with optional image files
+    save_ram_disks: bool,         //   to be saved on halt
+    disk_timing: Option<Timing>,  // Operation times for all disks
+    write_protect: Vec<usize>,    // Write protected disks, 0-15 for A: to P:
+    read_only:   Vec<usize>,      // Disks whose files are opened read-only
+    overlay:     bool,            // Send disk writes to overlays, see disk_image
+    overlay_commit: bool,         //   and commit them on halt
+    block_device: Option<(String, u32)>,  // Block device image and number of blocks
 }
 
-fn main()
-{
-    let mut _dsk_a = file_backed_spinning_disk::make("a_drive.bin", A_HEADS, A_TRACKS, A_SECTORS);
-    let mut _tty = rust_console_io::make();
-
-    let mut m = Machine {
-        tty:   &mut _tty,
-        dsk_a: &mut _dsk_a,
-    };
-
-    // We have boot ROM in high memory.  The rest of the memory (before that)
-    // will be filled with zeroes, ie NOPs, so after reset we'll just execute
-    // NOPs until we get to the ROM.  However we cheat here by just setting the
-    // initial PC to the ROM address, it simplifies debugging the emulator.
+// A disk image and its geometry
+struct DiskSpec {
+    drive:       usize,           // 0-15 for A: to P:
+    filename:    String,
+    heads:       u8,
+    tracks:      u16,
+    sectors:     u16,
+    sector_size: usize,
+}
 
-    let mut cpu = z80::make(/*pc=*/ ROM_ADDR as u16);
+fn usage() -> ! {
+    eprintln!("Usage: z80emu [options]");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --coverage <file>     Write lcov coverage data to <file> on halt");
+    eprintln!("  --source-map <file>   Load a z80asm source map for coverage (repeatable)");
+    eprintln!("  --load-state <file>   Resume from a save state instead of booting");
+    eprintln!("  --save-state <file>   Write a save state to <file> on halt");
+    eprintln!("  --load-snapshot <file>  Load a .sna or .z80 snapshot after booting");
+    eprintln!("  --save-snapshot <file>  Write a .sna or .z80 snapshot to <file> on halt");
+    eprintln!("  --snapshot-128k       Write .sna snapshots in the 128K layout, without pushing PC");
+    eprintln!("  --record <file>       Record all input to <file>");
+    eprintln!("  --replay <file>       Replay the input recorded in <file> instead of using the devices,");
+    eprintln!("                        without console output");
+    eprintln!("  --block-cache         Run with a basic-block cache");
+    eprintln!("  --block-cache-check   Run with a block cache, checking it against the interpreter");
+    eprintln!("  --cpm <file>          Run a CP/M .COM program with console output only, see cpm.rs");
+    eprintln!("  --power-on <seed>     Boot with random registers from <seed>, see z80::power_on");
+    eprintln!("  --random-memory       With --power-on, randomize memory too");
+    eprintln!("  --exec-check          Report self-modifying code and execution of unwritten memory");
+    eprintln!("  --exec-check-stop     As --exec-check, but stop with an error at the first finding");
+    eprintln!("  --trap <opcode>       Make ED <opcode> n a trap, eg 254; the emulator provides no");
+    eprintln!("                        trap services, so a trap stops it with an error giving n");
+    eprintln!("  --disk <d>:<file>,<heads>,<tracks>,<sectors>[,<sector size>]");
+    eprintln!("                        Attach <file> as disk <d>, A to P (repeatable); the default");
+    eprintln!("                        is A:a_drive.bin,1,1,1; up to 255 heads and 65535 tracks and");
+    eprintln!("                        sectors, see disk_controller.rs");
+    eprintln!("  --ram-disk <d>:[<file>],<heads>,<tracks>,<sectors>[,<sector size>]");
+    eprintln!("                        Attach a RAM disk as disk <d> (repeatable), loaded from <file>");
+    eprintln!("                        if given");
+    eprintln!("  --save-ram-disks      Save RAM disks to their files on halt, and before stopping with");
+    eprintln!("                        an error, as their writes are otherwise lost");
+    eprintln!("  --write-protect <d>   Write protect disk <d> (repeatable); disks with read-only files");
+    eprintln!("                        are always write protected");
+    eprintln!("  --read-only <d>       Open the file of disk <d> read-only (repeatable), so that the");
+    eprintln!("                        disk is write protected unless it has an overlay; not for RAM");
+    eprintln!("                        disks");
+    eprintln!("  --overlay             Keep disk images unmodified, discarding the guest's writes on exit");
+    eprintln!("  --overlay-commit      As --overlay, but write the changes to the images on halt");
+    eprintln!("  --disk-timing <step>,<rotation>,<transfer>[,<interrupt data>]");
+    eprintln!("                        Make disk operations take time, in cycles per track stepped,");
+    eprintln!("                        per revolution, and per sector transferred, and optionally");
+    eprintln!("                        interrupt on completion; RAM disks still complete at once");
+    eprintln!("  --block-device <file>,<blocks>");
+    eprintln!("                        Attach <file> as a block device of 512-byte blocks");
+    process::exit(1);
+}
 
-    setup_boot_rom(&mut cpu.mem[ROM_ADDR .. ROM_ADDR + ROM_SIZE]);
-    
-    loop {
-        z80::run(&mut cpu, TIMESLICE);
-        match cpu.stop_reason {
-            StopReason::Halt => {
-                break;
+fn parse_options() -> Options
+{
+    let mut opts = Options::default();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--coverage" => { opts.coverage = Some(args.next().unwrap_or_else(|| usage())); }
+            "--source-map" => { opts.source_maps.push(args.next().unwrap_or_else(|| usage())); }
+            "--load-state" => { opts.load_state = Some(args.next().unwrap_or_else(|| usage())); }
+            "--save-state" => { opts.save_state = Some(args.next().unwrap_or_else(|| usage())); }
+            "--load-snapshot" => { opts.load_snapshot = Some(args.next().unwrap_or_else(|| usage())); }
+            "--save-snapshot" => { opts.save_snapshot = Some(args.next().unwrap_or_else(|| usage())); }
+            "--snapshot-128k" => { opts.snapshot_128k = true; }
+            "--record" => { opts.record = Some(args.next().unwrap_or_else(|| usage())); }
+            "--replay" => { opts.replay = Some(args.next().unwrap_or_else(|| usage())); }
+            "--block-cache" => { opts.block_cache = true; }
+            "--block-cache-check" => { opts.block_cache_check = true; }
+            "--cpm" => { opts.cpm = Some(args.next().unwrap_or_else(|| usage())); }
+            "--power-on" => {
+                opts.power_on = Some(args.next().and_then(|s| s.parse().ok()).unwrap_or_else(|| usage()));
             }
-            StopReason::Poll => {
-                // Do nothing, yet
+            "--random-memory" => { opts.random_memory = true; }
+            "--exec-check" => { opts.exec_check = true; }
+            "--exec-check-stop" => { opts.exec_check_stop = true; }
+            "--trap" => { opts.trap = Some(args.next().and_then(|s| s.parse().ok()).unwrap_or_else(|| usage())); }
+            "--disk" => { opts.disks.push(args.next().and_then(|s| parse_disk(&s)).unwrap_or_else(|| usage())); }
+            "--ram-disk" => {
+                opts.ram_disks.push(args.next().and_then(|s| parse_disk(&s)).unwrap_or_else(|| usage()));
             }
-            StopReason::In => {
-                cpu.a = port_in(cpu.port_addr, &mut m);
+            "--save-ram-disks" => { opts.save_ram_disks = true; }
+            "--write-protect" => {
+                opts.write_protect.push(args.next().and_then(|s| parse_drive(&s)).unwrap_or_else(|| usage()));
             }
-            StopReason::Out => {
-                port_out(cpu.port_addr, cpu.a, &mut cpu.mem, &mut m);
+            "--read-only" => {
+                opts.read_only.push(args.next().and_then(|s| parse_drive(&s)).unwrap_or_else(|| usage()));
             }
-            StopReason::Illegal => {
-                panic!("Illegal instruction");
+            "--overlay" => { opts.overlay = true; }
+            "--overlay-commit" => { opts.overlay_commit = true; }
+            "--disk-timing" => {
+                opts.disk_timing = Some(args.next().and_then(|s| parse_disk_timing(&s)).unwrap_or_else(|| usage()));
             }
+            "--block-device" => {
+                opts.block_device = Some(args.next().and_then(|s| parse_block_device(&s)).unwrap_or_else(|| usage()));
+            }
+            _ => { usage(); }
         }
     }
+    if opts.random_memory && opts.power_on.is_none() {
+        usage();
+    }
+    if opts.ram_disks.iter().any(|d| opts.read_only.contains(&d.drive)) {
+        usage();
+    }
+    opts
 }
 
-fn setup_boot_rom(mem: &mut [u8])
+// Parse "<d>:<file>,<heads>,<tracks>,<sectors>[,<sector size>]".
+
+fn parse_disk(spec: &str) -> Option<DiskSpec>
 {
-    OpenOptions::new().read(true)
-        .open("rom.bin").expect("Could not open `rom.bin`")
-        .read(mem).expect("Could not read `rom.bin`");
+    let colon = spec.find(':')?;
+    let drive = parse_drive(&spec[..colon])?;
+    let fields = spec[colon + 1..].split(',').collect::<Vec<_>>();
+    if fields.len() != 4 && fields.len() != 5 {
+        return None;
+    }
+    Some(DiskSpec {
+        drive,
+        filename: fields[0].to_string(),
+        heads: fields[1].parse().ok()?,
+        tracks: fields[2].parse().ok()?,
+        sectors: fields[3].parse().ok()?,
+        sector_size: if fields.len() == 5 { fields[4].parse().ok()? } else { 128 },
+    })
+}
+
+// Exit with an error if the disk's geometry is not valid.
+
+fn check_geometry(d: &DiskSpec)
+{
+    if !file_backed_spinning_disk::SECTOR_SIZES.contains(&d.sector_size) || d.heads == 0 || d.tracks == 0
+        || d.sectors == 0 {
+        eprintln!("Bad geometry for disk {}:", (b'A' + d.drive as u8) as char);
+        process::exit(1);
+    }
+}
+
+// Parse a drive letter, A to P, as 0-15.
+
+fn parse_drive(spec: &str) -> Option<usize>
+{
+    match *spec.as_bytes() {
+        [d @ b'A'..=b'P'] => Some((d - b'A') as usize),
+        [d @ b'a'..=b'p'] => Some((d - b'a') as usize),
+        _ => None
+    }
+}
+
+// Parse "<step>,<rotation>,<transfer>[,<interrupt data>]".
+
+fn parse_disk_timing(spec: &str) -> Option<Timing>
+{
+    let fields = spec.split(',').collect::<Vec<_>>();
+    if fields.len() != 3 && fields.len() != 4 {
+        return None;
+    }
+    let timing = Timing {
+        step: fields[0].parse().ok()?,
+        rotation: fields[1].parse().ok()?,
+        transfer: fields[2].parse().ok()?,
+        interrupt: if fields.len() == 4 { Some(fields[3].parse().ok()?) } else { None },
+    };
+    if timing.rotation == 0 {
+        return None;
+    }
+    Some(timing)
 }
 
-fn port_out(port: u8, value: u8, mem: &mut [u8], m: &mut Machine)
+// Parse "<file>,<blocks>".
+
+fn parse_block_device(spec: &str) -> Option<(String, u32)>
 {
-    match port {
-        0x00 => /* CHAR_OUT (n) */ { m.tty.put_nonblocking(value); }
+    let comma = spec.rfind(',')?;
+    let blocks = spec[comma + 1..].parse().ok()?;
+    if blocks == 0 {
+        return None;
+    }
+    Some((spec[..comma].to_string(), blocks))
+}
+
+fn main()
+{
+    let opts = parse_options();
+
+    if let Some(ref filename) = opts.cpm {
+        run_cpm(filename);
+        return;
+    }
+
+    let open_image = |drive: usize, filename: &str| {
+        if opts.read_only.contains(&drive) {
+            disk_image::open_read_only(filename)
+        } else {
+            disk_image::open(filename)
+        }.unwrap_or_else(|e| panic!("Could not open `{}`: {}", filename, e))
+    };
+    let mut disks = vec![];
+    if !opts.disks.iter().chain(&opts.ram_disks).any(|d| d.drive == 0) {
+        disks.push((0, file_backed_spinning_disk::make_from_image(open_image(0, "a_drive.bin"), A_HEADS,
+                                                                  A_TRACKS as u16, A_SECTORS as u16, 128)));
+    }
+    for d in &opts.disks {
+        check_geometry(d);
+        disks.push((d.drive, file_backed_spinning_disk::make_from_image(open_image(d.drive, &d.filename), d.heads,
+                                                                        d.tracks, d.sectors, d.sector_size)));
+    }
+    for &mut (drive, ref mut dsk) in disks.iter_mut() {
+        dsk.set_write_protect(opts.write_protect.contains(&drive));
+        if opts.overlay || opts.overlay_commit {
+            dsk.image().add_overlay();
+        }
+    }
+    if let Some(timing) = opts.disk_timing {
+        for &mut (_, ref mut dsk) in disks.iter_mut() {
+            dsk.set_timing(timing);
+        }
+    }
+    let mut ram_disks = opts.ram_disks.iter().map(|d| {
+        check_geometry(d);
+        let mut dsk = if d.filename.is_empty() {
+            ram_disk::make(d.heads, d.tracks, d.sectors, d.sector_size)
+        } else {
+            ram_disk::load(&d.filename, d.heads, d.tracks, d.sectors, d.sector_size)
+                .unwrap_or_else(|e| panic!("Could not load `{}`: {}", d.filename, e))
+        };
+        dsk.set_write_protect(opts.write_protect.contains(&d.drive));
+        (d.drive, dsk)
+    }).collect::<Vec<_>>();
+    let mut block_device = opts.block_device.as_ref()
+        .map(|&(ref filename, blocks)| {
+            let image = disk_image::open(filename).unwrap_or_else(|e| panic!("Could not open `{}`: {}", filename, e));
+            file_backed_block_device::make_from_image(image, blocks)
+        });
+    if let Some(ref mut dev) = block_device {
+        if opts.overlay || opts.overlay_commit {
+            dev.image().add_overlay();
+        }
+    }
+    let mut tty = rust_console_io::make();
+
+    // We have boot ROM in high memory.  The rest of the memory (before that)
+    // is filled with zeroes, ie NOPs, so after reset a real CPU would execute
+    // NOPs until it got to the ROM.  With --random-memory it holds random
+    // bytes instead, which a real CPU would execute as code.  Either way we
+    // cheat here by just setting the initial PC to the ROM address, it
+    // simplifies debugging the emulator.
+
+    let mut cpu = match opts.power_on {
+        Some(seed) => z80::power_on(&mut rng::make(seed), opts.random_memory),
+        None => z80::make(0)
+    };
+    cpu.pc = ROM_ADDR as u16;
+    cpu.trap = opts.trap;
+
+    setup_boot_rom(&mut cpu.mem[ROM_ADDR .. ROM_ADDR + ROM_SIZE]);
+
+    let mut builder = machine::builder().tty(&mut tty);
+    for &mut (drive, ref mut dsk) in disks.iter_mut() {
+        builder = builder.disk(drive, dsk);
+    }
+    for &mut (drive, ref mut dsk) in ram_disks.iter_mut() {
+        builder = builder.disk(drive, dsk);
+    }
+    if let Some(ref mut dev) = block_device {
+        builder = builder.block_device(dev);
+    }
+    if opts.block_cache_check {
+        builder = builder.block_cache(block_cache::make_differential());
+    } else if opts.block_cache {
+        builder = builder.block_cache(block_cache::make());
+    }
+    let mut m = builder.build();
+
+    if let Some(ref filename) = opts.load_state {
+        save_state::restore(filename, &mut cpu, &mut m)
+            .unwrap_or_else(|e| panic!("Could not load `{}`: {}", filename, e));
+    }
+
+    if let Some(ref filename) = opts.load_snapshot {
+        snapshot::load(filename, &mut cpu)
+            .unwrap_or_else(|e| panic!("Could not load `{}`: {}", filename, e));
+    }
+
+    if opts.coverage.is_some() {
+        cpu.coverage = Some(Box::new(coverage::make()));
+    }
+
+    if opts.exec_check || opts.exec_check_stop {
+        let mut chk = if opts.exec_check_stop { exec_check::make_stopping() } else { exec_check::make() };
+        if opts.load_state.is_some() || opts.load_snapshot.is_some() {
+            chk.load(0, 65536);
+        } else {
+            chk.load(ROM_ADDR as u16, ROM_SIZE);
+        }
+        cpu.exec_check = Some(Box::new(chk));
+    }
+
+    if let Some(ref filename) = opts.record {
+        m.record(record_replay::make_recorder(filename, &cpu)
+                 .unwrap_or_else(|e| panic!("Could not create `{}`: {}", filename, e)));
+    }
+
+    if let Some(ref filename) = opts.replay {
+        m.replay(record_replay::make_replayer(filename, &cpu)
+                 .unwrap_or_else(|e| panic!("Could not load `{}`: {}", filename, e)));
+    }
+
+    let stop = m.run(&mut cpu, u64::MAX).unwrap_or_else(|e| panic!("{}", e));
+    m.finish(&cpu).unwrap_or_else(|e| panic!("{}", e));
+
+    // Stops other than halting are errors.  The trap number is the operand of
+    // the ED t n that the CPU has just executed.
+    let error = match stop {
+        StopReason::Illegal => Some("Illegal instruction".to_string()),
+        StopReason::Trap(n) => Some(format!("Unhandled trap {} at {:04X}h", n, cpu.pc.wrapping_sub(3))),
+        StopReason::ExecCheck => Some("Stopped by the exec check".to_string()),
+        _ => None
+    };
+    if let Some(error) = error {
+        drop(m);
+        if opts.save_ram_disks {
+            save_ram_disks(&opts.ram_disks, &ram_disks);
+        }
+        if let Some(ref chk) = cpu.exec_check {
+            report_exec_check(chk);
+        }
+        panic!("{}", error);
+    }
+
+    if let Some(ref filename) = opts.save_state {
+        save_state::save(filename, &cpu, &m)
+            .unwrap_or_else(|e| panic!("Could not write `{}`: {}", filename, e));
+    }
+
+    if let Some(ref filename) = opts.save_snapshot {
+        snapshot::save(filename, &cpu, opts.snapshot_128k)
+            .unwrap_or_else(|e| panic!("Could not write `{}`: {}", filename, e));
+    }
 
-        // "A" drive is a spinning disk
-        0x10 => /* SET_HEAD (n) */ { m.dsk_a.set_head(value); }
-        0x11 => /* SET_TRACK (n) */ { m.dsk_a.set_track(value); }
-        0x12 => /* SET_SECTOR (n) */ { m.dsk_a.set_sector(value); }
-        0x13 => /* SET_DMA_LOW (n) */ { m.dsk_a.set_dma_low(value); }
-        0x14 => /* SET_DMA_HIGH (n) */ { m.dsk_a.set_dma_high(value); }
-        0x15 => /* DISK_OP (n) */ { m.dsk_a.disk_operation(value, mem); }
+    if opts.overlay_commit {
+        for &mut (drive, ref mut dsk) in disks.iter_mut() {
+            dsk.image().commit()
+                .unwrap_or_else(|e| panic!("Could not commit disk {}: {}", (b'A' + drive as u8) as char, e));
+        }
+        if let Some(ref mut dev) = block_device {
+            dev.image().commit().unwrap_or_else(|e| panic!("Could not commit the block device: {}", e));
+        }
+    }
+
+    if opts.save_ram_disks {
+        save_ram_disks(&opts.ram_disks, &ram_disks);
+    }
+
+    if let Some(ref filename) = opts.coverage {
+        write_coverage(&cpu, filename, &opts.source_maps);
+    }
 
-        _ => /* Unknown */ { panic!("Unassigned output port {}", port); }
+    if let Some(ref chk) = cpu.exec_check {
+        report_exec_check(chk);
     }
 }
 
-fn port_in(port: u8, m: &mut Machine) -> u8
+// Save the RAM disks that were given files to their files.
+
+fn save_ram_disks(specs: &[DiskSpec], ram_disks: &[(usize, ram_disk::RamDisk)])
 {
-    match port {
-        0x00 => /* CHAR_IN */ { m.tty.get_nonblocking() }
-        0x01 => /* CHAR_AVAIL => 00h or FFh */ { m.tty.poll_nonblocking() }
+    for (d, (_, dsk)) in specs.iter().zip(ram_disks) {
+        if !d.filename.is_empty() {
+            dsk.save(&d.filename).unwrap_or_else(|e| panic!("Could not write `{}`: {}", d.filename, e));
+        }
+    }
+}
 
-        // "A" drive is a spinning disk
-        0x10 => /* DISK_RESULT */ { m.dsk_a.get_status() as u8 }
+fn run_cpm(filename: &str)
+{
+    let mut program = vec![];
+    File::open(filename).and_then(|mut f| f.read_to_end(&mut program))
+        .unwrap_or_else(|e| panic!("Could not read `{}`: {}", filename, e));
+    let mut cpu = cpm::make(&program);
+    let mut tty = rust_console_io::make();
+    match cpm::run(&mut cpu, u64::MAX, &mut tty) {
+        cpm::Exit::WarmBoot => {}
+        exit => {
+            eprintln!("\n{} stopped: {:?}", filename, exit);
+            process::exit(1);
+        }
+    }
+}
 
-        _ => /* Unknown */ { panic!("Unassigned input port {}", port); }
+fn write_coverage(cpu: &z80::Z80, filename: &str, source_maps: &[String])
+{
+    let mut map = coverage::make_source_map();
+    for name in source_maps {
+        map.load(name).unwrap_or_else(|e| panic!("Could not load `{}`: {}", name, e));
+    }
+    let mut out = File::create(filename).unwrap_or_else(|e| panic!("Could not create `{}`: {}", filename, e));
+    cpu.coverage.as_ref().unwrap().write_lcov(&mut out, &map)
+        .unwrap_or_else(|e| panic!("Could not write `{}`: {}", filename, e));
+}
+
+fn report_exec_check(chk: &exec_check::ExecCheck)
+{
+    for finding in chk.findings() {
+        match finding.kind {
+            exec_check::Kind::SelfModified => {
+                eprintln!("Executed modified code at {:04X}h: byte {:04X}h", finding.pc, finding.addr);
+            }
+            exec_check::Kind::NeverWritten => {
+                eprintln!("Executed unwritten memory at {:04X}h", finding.pc);
+            }
+        }
     }
 }
+
+fn setup_boot_rom(mem: &mut [u8])
+{
+    OpenOptions::new().read(true)
+        .open("rom.bin").expect("Could not open `rom.bin`")
+        .read_exact(mem).expect("Could not read `rom.bin`");
+}
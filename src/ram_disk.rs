@@ -0,0 +1,67 @@
+// A RAM disk is a spinning disk held in memory, for scratch drives and for
+// tests that should not touch the file system.
+//
+// It is a disk_controller::DiskController on a vector, so it has the geometry,
+// the sector layout, and the commands of a file backed spinning disk,
+// including multi-sector transfers, write protection, and timing.  Its
+// operations cannot fail with a read or write error.
+//
+// The disk starts zeroed, or with the contents of an image, which may be
+// shorter than the disk.  With the `std` feature the image can be loaded from
+// a file and the contents saved to one.
+//
+// The contents are part of the saved state, after the controller's, so a save
+// state holds the whole disk.  A state without them, from before they were
+// saved or from a file backed disk, leaves the contents as they are.
+
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::io;
+
+use disk_controller::{self, DiskController};
+
+pub type RamDisk = DiskController<Vec<u8>>;
+
+// A zeroed disk.  Panics as disk_controller::make() does.
+
+pub fn make(heads: u8, tracks: u16, sectors: u16, sector_size: usize) -> RamDisk
+{
+    let size = heads as usize * tracks as usize * sectors as usize * sector_size;
+    disk_controller::make(vec![0; size], heads, tracks, sectors, sector_size)
+}
+
+// A disk holding `image`, zero-filled past its end.  Panics as make() does, or
+// if the image is larger than the disk.
+
+pub fn make_from_image(image: &[u8], heads: u8, tracks: u16, sectors: u16, sector_size: usize) -> RamDisk
+{
+    let mut dsk = make(heads, tracks, sectors, sector_size);
+    assert!(image.len() <= dsk.storage.len(), "Image is larger than the disk");
+    dsk.storage[..image.len()].copy_from_slice(image);
+    dsk
+}
+
+// A disk holding the image in `filename`, as make_from_image().
+
+#[cfg(feature = "std")]
+pub fn load(filename: &str, heads: u8, tracks: u16, sectors: u16, sector_size: usize) -> io::Result<RamDisk>
+{
+    Ok(make_from_image(&fs::read(filename)?, heads, tracks, sectors, sector_size))
+}
+
+impl RamDisk
+{
+    // The contents of the whole disk.
+    pub fn contents(&self) -> &[u8] {
+        &self.storage
+    }
+
+    // Write the contents of the whole disk to `filename`.
+    #[cfg(feature = "std")]
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        fs::write(filename, &self.storage)
+    }
+}
@@ -0,0 +1,298 @@
+// Deterministic record and replay of the machine's I/O.
+//
+// The CPU is deterministic, so an execution is fully determined by the initial
+// machine state and by whatever flows into the machine from the outside: the
+// values returned by IN, memory written by devices (disk reads, by DMA), and
+// interrupts.  A recording logs all of those, along with every OUT so that a
+// replay can detect when it diverges from the recording.
+//
+// During replay the input values and memory writes come from the log, and the
+// output goes nowhere, so the devices are not used and the machine needs none.
+// In particular the console output does not appear.  Every port access is
+// checked against the log; the first access that does not match the recording
+// (a different direction, port, value, or cycle count) is reported as a
+// divergence.  The machine stops the CPU at the cycle of the next recorded
+// interrupt, see next_interrupt(), so a replay does not depend on where the
+// recording's timeslices ended; running past that cycle without reaching it is
+// also a divergence.
+//
+// The replay must start from the same machine state as the recording, and the
+// log starts with a checksum of the CPU state to catch mistakes.
+//
+// File format, all multi-byte values little-endian:
+//
+//   magic      "Z80EMURR"
+//   version    u16
+//   checksum   u64, FNV-1a hash of the initial CPU state and memory
+//   events     tag (u8) followed by the event's fields in declaration order,
+//              cycles as u64, addresses and lengths as u16, data as bytes
+//
+// Version history:
+//   1 - Initial version
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+use binfile;
+use z80::Z80;
+
+const MAGIC: &[u8; 8] = b"Z80EMURR";
+const VERSION: u16 = 1;
+
+const TAG_IN: u8 = 0;
+const TAG_OUT: u8 = 1;
+const TAG_MEMORY: u8 = 2;
+const TAG_INTERRUPT: u8 = 3;
+
+#[derive(PartialEq)]
+pub enum Event {
+    In { cycles: u64, port: u8, value: u8 },
+    Out { cycles: u64, port: u8, value: u8 },
+    Memory { addr: u16, data: Vec<u8> },     // Written by the preceding Out
+    Interrupt { cycles: u64, data: u8 },
+}
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// Recording
+
+pub struct Recorder
+{
+    out: BufWriter<File>,
+}
+
+pub fn make_recorder(filename: &str, cpu: &Z80) -> io::Result<Recorder>
+{
+    let mut out = BufWriter::new(File::create(filename)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&state_hash(cpu).to_le_bytes())?;
+    Ok(Recorder { out })
+}
+
+impl Recorder
+{
+    pub fn port_in(&mut self, cpu: &Z80, port: u8, value: u8) -> io::Result<()> {
+        self.write(&Event::In { cycles: cpu.cycles, port, value })
+    }
+
+    // `before` is the memory as it was before the OUT, if the port's device
+    // may write memory; the differences are logged.
+    pub fn port_out(&mut self, cpu: &Z80, port: u8, value: u8, before: Option<&[u8]>) -> io::Result<()> {
+        self.write(&Event::Out { cycles: cpu.cycles, port, value })?;
+        if let Some(before) = before {
+            let mut i = 0;
+            while i < before.len() {
+                if before[i] == cpu.mem[i] {
+                    i += 1;
+                    continue;
+                }
+                let start = i;
+                while i < before.len() && before[i] != cpu.mem[i] && i - start < 0xFFFF {
+                    i += 1;
+                }
+                self.write(&Event::Memory { addr: start as u16, data: cpu.mem[start..i].to_vec() })?;
+            }
+        }
+        Ok(())
+    }
+
+    // To be called just before an interrupt is signalled to the CPU.
+    pub fn interrupt(&mut self, cpu: &Z80, data: u8) -> io::Result<()> {
+        self.write(&Event::Interrupt { cycles: cpu.cycles, data })
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn write(&mut self, event: &Event) -> io::Result<()> {
+        match *event {
+            Event::In { cycles, port, value } => {
+                self.out.write_all(&[TAG_IN])?;
+                self.out.write_all(&cycles.to_le_bytes())?;
+                self.out.write_all(&[port, value])
+            }
+            Event::Out { cycles, port, value } => {
+                self.out.write_all(&[TAG_OUT])?;
+                self.out.write_all(&cycles.to_le_bytes())?;
+                self.out.write_all(&[port, value])
+            }
+            Event::Memory { addr, ref data } => {
+                self.out.write_all(&[TAG_MEMORY])?;
+                self.out.write_all(&addr.to_le_bytes())?;
+                self.out.write_all(&(data.len() as u16).to_le_bytes())?;
+                self.out.write_all(data)
+            }
+            Event::Interrupt { cycles, data } => {
+                self.out.write_all(&[TAG_INTERRUPT])?;
+                self.out.write_all(&cycles.to_le_bytes())?;
+                self.out.write_all(&[data])
+            }
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// Replay
+
+pub struct Replayer
+{
+    events: Vec<Event>,
+    next: usize,
+}
+
+pub fn make_replayer(filename: &str, cpu: &Z80) -> io::Result<Replayer>
+{
+    let mut data = vec![];
+    File::open(filename)?.read_to_end(&mut data)?;
+    let mut r = binfile::make_reader(&data);
+
+    if r.bytes(MAGIC.len())? != MAGIC {
+        return Err(bad_log("not a recording"));
+    }
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(bad_log(&format!("unsupported version {}", version)));
+    }
+    if r.u64()? != state_hash(cpu) {
+        return Err(bad_log("the recording was made from a different initial state"));
+    }
+
+    let mut events = vec![];
+    while !r.at_end() {
+        let event = match r.u8()? {
+            TAG_IN => Event::In { cycles: r.u64()?, port: r.u8()?, value: r.u8()? },
+            TAG_OUT => Event::Out { cycles: r.u64()?, port: r.u8()?, value: r.u8()? },
+            TAG_MEMORY => {
+                let addr = r.u16()?;
+                let len = r.u16()? as usize;
+                if addr as usize + len > 65536 {
+                    return Err(bad_log("memory write out of range"));
+                }
+                Event::Memory { addr, data: r.bytes(len)?.to_vec() }
+            }
+            TAG_INTERRUPT => Event::Interrupt { cycles: r.u64()?, data: r.u8()? },
+            tag => { return Err(bad_log(&format!("unknown event {}", tag))); }
+        };
+        events.push(event);
+    }
+    Ok(Replayer { events, next: 0 })
+}
+
+impl Replayer
+{
+    // The value to return for the IN the CPU has stopped at.
+    pub fn port_in(&mut self, cpu: &Z80) -> Result<u8, String> {
+        match self.events.get(self.next) {
+            Some(&Event::In { cycles, port, value }) if cycles == cpu.cycles && port == cpu.port_addr => {
+                self.next += 1;
+                Ok(value)
+            }
+            _ => {
+                let actual = format!("IN from port {:02X}h at cycle {}", cpu.port_addr, cpu.cycles);
+                Err(self.divergence(&actual))
+            }
+        }
+    }
+
+    // Check the OUT the CPU has stopped at, and apply the memory writes that
+    // resulted from it.
+    pub fn port_out(&mut self, cpu: &mut Z80) -> Result<(), String> {
+        let out = Event::Out { cycles: cpu.cycles, port: cpu.port_addr, value: cpu.a };
+        if self.events.get(self.next) != Some(&out) {
+            return Err(self.divergence(&describe(&out)));
+        }
+        self.next += 1;
+        while let Some(&Event::Memory { addr, ref data }) = self.events.get(self.next) {
+            cpu.mem[addr as usize..addr as usize + data.len()].copy_from_slice(data);
+            self.next += 1;
+        }
+        Ok(())
+    }
+
+    // The cycle of the next recorded interrupt, if nothing comes before it.
+    // The CPU must be stopped exactly there for interrupt() to signal it.
+    pub fn next_interrupt(&self) -> Option<u64> {
+        match self.events.get(self.next) {
+            Some(&Event::Interrupt { cycles, .. }) => Some(cycles),
+            _ => None
+        }
+    }
+
+    // The interrupt to signal now, if the recording has one at this point.
+    pub fn interrupt(&mut self, cpu: &Z80) -> Result<Option<u8>, String> {
+        match self.events.get(self.next) {
+            Some(&Event::Interrupt { cycles, data }) if cycles == cpu.cycles => {
+                self.next += 1;
+                Ok(Some(data))
+            }
+            Some(&Event::Interrupt { cycles, .. }) if cycles < cpu.cycles => {
+                let actual = format!("no interrupt by cycle {}", cpu.cycles);
+                Err(self.divergence(&actual))
+            }
+            _ => Ok(None)
+        }
+    }
+
+    // To be called when the machine halts: the whole log must have been used.
+    pub fn finish(&self, cpu: &Z80) -> Result<(), String> {
+        if self.next < self.events.len() {
+            let actual = format!("stop at cycle {}", cpu.cycles);
+            return Err(self.divergence(&actual));
+        }
+        Ok(())
+    }
+
+    fn divergence(&self, actual: &str) -> String {
+        match self.events.get(self.next) {
+            Some(expected) => format!("Replay diverged at event #{}: expected {}, got {}",
+                                      self.next, describe(expected), actual),
+            None => format!("Replay diverged after the end of the recording: got {}", actual)
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// Utilities
+
+fn describe(event: &Event) -> String
+{
+    match *event {
+        Event::In { cycles, port, value } =>
+            format!("IN from port {:02X}h at cycle {} (value {:02X}h)", port, cycles, value),
+        Event::Out { cycles, port, value } =>
+            format!("OUT {:02X}h to port {:02X}h at cycle {}", value, port, cycles),
+        Event::Memory { addr, ref data } =>
+            format!("memory write of {} bytes at {:04X}h", data.len(), addr),
+        Event::Interrupt { cycles, data } =>
+            format!("interrupt with data {:02X}h at cycle {}", data, cycles),
+    }
+}
+
+fn state_hash(cpu: &Z80) -> u64
+{
+    let mut regs = vec![
+        cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l,
+        cpu.a_alt, cpu.f_alt, cpu.b_alt, cpu.c_alt, cpu.d_alt, cpu.e_alt, cpu.h_alt, cpu.l_alt,
+        cpu.i, cpu.r, cpu.im, cpu.iff1 as u8, cpu.iff2 as u8,
+    ];
+    for w in &[cpu.pc, cpu.sp, cpu.ix, cpu.iy] {
+        regs.extend_from_slice(&w.to_le_bytes());
+    }
+    regs.extend_from_slice(&cpu.cycles.to_le_bytes());
+
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in regs.iter().chain(cpu.mem.iter()) {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h
+}
+
+fn bad_log(msg: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, format!("Bad recording: {}", msg))
+}
@@ -0,0 +1,45 @@
+// A small seedable pseudo-random number generator, for state that is undefined
+// on real hardware, see z80::power_on().  The same seed always gives the same
+// sequence, so a failure seen with random state can be reproduced.
+//
+// This is xorshift64* seeded through splitmix64, so that any seed, including
+// zero, gives a good sequence.  It is not for cryptographic use.
+
+pub struct Rng
+{
+    state: u64,
+}
+
+pub fn make(seed: u64) -> Rng
+{
+    let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    Rng { state: if z == 0 { 1 } else { z } }
+}
+
+impl Rng
+{
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    pub fn next_u16(&mut self) -> u16 {
+        (self.next_u64() >> 48) as u16
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use devices::{TTY, ByteReader, ByteWriter};
+use devices::{TTY, ByteReader, ByteWriter, DeviceState};
 
 pub struct RustConsoleIo {
 }
@@ -19,6 +19,8 @@ impl ByteWriter for RustConsoleIo {
     }
 }
 
+impl DeviceState for RustConsoleIo {}
+
 impl TTY for RustConsoleIo {}
 
 pub fn make() -> RustConsoleIo
@@ -0,0 +1,267 @@
+// Machine save states.
+//
+// A save state captures the whole machine: all CPU registers including the
+// alternate set, the CPU's stop state, memory, and the state of every device
+// in the Machine.  Instrumentation (coverage, exec_check, bus_trace) and the
+// trap opcode are not part of the state.
+//
+// File format, all multi-byte values little-endian:
+//
+//   magic      "Z80EMUST"
+//   version    u16
+//   cpu        pc, sp, ix, iy (u16 each); a, f, b, c, d, e, h, l (u8 each);
+//              the alternates a', f', b', c', d', e', h', l' (u8 each);
+//              stop reason (u8); port address (u8);
+//              i, r, im, iff1, iff2 (u8 each); cycles (u64);
+//              trap number (u8), for the Trap stop reason
+//   memory     65536 bytes
+//   devices    for each device in a fixed order (tty, disks A: to P:, block
+//              device): the length of its state (u32) followed by the state
+//              bytes; an absent device has an empty state
+//   machine    selected disk (u8)
+//
+// Version history:
+//   1 - Initial version
+//   2 - Added i, r, im, iff1, iff2; these are zero when loading version 1
+//   3 - Added cycles; this is zero when loading older versions
+//   4 - Added the Trap stop reason and the trap number
+//   5 - Added the ExecCheck stop reason
+//   6 - Added disks B: to P: and the selected disk; when loading older
+//       versions A: is selected and the other disks are left as they are
+//   7 - Added the block device; it is left as it is when loading older versions
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use binfile;
+use z80::{self, StopReason, Z80};
+use machine::{Machine, DISKS};
+
+const MAGIC: &[u8; 8] = b"Z80EMUST";
+const VERSION: u16 = 7;
+
+pub fn save(filename: &str, cpu: &Z80, m: &Machine) -> io::Result<()>
+{
+    let mut out = vec![];
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+
+    for r in &[cpu.pc, cpu.sp, cpu.ix, cpu.iy] {
+        out.extend_from_slice(&r.to_le_bytes());
+    }
+    out.extend_from_slice(&[cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l]);
+    out.extend_from_slice(&[cpu.a_alt, cpu.f_alt, cpu.b_alt, cpu.c_alt,
+                            cpu.d_alt, cpu.e_alt, cpu.h_alt, cpu.l_alt]);
+    out.push(encode_stop_reason(cpu.stop_reason));
+    out.push(cpu.port_addr);
+    out.extend_from_slice(&[cpu.i, cpu.r, cpu.im, cpu.iff1 as u8, cpu.iff2 as u8]);
+    out.extend_from_slice(&cpu.cycles.to_le_bytes());
+    out.push(match cpu.stop_reason { StopReason::Trap(n) => n, _ => 0 });
+    out.extend_from_slice(&cpu.mem);
+
+    save_devices(&mut out, m);
+    out.push(m.selected_disk);
+
+    File::create(filename)?.write_all(&out)
+}
+
+// Restore a save state.  The whole file is read and checked before anything is
+// restored, and a device that rejects its state is put back as it was, so an
+// error leaves the CPU and the machine unchanged.
+
+pub fn restore(filename: &str, cpu: &mut Z80, m: &mut Machine) -> io::Result<()>
+{
+    let mut data = vec![];
+    File::open(filename)?.read_to_end(&mut data)?;
+    let mut r = binfile::make_reader(&data);
+
+    if r.bytes(MAGIC.len())? != MAGIC {
+        return Err(bad_state("not a save state"));
+    }
+    let version = r.u16()?;
+    if !(1..=VERSION).contains(&version) {
+        return Err(bad_state(&format!("unsupported version {}", version)));
+    }
+
+    let mut state = Box::new(z80::make(0));
+    read_cpu(&mut r, version, &mut state)?;
+    let devices = read_devices(&mut r, version)?;
+    let selected_disk = if version >= 6 { r.u8()? } else { 0 };
+    if selected_disk as usize >= m.disks.len() {
+        return Err(bad_state("bad selected disk"));
+    }
+    if !r.at_end() {
+        return Err(bad_state("trailing data"));
+    }
+
+    let mut current = vec![];
+    save_devices(&mut current, m);
+    if let Err(e) = restore_devices(m, &devices) {
+        let current = read_devices(&mut binfile::make_reader(&current), VERSION).unwrap();
+        restore_devices(m, &current).expect("Could not put the devices back");
+        return Err(e);
+    }
+    m.selected_disk = selected_disk;
+
+    // Instrumentation and the trap opcode are not part of the state
+    state.coverage = cpu.coverage.take();
+    state.exec_check = cpu.exec_check.take();
+    state.bus_trace = cpu.bus_trace.take();
+    state.trap = cpu.trap;
+    *cpu = *state;
+    Ok(())
+}
+
+// The device sections of a save state, see above.  Older versions have fewer
+// disks, and no block device.
+
+struct Devices<'a>
+{
+    tty: &'a [u8],
+    disks: Vec<&'a [u8]>,
+    block_device: Option<&'a [u8]>,
+}
+
+fn save_devices(out: &mut Vec<u8>, m: &Machine)
+{
+    let mut dev = vec![];
+    if let Some(ref tty) = m.tty {
+        tty.save_state(&mut dev);
+    }
+    put_section(out, &dev);
+
+    for disk in &m.disks {
+        dev.clear();
+        if let Some(ref dsk) = *disk {
+            dsk.save_state(&mut dev);
+        }
+        put_section(out, &dev);
+    }
+    dev.clear();
+    if let Some(ref blk) = m.block_device {
+        blk.save_state(&mut dev);
+    }
+    put_section(out, &dev);
+}
+
+fn read_cpu(r: &mut binfile::Reader, version: u16, cpu: &mut Z80) -> io::Result<()>
+{
+    cpu.pc = r.u16()?;
+    cpu.sp = r.u16()?;
+    cpu.ix = r.u16()?;
+    cpu.iy = r.u16()?;
+    cpu.a = r.u8()?;
+    cpu.f = r.u8()?;
+    cpu.b = r.u8()?;
+    cpu.c = r.u8()?;
+    cpu.d = r.u8()?;
+    cpu.e = r.u8()?;
+    cpu.h = r.u8()?;
+    cpu.l = r.u8()?;
+    cpu.a_alt = r.u8()?;
+    cpu.f_alt = r.u8()?;
+    cpu.b_alt = r.u8()?;
+    cpu.c_alt = r.u8()?;
+    cpu.d_alt = r.u8()?;
+    cpu.e_alt = r.u8()?;
+    cpu.h_alt = r.u8()?;
+    cpu.l_alt = r.u8()?;
+    let stop_reason = r.u8()?;
+    cpu.port_addr = r.u8()?;
+    if version >= 2 {
+        cpu.i = r.u8()?;
+        cpu.r = r.u8()?;
+        cpu.im = r.u8()?;
+        cpu.iff1 = r.u8()? != 0;
+        cpu.iff2 = r.u8()? != 0;
+    }
+    cpu.cycles = if version >= 3 { r.u64()? } else { 0 };
+    let trap = if version >= 4 { Some(r.u8()?) } else { None };
+    cpu.stop_reason = decode_stop_reason(stop_reason, trap)?;
+    cpu.mem.copy_from_slice(r.bytes(65536)?);
+    Ok(())
+}
+
+fn read_devices<'a>(r: &mut binfile::Reader<'a>, version: u16) -> io::Result<Devices<'a>>
+{
+    let tty = r.section()?;
+    let mut disks = vec![];
+    for _ in 0..if version >= 6 { DISKS } else { 1 } {
+        disks.push(r.section()?);
+    }
+    let block_device = if version >= 7 { Some(r.section()?) } else { None };
+    Ok(Devices { tty, disks, block_device })
+}
+
+// Restore the devices' states, stopping at the first one a device rejects.
+// Devices without a section are left as they are.
+
+fn restore_devices(m: &mut Machine, devices: &Devices) -> io::Result<()>
+{
+    let ok = match m.tty {
+        Some(ref mut dev) => dev.restore_state(devices.tty),
+        None => devices.tty.is_empty()
+    };
+    if !ok {
+        return Err(bad_state("bad TTY state"));
+    }
+    for (n, (disk, &state)) in m.disks.iter_mut().zip(&devices.disks).enumerate() {
+        let ok = match *disk {
+            Some(ref mut dev) => dev.restore_state(state),
+            None => state.is_empty()
+        };
+        if !ok {
+            return Err(bad_state(&format!("bad state for disk {}:", (b'A' + n as u8) as char)));
+        }
+    }
+    if let Some(state) = devices.block_device {
+        let ok = match m.block_device {
+            Some(ref mut dev) => dev.restore_state(state),
+            None => state.is_empty()
+        };
+        if !ok {
+            return Err(bad_state("bad block device state"));
+        }
+    }
+    Ok(())
+}
+
+fn encode_stop_reason(reason: StopReason) -> u8
+{
+    match reason {
+        StopReason::Halt => 0,
+        StopReason::Poll => 1,
+        StopReason::Out => 2,
+        StopReason::In => 3,
+        StopReason::Illegal => 4,
+        StopReason::Trap(_) => 5,
+        StopReason::ExecCheck => 6,
+    }
+}
+
+// Traps are only in version 4 and later, which have the trap number.
+
+fn decode_stop_reason(n: u8, trap: Option<u8>) -> io::Result<StopReason>
+{
+    match (n, trap) {
+        (0, _) => Ok(StopReason::Halt),
+        (1, _) => Ok(StopReason::Poll),
+        (2, _) => Ok(StopReason::Out),
+        (3, _) => Ok(StopReason::In),
+        (4, _) => Ok(StopReason::Illegal),
+        (5, Some(n)) => Ok(StopReason::Trap(n)),
+        (6, _) => Ok(StopReason::ExecCheck),
+        _ => Err(bad_state("bad stop reason"))
+    }
+}
+
+fn put_section(out: &mut Vec<u8>, section: &[u8])
+{
+    out.extend_from_slice(&(section.len() as u32).to_le_bytes());
+    out.extend_from_slice(section);
+}
+
+fn bad_state(msg: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, format!("Bad save state: {}", msg))
+}
@@ -0,0 +1,446 @@
+// ZX Spectrum snapshot formats: .SNA (48K and 128K) and .Z80 (versions 1, 2,
+// and 3).
+//
+// Snapshots only carry the Spectrum's RAM, 0x4000-0xFFFF; memory below that is
+// ROM on the Spectrum and is left alone here.  Our memory is a flat 64KB, so
+// for 128K snapshots we load the banks that are paged in (5 at 0x4000, 2 at
+// 0x8000, and the selected bank at 0xC000) and ignore the rest.  Border color
+// and other Spectrum hardware state are ignored on load and zero on save.
+//
+// .SNA layout (27-byte header, then 48KB of RAM):
+//
+//   0 I, 1-8 HL' DE' BC' AF', 9-14 HL DE BC, 15-18 IY IX,
+//   19 interrupt state (bit 2 = IFF2), 20 R, 21-22 AF, 23-24 SP, 25 IM, 26 border
+//
+// A 48K .SNA has PC pushed on the stack.  A 128K .SNA instead follows the RAM
+// with PC (2 bytes), the last write to port 0x7FFD, a TR-DOS flag, and the
+// remaining five or six banks in ascending order.
+//
+// .Z80 layout (30-byte header):
+//
+//   0 A, 1 F, 2-3 BC, 4-5 HL, 6-7 PC, 8-9 SP, 10 I, 11 R (low 7 bits),
+//   12 flags (bit 0 = R bit 7, bits 1-3 border, bit 5 = RAM compressed),
+//   13-14 DE, 15-20 BC' DE' HL', 21 A', 22 F', 23-24 IY, 25-26 IX,
+//   27 IFF1, 28 IFF2, 29 flags (bits 0-1 = IM)
+//
+// Version 1 follows the header with the 48KB of RAM, optionally compressed and
+// terminated by 00 ED ED 00.  In versions 2 and 3 PC in the header is zero and
+// an extra header follows: its length (2 bytes, 23 for v2 and 54 or 55 for
+// v3), PC, the hardware mode, and the last write to port 0x7FFD, among other
+// things.  Then come memory pages, each a 3-byte header -- compressed length
+// (0xFFFF for uncompressed, v3 only) and page number -- and the data.
+//
+// Compression replaces runs of five or more identical bytes, and runs of two
+// or more ED bytes, by ED ED <count> <byte>.  A byte following a single ED is
+// never part of a run.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use z80::Z80;
+
+// Load a snapshot file, picking the format from the file name extension.
+
+pub fn load(filename: &str, cpu: &mut Z80) -> io::Result<()>
+{
+    let mut data = vec![];
+    File::open(filename)?.read_to_end(&mut data)?;
+    match extension(filename).as_str() {
+        "sna" => load_sna(&data, cpu),
+        "z80" => load_z80(&data, cpu),
+        _ => Err(bad_snapshot("Unknown snapshot type, expected .sna or .z80"))
+    }
+}
+
+// Save a snapshot file, picking the format from the file name extension:
+// .sna files are written as 48K unless `sna_128k` is set, .z80 files as
+// version 3.
+
+pub fn save(filename: &str, cpu: &Z80, sna_128k: bool) -> io::Result<()>
+{
+    let data = match extension(filename).as_str() {
+        "sna" if sna_128k => save_sna128(cpu),
+        "sna" => save_sna48(cpu),
+        "z80" => save_z80(cpu, Z80Version::V3),
+        _ => { return Err(bad_snapshot("Unknown snapshot type, expected .sna or .z80")); }
+    };
+    File::create(filename)?.write_all(&data)
+}
+
+fn extension(filename: &str) -> String
+{
+    match filename.rfind('.') {
+        Some(i) => filename[i + 1..].to_lowercase(),
+        None => String::new()
+    }
+}
+
+const RAM_START: usize = 0x4000;
+const RAM_SIZE: usize = 0xC000;
+const BANK_SIZE: usize = 0x4000;
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// .SNA
+
+const SNA_HEADER_SIZE: usize = 27;
+const SNA_48K_SIZE: usize = SNA_HEADER_SIZE + RAM_SIZE;
+
+pub fn load_sna(data: &[u8], cpu: &mut Z80) -> io::Result<()>
+{
+    if data.len() < SNA_48K_SIZE {
+        return Err(bad_snapshot(".SNA file is too short"));
+    }
+    let h = &data[..SNA_HEADER_SIZE];
+
+    cpu.i = h[0];
+    cpu.l_alt = h[1];
+    cpu.h_alt = h[2];
+    cpu.e_alt = h[3];
+    cpu.d_alt = h[4];
+    cpu.c_alt = h[5];
+    cpu.b_alt = h[6];
+    cpu.f_alt = h[7];
+    cpu.a_alt = h[8];
+    cpu.l = h[9];
+    cpu.h = h[10];
+    cpu.e = h[11];
+    cpu.d = h[12];
+    cpu.c = h[13];
+    cpu.b = h[14];
+    cpu.iy = word(h, 15);
+    cpu.ix = word(h, 17);
+    cpu.iff2 = h[19] & 0x04 != 0;
+    cpu.iff1 = cpu.iff2;
+    cpu.r = h[20];
+    cpu.f = h[21];
+    cpu.a = h[22];
+    cpu.sp = word(h, 23);
+    cpu.im = h[25] & 3;
+
+    if data.len() == SNA_48K_SIZE {
+        cpu.mem[RAM_START..].copy_from_slice(&data[SNA_HEADER_SIZE..]);
+        let sp = cpu.sp;
+        cpu.pc = (cpu.mem[sp as usize] as u16) | ((cpu.mem[sp.wrapping_add(1) as usize] as u16) << 8);
+        cpu.sp = sp.wrapping_add(2);
+        return Ok(());
+    }
+
+    // 128K: banks 5, 2, and the paged bank have been stored first, followed by
+    // the extra header.
+    let ext = &data[SNA_48K_SIZE..];
+    if ext.len() < 4 {
+        return Err(bad_snapshot(".SNA file has a bad size"));
+    }
+    let remaining = (ext.len() - 4) / BANK_SIZE;
+    if !(ext.len() - 4).is_multiple_of(BANK_SIZE) || (remaining != 5 && remaining != 6) {
+        return Err(bad_snapshot(".SNA file has a bad size"));
+    }
+    cpu.mem[RAM_START..].copy_from_slice(&data[SNA_HEADER_SIZE..SNA_48K_SIZE]);
+    cpu.pc = word(ext, 0);
+    Ok(())
+}
+
+// A 48K .SNA pushes PC on the stack in the image, not in the CPU's memory.
+
+pub fn save_sna48(cpu: &Z80) -> Vec<u8>
+{
+    let mut mem = cpu.mem.to_vec();
+    let sp = cpu.sp.wrapping_sub(2);
+    mem[sp as usize] = cpu.pc as u8;
+    mem[sp.wrapping_add(1) as usize] = (cpu.pc >> 8) as u8;
+
+    let mut out = sna_header(cpu, sp);
+    out.extend_from_slice(&mem[RAM_START..]);
+    out
+}
+
+// A 128K .SNA with bank 0 paged in at 0xC000.  Banks that are not in our
+// memory are written as zeroes.
+
+pub fn save_sna128(cpu: &Z80) -> Vec<u8>
+{
+    let mut out = sna_header(cpu, cpu.sp);
+    out.extend_from_slice(&cpu.mem[RAM_START..]);
+    out.extend_from_slice(&[cpu.pc as u8, (cpu.pc >> 8) as u8]);
+    out.push(0x00);             // Port 0x7FFD: bank 0, screen 0, ROM 0
+    out.push(0x00);             // TR-DOS not paged
+    for _ in 0..5 {
+        out.extend_from_slice(&[0; BANK_SIZE]);   // Banks 1, 3, 4, 6, 7
+    }
+    out
+}
+
+fn sna_header(cpu: &Z80, sp: u16) -> Vec<u8>
+{
+    let mut h = vec![
+        cpu.i,
+        cpu.l_alt, cpu.h_alt, cpu.e_alt, cpu.d_alt, cpu.c_alt, cpu.b_alt, cpu.f_alt, cpu.a_alt,
+        cpu.l, cpu.h, cpu.e, cpu.d, cpu.c, cpu.b,
+    ];
+    put_word(&mut h, cpu.iy);
+    put_word(&mut h, cpu.ix);
+    h.push(if cpu.iff2 { 0x04 } else { 0x00 });
+    h.push(cpu.r);
+    h.push(cpu.f);
+    h.push(cpu.a);
+    put_word(&mut h, sp);
+    h.push(cpu.im);
+    h.push(0);                  // Border
+    h
+}
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// .Z80
+
+const Z80_HEADER_SIZE: usize = 30;
+const Z80_V2_EXTRA_SIZE: usize = 23;
+const Z80_V3_EXTRA_SIZE: usize = 54;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Z80Version {
+    V1,
+    V2,
+    V3,
+}
+
+pub fn load_z80(data: &[u8], cpu: &mut Z80) -> io::Result<()>
+{
+    if data.len() < Z80_HEADER_SIZE {
+        return Err(bad_snapshot(".Z80 file is too short"));
+    }
+    let h = &data[..Z80_HEADER_SIZE];
+    let flags = if h[12] == 0xFF { 0x01 } else { h[12] };
+
+    cpu.a = h[0];
+    cpu.f = h[1];
+    cpu.c = h[2];
+    cpu.b = h[3];
+    cpu.l = h[4];
+    cpu.h = h[5];
+    cpu.sp = word(h, 8);
+    cpu.i = h[10];
+    cpu.r = (h[11] & 0x7F) | ((flags & 0x01) << 7);
+    cpu.e = h[13];
+    cpu.d = h[14];
+    cpu.c_alt = h[15];
+    cpu.b_alt = h[16];
+    cpu.e_alt = h[17];
+    cpu.d_alt = h[18];
+    cpu.l_alt = h[19];
+    cpu.h_alt = h[20];
+    cpu.a_alt = h[21];
+    cpu.f_alt = h[22];
+    cpu.iy = word(h, 23);
+    cpu.ix = word(h, 25);
+    cpu.iff1 = h[27] != 0;
+    cpu.iff2 = h[28] != 0;
+    cpu.im = h[29] & 3;
+
+    let pc = word(h, 6);
+    if pc != 0 {
+        // Version 1
+        let body = &data[Z80_HEADER_SIZE..];
+        let ram = if flags & 0x20 != 0 {
+            let end = if body.ends_with(&[0x00, 0xED, 0xED, 0x00]) { body.len() - 4 } else { body.len() };
+            decompress(&body[..end], RAM_SIZE)?
+        } else {
+            body.to_vec()
+        };
+        if ram.len() != RAM_SIZE {
+            return Err(bad_snapshot(".Z80 file has the wrong amount of memory"));
+        }
+        cpu.mem[RAM_START..].copy_from_slice(&ram);
+        cpu.pc = pc;
+        return Ok(());
+    }
+
+    // Versions 2 and 3
+    if data.len() < Z80_HEADER_SIZE + 2 {
+        return Err(bad_snapshot(".Z80 file is too short"));
+    }
+    let extra_len = word(data, Z80_HEADER_SIZE) as usize;
+    let version = match extra_len {
+        Z80_V2_EXTRA_SIZE => Z80Version::V2,
+        54 | 55 => Z80Version::V3,
+        _ => { return Err(bad_snapshot(".Z80 file has an unknown version")); }
+    };
+    let pages_start = Z80_HEADER_SIZE + 2 + extra_len;
+    if data.len() < pages_start {
+        return Err(bad_snapshot(".Z80 file is too short"));
+    }
+    let ext = &data[Z80_HEADER_SIZE + 2..pages_start];
+    cpu.pc = word(ext, 0);
+    let hw_mode = ext[2];
+    let is_128k = match version {
+        Z80Version::V2 => hw_mode == 3 || hw_mode == 4,
+        _ => (4..=6).contains(&hw_mode),
+    };
+    let paged_bank = ext[3] & 7;
+
+    let mut pos = pages_start;
+    while pos < data.len() {
+        if data.len() - pos < 3 {
+            return Err(bad_snapshot(".Z80 file has a truncated page"));
+        }
+        let len = word(data, pos);
+        let page = data[pos + 2];
+        pos += 3;
+        let bytes = if len == 0xFFFF {
+            if data.len() - pos < BANK_SIZE {
+                return Err(bad_snapshot(".Z80 file has a truncated page"));
+            }
+            pos += BANK_SIZE;
+            data[pos - BANK_SIZE..pos].to_vec()
+        } else {
+            let len = len as usize;
+            if data.len() - pos < len {
+                return Err(bad_snapshot(".Z80 file has a truncated page"));
+            }
+            pos += len;
+            decompress(&data[pos - len..pos], BANK_SIZE)?
+        };
+        if bytes.len() != BANK_SIZE {
+            return Err(bad_snapshot(".Z80 file has a page of the wrong size"));
+        }
+        if is_128k {
+            // Pages 3-10 are banks 0-7
+            if (3..=10).contains(&page) {
+                let bank = page - 3;
+                if bank == 5 {
+                    cpu.mem[0x4000..0x4000 + BANK_SIZE].copy_from_slice(&bytes);
+                }
+                if bank == 2 {
+                    cpu.mem[0x8000..0x8000 + BANK_SIZE].copy_from_slice(&bytes);
+                }
+                if bank == paged_bank {
+                    cpu.mem[0xC000..0xC000 + BANK_SIZE].copy_from_slice(&bytes);
+                }
+            }
+        } else {
+            let addr = match page {
+                8 => 0x4000,
+                4 => 0x8000,
+                5 => 0xC000,
+                _ => { continue; }
+            };
+            cpu.mem[addr..addr + BANK_SIZE].copy_from_slice(&bytes);
+        }
+    }
+    Ok(())
+}
+
+// Snapshots are written in 48K mode, compressed.
+
+pub fn save_z80(cpu: &Z80, version: Z80Version) -> Vec<u8>
+{
+    let mut out = vec![cpu.a, cpu.f, cpu.c, cpu.b, cpu.l, cpu.h];
+    put_word(&mut out, if version == Z80Version::V1 { cpu.pc } else { 0 });
+    put_word(&mut out, cpu.sp);
+    out.push(cpu.i);
+    out.push(cpu.r & 0x7F);
+    out.push((cpu.r >> 7) | if version == Z80Version::V1 { 0x20 } else { 0x00 });
+    out.extend_from_slice(&[cpu.e, cpu.d, cpu.c_alt, cpu.b_alt, cpu.e_alt, cpu.d_alt,
+                            cpu.l_alt, cpu.h_alt, cpu.a_alt, cpu.f_alt]);
+    put_word(&mut out, cpu.iy);
+    put_word(&mut out, cpu.ix);
+    out.push(cpu.iff1 as u8);
+    out.push(cpu.iff2 as u8);
+    out.push(cpu.im & 3);
+
+    if version == Z80Version::V1 {
+        out.extend_from_slice(&compress(&cpu.mem[RAM_START..]));
+        out.extend_from_slice(&[0x00, 0xED, 0xED, 0x00]);
+        return out;
+    }
+
+    let extra_len = if version == Z80Version::V2 { Z80_V2_EXTRA_SIZE } else { Z80_V3_EXTRA_SIZE };
+    put_word(&mut out, extra_len as u16);
+    let mut ext = vec![0; extra_len];
+    ext[0] = cpu.pc as u8;
+    ext[1] = (cpu.pc >> 8) as u8;
+    ext[2] = 0;                 // Hardware mode: 48K
+    out.extend_from_slice(&ext);
+
+    for &(page, addr) in &[(8, 0x4000), (4, 0x8000), (5, 0xC000)] {
+        let data = compress(&cpu.mem[addr..addr + BANK_SIZE]);
+        put_word(&mut out, data.len() as u16);
+        out.push(page);
+        out.extend_from_slice(&data);
+    }
+    out
+}
+
+// Decompress `data`, which must not expand to more than `size` bytes.
+
+fn decompress(data: &[u8], size: usize) -> io::Result<Vec<u8>>
+{
+    let mut out = Vec::with_capacity(size);
+    let mut i = 0;
+    while i < data.len() {
+        let (byte, count, len) = if data[i] == 0xED && i + 1 < data.len() && data[i + 1] == 0xED {
+            if data.len() - i < 4 {
+                return Err(bad_snapshot(".Z80 file has truncated compressed data"));
+            }
+            (data[i + 3], data[i + 2] as usize, 4)
+        } else {
+            (data[i], 1, 1)
+        };
+        if out.len() + count > size {
+            return Err(bad_snapshot(".Z80 file has too much compressed data"));
+        }
+        out.resize(out.len() + count, byte);
+        i += len;
+    }
+    Ok(out)
+}
+
+fn compress(data: &[u8]) -> Vec<u8>
+{
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let b = data[i];
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == b && run < 255 {
+            run += 1;
+        }
+        if run >= 5 || (b == 0xED && run >= 2) {
+            out.extend_from_slice(&[0xED, 0xED, run as u8, b]);
+            i += run;
+        } else if b == 0xED {
+            // A single ED: the byte after it must not start a run
+            out.push(b);
+            i += 1;
+            if i < data.len() {
+                out.push(data[i]);
+                i += 1;
+            }
+        } else {
+            out.push(b);
+            i += 1;
+        }
+    }
+    out
+}
+
+///////////////////////////////////////////////////////////////////////////////
+//
+// Utilities
+
+fn word(data: &[u8], offset: usize) -> u16
+{
+    (data[offset] as u16) | ((data[offset + 1] as u16) << 8)
+}
+
+fn put_word(out: &mut Vec<u8>, n: u16)
+{
+    out.push(n as u8);
+    out.push((n >> 8) as u8);
+}
+
+fn bad_snapshot(msg: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
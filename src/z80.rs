@@ -1,3 +1,10 @@
+use alloc::boxed::Box;
+
+use bus_trace::BusTrace;
+use coverage::Coverage;
+use exec_check::ExecCheck;
+use rng::Rng;
+
 pub struct Z80
 {
     // 64KB of RAM
@@ -21,29 +28,105 @@ pub struct Z80
     pub h: u8,
     pub l: u8,
 
+    // Special registers and interrupt state
+    pub i: u8,
+    pub r: u8,                  // Low 7 bits count opcode fetches
+    pub im: u8,                 // Interrupt mode 0, 1, or 2
+    pub iff1: bool,
+    pub iff2: bool,
+
+    // T-states executed since reset
+    pub cycles: u64,
+
     // Alternate registers
-    a_alt: u8, f_alt: u8, b_alt: u8, c_alt: u8, d_alt: u8, e_alt: u8, h_alt: u8, l_alt: u8,
+    pub a_alt: u8, pub f_alt: u8, pub b_alt: u8, pub c_alt: u8,
+    pub d_alt: u8, pub e_alt: u8, pub h_alt: u8, pub l_alt: u8,
+
+    // Instrumentation, if enabled
+    pub coverage: Option<Box<Coverage>>,
+    pub exec_check: Option<Box<ExecCheck>>,
+    pub bus_trace: Option<Box<BusTrace>>,
+
+    // The second byte of an ED-prefixed opcode that traps to the host, if
+    // any, see StopReason::Trap
+    pub trap: Option<u8>,
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum StopReason {
     Halt,                       // HLT executed
     Poll,                       // Timeslice expired
     Out,                        // OUT executed
     In,                         // IN executed
-    Illegal                     // Illegal opcode and/or argument
+    Illegal,                    // Illegal opcode and/or argument
+    Trap(u8),                   // Trap opcode executed, with its operand
+    ExecCheck,                  // Finding in the previous instruction, see exec_check
 }
 
-pub fn make(pc:u16) -> Z80 {
-    // TODO: On RESET, the pc is zero but the other registers are all random,
-    // and it would be useful to set them to random values here.
+// Traps let guest code call the host.  When z80.trap is Some(t), the otherwise
+// unused instruction ED t n stops the CPU with Trap(n) and pc after the
+// instruction, so the host can perform service n, reading and writing
+// registers and memory as it likes, and then resume the CPU.  ED FEh is a good
+// choice for t.  An opcode that the CPU implements cannot be a trap.
+//
+// The instruction takes 11 T-states.
 
+// Make a CPU with all registers and memory zeroed, starting at `pc`.  This is
+// not what the hardware does, see power_on(), but it is predictable.
+
+pub fn make(pc:u16) -> Z80 {
     Z80 {
-        mem: [0; 65536], pc: pc, sp: 0, ix: 0, iy: 0,
+        mem: [0; 65536], pc, sp: 0, ix: 0, iy: 0,
         stop_reason: StopReason::Poll,
         port_addr: 0,
         a: 0, f: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0,
-        a_alt: 0, f_alt: 0, b_alt: 0, c_alt: 0, d_alt: 0, e_alt: 0, h_alt: 0, l_alt: 0
+        i: 0, r: 0, im: 0, iff1: false, iff2: false,
+        cycles: 0,
+        a_alt: 0, f_alt: 0, b_alt: 0, c_alt: 0, d_alt: 0, e_alt: 0, h_alt: 0, l_alt: 0,
+        coverage: None,
+        exec_check: None,
+        bus_trace: None,
+        trap: None
+    }
+}
+
+// Make a CPU as it is at power-on.  PC, I, R, IM and the interrupt flip-flops
+// are cleared, as by RESET, and the other registers hold whatever the hardware
+// came up with, which here comes from `rng`.  If `randomize_memory` is set
+// memory is random too, otherwise it is zeroed; the registers are the same
+// either way.
+//
+// Guest code that depends on uninitialized registers or memory will behave
+// differently with different seeds.
+
+pub fn power_on(rng: &mut Rng, randomize_memory: bool) -> Z80 {
+    let mut z80 = make(0);
+    z80.sp = rng.next_u16();
+    z80.ix = rng.next_u16();
+    z80.iy = rng.next_u16();
+    for reg in [&mut z80.a, &mut z80.f, &mut z80.b, &mut z80.c, &mut z80.d, &mut z80.e, &mut z80.h, &mut z80.l,
+                &mut z80.a_alt, &mut z80.f_alt, &mut z80.b_alt, &mut z80.c_alt,
+                &mut z80.d_alt, &mut z80.e_alt, &mut z80.h_alt, &mut z80.l_alt] {
+        *reg = rng.next_u8();
+    }
+    if randomize_memory {
+        rng.fill(&mut z80.mem);
     }
+    z80
+}
+
+// Assert the RESET pin: PC, I, R, IM and the interrupt flip-flops are cleared,
+// and the CPU leaves the halt state.  The other registers, memory, and the
+// cycle count are unchanged.
+
+pub fn reset(z80: &mut Z80) {
+    z80.pc = 0;
+    z80.i = 0;
+    z80.r = 0;
+    z80.im = 0;
+    z80.iff1 = false;
+    z80.iff2 = false;
+    z80.stop_reason = StopReason::Poll;
 }
 
 // Flag bits in the flag registers
@@ -51,27 +134,119 @@ pub fn make(pc:u16) -> Z80 {
 const CARRY_FLAG: u8 = 0x01;
 const CARRY_SHIFT: u8 = 0;
 
+#[allow(dead_code)]
 const NEG_FLAG: u8 = 0x02;
+#[allow(dead_code)]
 const NEG_SHIFT: u8 = 1;
 
 const OVERFLOW_FLAG: u8 = 0x04;
+#[allow(dead_code)]
 const OVERFLOW_SHIFT: u8 = 2;
 
 const PARITY_FLAG: u8 = 0x04;
+#[allow(dead_code)]
 const PARITY_SHIFT: u8 = 2;
 
 const HALF_FLAG: u8 = 0x10;
+#[allow(dead_code)]
 const HALF_SHIFT: u8 = 4;
 
 const ZERO_FLAG: u8 = 0x40;
+#[allow(dead_code)]
 const ZERO_SHIFT: u8 = 6;
 
 const SIGN_FLAG: u8 = 0x80;
+#[allow(dead_code)]
 const SIGN_SHIFT: u8 = 7;
 
 const UNUSED_FLAGS: u8 = 0x28;
 
+// Flag lookup tables.  SZ holds the sign and zero flags for an 8-bit result,
+// SZP also the parity flag.
+
+static SZ: [u8; 256] = make_flag_table(false);
+static SZP: [u8; 256] = make_flag_table(true);
+
+const fn make_flag_table(parity: bool) -> [u8; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut flags = (i as u8) & SIGN_FLAG;
+        if i == 0 {
+            flags |= ZERO_FLAG;
+        }
+        if parity && (i as u8).count_ones() & 1 == 0 {
+            flags |= PARITY_FLAG;
+        }
+        table[i] = flags;
+        i += 1;
+    }
+    table
+}
+
+// Half carry and overflow of an addition, indexed by bits of the operands and
+// the result.  For the half carry the index is bit 3 of the first operand, the
+// second operand, and the result, in bits 0, 1, and 2; for the overflow it is
+// bit 7 of each, likewise.  For 16-bit additions use bits 11 and 15.
+
+static HALFCARRY_ADD: [u8; 8] = [0, HALF_FLAG, HALF_FLAG, HALF_FLAG, 0, 0, 0, HALF_FLAG];
+static OVERFLOW_ADD: [u8; 8] = [0, 0, 0, OVERFLOW_FLAG, OVERFLOW_FLAG, 0, 0, 0];
+
+// Flag computations, shared with the block cache.  Each takes the current
+// flags and returns the new ones.  For additions `result` is the full sum of
+// the operands and the carry in, wider than the operands so that it includes
+// the carry out.
+
+#[inline(always)]
+pub(crate) fn add8_flags(f: u8, op1: u8, op2: u8, result: usize) -> u8 {
+    let lookup = ((op1 as usize & 0x88) >> 3) | ((op2 as usize & 0x88) >> 2) | ((result & 0x88) >> 1);
+    (f & UNUSED_FLAGS)
+        | SZ[result & 0xFF]
+        | HALFCARRY_ADD[lookup & 7]
+        | OVERFLOW_ADD[lookup >> 4]
+        | (((result >> 8) & 1) as u8)
+}
+
+#[inline(always)]
+pub(crate) fn and8_flags(f: u8, result: usize) -> u8 {
+    (f & UNUSED_FLAGS) | SZP[result & 0xFF] | HALF_FLAG
+}
+
+#[inline(always)]
+pub(crate) fn bit_flags(f: u8, result: u16) -> u8 {
+    // The tested bit alone has odd parity, so P is set exactly when Z is, and
+    // S is set only by a set bit 7.
+    (f & (CARRY_FLAG | UNUSED_FLAGS)) | SZP[(result & 0xFF) as usize] | HALF_FLAG
+}
+
+#[inline(always)]
+pub(crate) fn adc16_flags(f: u8, op1: u16, op2: u16, result: usize) -> u8 {
+    let lookup = ((op1 as usize & 0x8800) >> 11) | ((op2 as usize & 0x8800) >> 10) | ((result & 0x8800) >> 9);
+    let zf = if result & 0xFFFF == 0 { ZERO_FLAG } else { 0 };
+    (f & UNUSED_FLAGS)
+        | (SZ[(result >> 8) & 0xFF] & SIGN_FLAG)
+        | zf
+        | HALFCARRY_ADD[lookup & 7]
+        | OVERFLOW_ADD[lookup >> 4]
+        | (((result >> 16) & 1) as u8)
+}
+
+#[inline(always)]
+pub(crate) fn add16_flags(f: u8, op1: u16, op2: u16, result: usize) -> u8 {
+    let lookup = ((op1 as usize & 0x8800) >> 11) | ((op2 as usize & 0x8800) >> 10) | ((result & 0x8800) >> 9);
+    (f & (SIGN_FLAG | ZERO_FLAG | PARITY_FLAG | UNUSED_FLAGS))
+        | HALFCARRY_ADD[lookup & 7]
+        | (((result >> 16) & 1) as u8)
+}
+
+// Run the CPU for at most `timeslice`-1 instructions, stopping early for I/O,
+// HALT, or an illegal instruction; see StopReason.
+
 pub fn run(z80: &mut Z80, mut timeslice: usize) {
+    // All the CPU state that instructions use lives in locals while running,
+    // so that the compiler can keep it in registers, and is written back when
+    // the CPU stops.
+
     let mem = &mut z80.mem;
     let mut pc = z80.pc;
     let mut sp_ = z80.sp;
@@ -85,6 +260,16 @@ pub fn run(z80: &mut Z80, mut timeslice: usize) {
     let mut e = z80.e;
     let mut h = z80.h;
     let mut l = z80.l;
+    let mut r = z80.r;
+    let mut cycles = z80.cycles;
+    let mut im = z80.im;
+    let mut iff1 = z80.iff1;
+    let mut iff2 = z80.iff2;
+    let mut coverage = z80.coverage.take();
+    let mut check = z80.exec_check.take();
+    let mut trace = z80.bus_trace.take();
+    let trap = z80.trap;
+    let mut stop_reason = StopReason::Illegal;
 
     // 16-bit register operations
 
@@ -138,93 +323,36 @@ pub fn run(z80: &mut Z80, mut timeslice: usize) {
     // The operand size follows 'set'.  For operand size 8, the
     // operands and result are 16-bit; for size 16, they are 32-bit.
 
-    macro_rules! sf8 {
-        ($result:ident) => { if $result & 0x80 == 0 { 0 } else { SIGN_FLAG } }
-    }
-
-    macro_rules! zf8 {
-        ($result:ident) => { if $result & 0xFF == 0 { ZERO_FLAG } else { 0 } }
-    }
-
-    macro_rules! cf8 {
-        ($result:ident) => { if $result & 0x100 == 0 { 0 } else { CARRY_FLAG } }
-    }
-
     macro_rules! set8_szhv0c {
         ($op1:ident, $op2:ident, $result:ident) => {{
-            f &= UNUSED_FLAGS;
-
-            let sf = sf8!($result);
-            let zf = zf8!($result);
-            let hf = 0;         // FIXME
-            let vf = 0;         // FIXME
-            let cf = cf8!($result);
-            f = sf | zf | hf | vf | cf;
+            f = add8_flags(f, $op1, $op2, $result);
         }};
         ($op1:ident, $op2:ident, $op3:ident, $result:ident) => {{
-            f &= UNUSED_FLAGS;
-
-            let sf = sf8!($result);
-            let zf = zf8!($result);
-            let hf = 0;         // FIXME
-            let vf = 0;         // FIXME
-            let cf = cf8!($result);
-            f = sf | zf | hf | vf | cf;
+            f = add8_flags(f, $op1, $op2, $result);
         }}
     }
 
     macro_rules! set8_sz1p00 {
         ($op1:ident, $op2:ident, $result:ident) => {{
-            f &= UNUSED_FLAGS;
-
-            let sf = sf8!($result);
-            let zf = zf8!($result);
-            let pf = 0;         // FIXME
-            f = sf | zf | HALF_FLAG | pf;
+            f = and8_flags(f, $result);
         }};
     }
 
-    macro_rules! set8__z1_0F {
+    macro_rules! set8_sz1p0F {
         ($v:ident, $bit:ident, $result:ident) => {{
-            f &= CARRY_FLAG | UNUSED_FLAGS;
-
-            let zf = zf8!($result);
-            f |= zf | HALF_FLAG;
+            f = bit_flags(f, $result);
         }}
     }
 
-    macro_rules! sf16 {
-        ($result:ident) => { if $result & 0x8000 == 0 { 0 } else { SIGN_FLAG } }
-    }
-
-    macro_rules! zf16 {
-        ($result:ident) => { if $result & 0xFFFF == 0 { ZERO_FLAG } else { 0 } }
-    }
-
-    macro_rules! cf16 {
-        ($result:ident) => { if $result & 0x10000 == 0 { 0 } else { CARRY_FLAG } }
-    }
-
     macro_rules! set16_szhv0c {
         ($op1:ident, $op2:ident, $op3:ident, $result:ident) => {{
-            f &= UNUSED_FLAGS;
-
-            let sf = sf16!($result);
-            let zf = zf16!($result);
-            let hf = 0;         // FIXME - carry from bit 11
-            let vf = 0;         // FIXME
-            let cf = cf16!($result);
-            f = sf | zf | hf | vf | cf;
+            f = adc16_flags(f, $op1, $op2, $result);
         }};
     }
 
     macro_rules! set16_FFhF0c {
         ($op1:ident, $op2:ident, $result:ident) => {{
-            f &= SIGN_FLAG | ZERO_FLAG | PARITY_FLAG | UNUSED_FLAGS;
-
-            let hf = 0;         // FIXME - carry from bit 11
-            let cf = cf16!($result);
-            f |= hf | cf;
+            f = add16_flags(f, $op1, $op2, $result);
         }};
     }
 
@@ -232,23 +360,89 @@ pub fn run(z80: &mut Z80, mut timeslice: usize) {
 
     macro_rules! byte {
         () => {{
+            if let Some(ref mut chk) = check {
+                chk.fetch(pc);
+            }
             let c = mem[pc as usize];
+            if let Some(ref mut t) = trace {
+                t.read(pc, c);
+            }
             pc = pc.wrapping_add(1);
             c
         }}
     }
+    // An opcode fetch (M1 cycle) also refreshes memory, which increments the
+    // low seven bits of R.
+    macro_rules! opcode {
+        () => {{
+            r = (r & 0x80) | (r.wrapping_add(1) & 0x7F);
+            byte!()
+        }}
+    }
+    macro_rules! word {
+        () => {{
+            let lo = byte!() as u16;
+            let hi = byte!() as u16;
+            (hi << 8) | lo
+        }}
+    }
+    // The word at pc, without stepping over it, for jumps that replace pc.
     macro_rules! peek_word {
         () => {{
-            let lo = mem[pc as usize] as u16;
-            let hi = mem[pc.wrapping_add(1) as usize] as u16;
+            if let Some(ref mut chk) = check {
+                chk.fetch(pc);
+                chk.fetch(pc.wrapping_add(1));
+            }
+            let lo = read!(pc) as u16;
+            let hi = read!(pc.wrapping_add(1)) as u16;
+            (hi << 8) | lo
+        }}
+    }
+    macro_rules! read {
+        ($addr:expr) => {{
+            let addr: u16 = $addr;
+            let v = mem[addr as usize];
+            if let Some(ref mut t) = trace {
+                t.read(addr, v);
+            }
+            v
+        }}
+    }
+    macro_rules! write {
+        ($addr:expr, $v:expr) => {{
+            let addr: u16 = $addr;
+            let v: u8 = $v;
+            if let Some(ref mut chk) = check {
+                chk.write(addr);
+            }
+            if let Some(ref mut t) = trace {
+                t.write(addr, v);
+            }
+            mem[addr as usize] = v;
+        }}
+    }
+    macro_rules! push {
+        ($v:expr) => {{
+            let v: u16 = $v;
+            sp_ = sp_.wrapping_sub(1);
+            write!(sp_, (v >> 8) as u8);
+            sp_ = sp_.wrapping_sub(1);
+            write!(sp_, v as u8);
+        }}
+    }
+    macro_rules! pop {
+        () => {{
+            let lo = read!(sp_) as u16;
+            let hi = read!(sp_.wrapping_add(1)) as u16;
+            sp_ = sp_.wrapping_add(2);
             (hi << 8) | lo
         }}
     }
 
-    macro_rules! at_hl { () => { mem[hl!() as usize] } }
+    macro_rules! at_hl { () => { read!(hl!()) } }
 
-    macro_rules! at_ixd { ($d:ident) => { mem[ix!().wrapping_add($d as u16) as usize] } }
-    macro_rules! at_iyd { ($d:ident) => { mem[iy!().wrapping_add($d as u16) as usize] } }
+    macro_rules! at_ixd { ($d:ident) => { read!(ix!().wrapping_add($d as i8 as u16)) } }
+    macro_rules! at_iyd { ($d:ident) => { read!(iy!().wrapping_add($d as i8 as u16)) } }
 
     // Instruction macros
 
@@ -305,83 +499,126 @@ pub fn run(z80: &mut Z80, mut timeslice: usize) {
             let v = $v as u16;
             let bit = (1 << $bit) as u16;
             let res = v & bit;
-            set8__z1_0F!(v, bit, res);
+            set8_sz1p0F!(v, bit, res);
         }}
     }
 
     macro_rules! swap {
         ($a:expr, $b:expr) => {{
-            let tmp = $a;
-            $a = $b;
-            $b = tmp;
+            ::core::mem::swap(&mut $a, &mut $b);
+        }}
+    }
+
+    macro_rules! jp_cc {
+        ($cond:expr) => {{
+            let taken = $cond;
+            if let Some(ref mut cov) = coverage {
+                cov.branch(pc.wrapping_sub(1), taken);
+            }
+            let target = word!();
+            if taken {
+                pc = target;
+            }
         }}
     }
 
-    z80.stop_reason = StopReason::Illegal;
     loop {
         timeslice -= 1;
         if timeslice == 0 {
-            z80.stop_reason = StopReason::Poll;
+            stop_reason = StopReason::Poll;
             break;
         }
-        match byte!() {
-            0x00 => {}
+        if let Some(ref mut chk) = check {
+            if chk.instruction(pc) {
+                stop_reason = StopReason::ExecCheck;
+                break;
+            }
+        }
+        if let Some(ref mut cov) = coverage {
+            cov.execute(pc);
+        }
+        match opcode!() {
+            0x00 => { cycles += 4; }
+            0x01 => { let v = word!(); set_bc!(v); cycles += 10; }
+            0x06 => { b = byte!(); cycles += 7; }
             0x08 => {
                 swap!(a, z80.a_alt);
                 swap!(f, z80.f_alt);
+                cycles += 4;
             }
-            0x09 => { add_rr_ss!(hl, bc); } 
-            0x19 => { add_rr_ss!(hl, de); } 
-            0x29 => { add_rr_ss!(hl, hl); } 
-            0x37 => { a = byte!(); }
-            0x39 => { add_rr_ss!(hl, sp); } 
+            0x09 => { add_rr_ss!(hl, bc); cycles += 11; }
+            0x0E => { c = byte!(); cycles += 7; }
+            0x11 => { let v = word!(); set_de!(v); cycles += 10; }
+            0x16 => { d = byte!(); cycles += 7; }
+            0x19 => { add_rr_ss!(hl, de); cycles += 11; }
+            0x1E => { e = byte!(); cycles += 7; }
+            0x21 => { let v = word!(); set_hl!(v); cycles += 10; }
+            0x26 => { h = byte!(); cycles += 7; }
+            0x29 => { add_rr_ss!(hl, hl); cycles += 11; }
+            0x2E => { l = byte!(); cycles += 7; }
+            0x31 => { let v = word!(); set_sp!(v); cycles += 10; }
+            0x37 => { a = byte!(); cycles += 7; } // FIXME: This is SCF, but z80asm uses it for LD A,n
+            0x39 => { add_rr_ss!(hl, sp); cycles += 11; }
+            0x3E => { a = byte!(); cycles += 7; }
             0x76 => {
-                z80.stop_reason = StopReason::Halt;
+                cycles += 4;
+                stop_reason = StopReason::Halt;
                 break;
             }
-            0x80 => { add_a_r!(b); }
-            0x81 => { add_a_r!(c); }
-            0x82 => { add_a_r!(d); }
-            0x83 => { add_a_r!(e); }
-            0x84 => { add_a_r!(h); }
-            0x85 => { add_a_r!(l); }
-            0x86 => { let n = at_hl!(); add_a_r!(n); }
-            0x87 => { add_a_r!(a); }
-            0x88 => { adc_a_r!(b); }
-            0x89 => { adc_a_r!(c); }
-            0x8A => { adc_a_r!(d); }
-            0x8B => { adc_a_r!(e); }
-            0x8C => { adc_a_r!(h); }
-            0x8D => { adc_a_r!(l); }
-            0x8E => { let n = at_hl!(); adc_a_r!(n); }
-            0x8F => { adc_a_r!(a); }
-            0xA0 => { and_a_r!(b); }
-            0xA1 => { and_a_r!(c); }
-            0xA2 => { and_a_r!(d); }
-            0xA3 => { and_a_r!(e); }
-            0xA4 => { and_a_r!(h); }
-            0xA5 => { and_a_r!(l); }
-            0xA6 => { let n = at_hl!(); and_a_r!(n); }
-            0xA7 => { and_a_r!(a); }
-            0xC3 => { pc = peek_word!(); }
-            0xC6 => { let n = byte!(); add_a_r!(n); }
+            0x80 => { add_a_r!(b); cycles += 4; }
+            0x81 => { add_a_r!(c); cycles += 4; }
+            0x82 => { add_a_r!(d); cycles += 4; }
+            0x83 => { add_a_r!(e); cycles += 4; }
+            0x84 => { add_a_r!(h); cycles += 4; }
+            0x85 => { add_a_r!(l); cycles += 4; }
+            0x86 => { let n = at_hl!(); add_a_r!(n); cycles += 7; }
+            0x87 => { add_a_r!(a); cycles += 4; }
+            0x88 => { adc_a_r!(b); cycles += 4; }
+            0x89 => { adc_a_r!(c); cycles += 4; }
+            0x8A => { adc_a_r!(d); cycles += 4; }
+            0x8B => { adc_a_r!(e); cycles += 4; }
+            0x8C => { adc_a_r!(h); cycles += 4; }
+            0x8D => { adc_a_r!(l); cycles += 4; }
+            0x8E => { let n = at_hl!(); adc_a_r!(n); cycles += 7; }
+            0x8F => { adc_a_r!(a); cycles += 4; }
+            0xA0 => { and_a_r!(b); cycles += 4; }
+            0xA1 => { and_a_r!(c); cycles += 4; }
+            0xA2 => { and_a_r!(d); cycles += 4; }
+            0xA3 => { and_a_r!(e); cycles += 4; }
+            0xA4 => { and_a_r!(h); cycles += 4; }
+            0xA5 => { and_a_r!(l); cycles += 4; }
+            0xA6 => { let n = at_hl!(); and_a_r!(n); cycles += 7; }
+            0xA7 => { and_a_r!(a); cycles += 4; }
+            0xC2 => { jp_cc!(f & ZERO_FLAG == 0); cycles += 10; }
+            0xC3 => { pc = peek_word!(); cycles += 10; }
+            0xC6 => { let n = byte!(); add_a_r!(n); cycles += 7; }
+            0xC9 => { pc = pop!(); cycles += 10; }
+            0xCA => { jp_cc!(f & ZERO_FLAG != 0); cycles += 10; }
             0xCB => {
-                match byte!() {
-                    0x46 => { let n = at_hl!(); bit_b!(n, 0); }
-                    0x4E => { let n = at_hl!(); bit_b!(n, 1); }
-                    0x56 => { let n = at_hl!(); bit_b!(n, 2); }
-                    0x5E => { let n = at_hl!(); bit_b!(n, 3); }
-                    0x66 => { let n = at_hl!(); bit_b!(n, 4); }
-                    0x6E => { let n = at_hl!(); bit_b!(n, 5); }
-                    0x76 => { let n = at_hl!(); bit_b!(n, 6); }
-                    0x7E => { let n = at_hl!(); bit_b!(n, 7); }
+                match opcode!() {
+                    0x46 => { let n = at_hl!(); bit_b!(n, 0); cycles += 12; }
+                    0x4E => { let n = at_hl!(); bit_b!(n, 1); cycles += 12; }
+                    0x56 => { let n = at_hl!(); bit_b!(n, 2); cycles += 12; }
+                    0x5E => { let n = at_hl!(); bit_b!(n, 3); cycles += 12; }
+                    0x66 => { let n = at_hl!(); bit_b!(n, 4); cycles += 12; }
+                    0x6E => { let n = at_hl!(); bit_b!(n, 5); cycles += 12; }
+                    0x76 => { let n = at_hl!(); bit_b!(n, 6); cycles += 12; }
+                    0x7E => { let n = at_hl!(); bit_b!(n, 7); cycles += 12; }
                     _ =>    { break; }
                 }
             }
-            0xCE => { let n = byte!(); adc_a_r!(n); }
+            0xCD => {
+                let target = word!();
+                push!(pc);
+                pc = target;
+                cycles += 17;
+            }
+            0xCE => { let n = byte!(); adc_a_r!(n); cycles += 7; }
+            0xD2 => { jp_cc!(f & CARRY_FLAG == 0); cycles += 10; }
             0xD3 => {
                 z80.port_addr = byte!();
-                z80.stop_reason = StopReason::Out;
+                cycles += 11;
+                stop_reason = StopReason::Out;
                 break;
             }
             0xD9 => {
@@ -391,10 +628,13 @@ pub fn run(z80: &mut Z80, mut timeslice: usize) {
                 swap!(e, z80.e_alt);
                 swap!(h, z80.h_alt);
                 swap!(l, z80.l_alt);
+                cycles += 4;
             }
+            0xDA => { jp_cc!(f & CARRY_FLAG != 0); cycles += 10; }
             0xDB => {
                 z80.port_addr = byte!();
-                z80.stop_reason = StopReason::In;
+                cycles += 11;
+                stop_reason = StopReason::In;
                 break;
             }
             0xDD => {
@@ -406,31 +646,47 @@ pub fn run(z80: &mut Z80, mut timeslice: usize) {
                     }}
                 }
 
-                match byte!() {
-                    0x09 => { add_rr_ss!(ix, bc); }
-                    0x19 => { add_rr_ss!(ix, de); }
-                    0x29 => { add_rr_ss!(ix, ix); }
-                    0x39 => { add_rr_ss!(ix, sp); }
-                    0x86 => { op_a_ixd!(add_a_r); }
-                    0x8E => { op_a_ixd!(adc_a_r); }
-                    0xA6 => { op_a_ixd!(and_a_r); }
+                match opcode!() {
+                    0x09 => { add_rr_ss!(ix, bc); cycles += 15; }
+                    0x19 => { add_rr_ss!(ix, de); cycles += 15; }
+                    0x29 => { add_rr_ss!(ix, ix); cycles += 15; }
+                    0x39 => { add_rr_ss!(ix, sp); cycles += 15; }
+                    0x86 => { op_a_ixd!(add_a_r); cycles += 19; }
+                    0x8E => { op_a_ixd!(adc_a_r); cycles += 19; }
+                    0xA6 => { op_a_ixd!(and_a_r); cycles += 19; }
                     _ =>    { break; }
                 }
             }
-            0xE6 => { let n = byte!(); and_a_r!(n); }
+            0xE2 => { jp_cc!(f & PARITY_FLAG == 0); cycles += 10; }
+            0xE6 => { let n = byte!(); and_a_r!(n); cycles += 7; }
+            0xEA => { jp_cc!(f & PARITY_FLAG != 0); cycles += 10; }
             0xEB => {
                 swap!(d, h);
                 swap!(e, l);
+                cycles += 4;
             }
             0xED => {
-                match byte!() {
-                    0x4A => { adc_hl_ss!(bc); }
-                    0x5A => { adc_hl_ss!(de); }
-                    0x6A => { adc_hl_ss!(hl); }
-                    0x7A => { adc_hl_ss!(sp); }
+                match opcode!() {
+                    0x46 => { im = 0; cycles += 8; }
+                    0x4A => { adc_hl_ss!(bc); cycles += 15; }
+                    0x56 => { im = 1; cycles += 8; }
+                    0x5A => { adc_hl_ss!(de); cycles += 15; }
+                    0x5E => { im = 2; cycles += 8; }
+                    0x6A => { adc_hl_ss!(hl); cycles += 15; }
+                    0x7A => { adc_hl_ss!(sp); cycles += 15; }
+                    op if trap == Some(op) => {
+                        let n = byte!();
+                        cycles += 11;
+                        stop_reason = StopReason::Trap(n);
+                        break;
+                    }
                     _ =>    { break; }
                 }
             }
+            0xF2 => { jp_cc!(f & SIGN_FLAG == 0); cycles += 10; }
+            0xF3 => { iff1 = false; iff2 = false; cycles += 4; }
+            0xFA => { jp_cc!(f & SIGN_FLAG != 0); cycles += 10; }
+            0xFB => { iff1 = true; iff2 = true; cycles += 4; }
             0xFD => {
                 macro_rules! op_a_iyd {
                     ($op:ident) => {{
@@ -440,14 +696,14 @@ pub fn run(z80: &mut Z80, mut timeslice: usize) {
                     }}
                 }
 
-                match byte!() {
-                    0x09 => { add_rr_ss!(iy, bc); }
-                    0x19 => { add_rr_ss!(iy, de); }
-                    0x29 => { add_rr_ss!(iy, iy); }
-                    0x39 => { add_rr_ss!(iy, sp); }
-                    0x86 => { op_a_iyd!(add_a_r); }
-                    0x8E => { op_a_iyd!(adc_a_r); }
-                    0xA6 => { op_a_iyd!(and_a_r); }
+                match opcode!() {
+                    0x09 => { add_rr_ss!(iy, bc); cycles += 15; }
+                    0x19 => { add_rr_ss!(iy, de); cycles += 15; }
+                    0x29 => { add_rr_ss!(iy, iy); cycles += 15; }
+                    0x39 => { add_rr_ss!(iy, sp); cycles += 15; }
+                    0x86 => { op_a_iyd!(add_a_r); cycles += 19; }
+                    0x8E => { op_a_iyd!(adc_a_r); cycles += 19; }
+                    0xA6 => { op_a_iyd!(and_a_r); cycles += 19; }
                     _ =>    { break; }
                 }
             }
@@ -467,4 +723,71 @@ pub fn run(z80: &mut Z80, mut timeslice: usize) {
     z80.e = e;
     z80.h = h;
     z80.l = l;
+    z80.r = r;
+    z80.cycles = cycles;
+    z80.im = im;
+    z80.iff1 = iff1;
+    z80.iff2 = iff2;
+    z80.coverage = coverage;
+    z80.exec_check = check;
+    z80.bus_trace = trace;
+    z80.stop_reason = stop_reason;
+}
+
+// Run one instruction.  The CPU stops with Poll, or with the reason it stopped
+// early as for run(); for IN and OUT the embedder completes the instruction.
+
+pub fn step(z80: &mut Z80) {
+    run(z80, 2);
+}
+
+// Signal a maskable interrupt.  If interrupts are enabled the CPU accepts it:
+// the current pc is pushed and control transfers according to the interrupt
+// mode.  In mode 0 `data` is the instruction placed on the bus, which must be
+// an RST; in mode 2 it is the low byte of the vector address.  Returns true if
+// the interrupt was accepted.
+
+pub fn interrupt(z80: &mut Z80, data: u8) -> bool {
+    if !z80.iff1 {
+        return false;
+    }
+    z80.iff1 = false;
+    z80.iff2 = false;
+    z80.r = (z80.r & 0x80) | (z80.r.wrapping_add(1) & 0x7F);
+
+    let pc = z80.pc;
+    z80.sp = z80.sp.wrapping_sub(1);
+    z80.mem[z80.sp as usize] = (pc >> 8) as u8;
+    z80.sp = z80.sp.wrapping_sub(1);
+    z80.mem[z80.sp as usize] = pc as u8;
+    if let Some(ref mut chk) = z80.exec_check {
+        chk.load(z80.sp, 2);
+    }
+    if let Some(ref mut t) = z80.bus_trace {
+        t.write(z80.sp.wrapping_add(1), (pc >> 8) as u8);
+        t.write(z80.sp, pc as u8);
+    }
+
+    match z80.im {
+        0 => {
+            z80.pc = (data & 0x38) as u16;
+            z80.cycles += 13;
+        }
+        1 => {
+            z80.pc = 0x0038;
+            z80.cycles += 13;
+        }
+        _ => {
+            let vector = ((z80.i as u16) << 8) | (data as u16);
+            let lo = z80.mem[vector as usize];
+            let hi = z80.mem[vector.wrapping_add(1) as usize];
+            if let Some(ref mut t) = z80.bus_trace {
+                t.read(vector, lo);
+                t.read(vector.wrapping_add(1), hi);
+            }
+            z80.pc = (lo as u16) | ((hi as u16) << 8);
+            z80.cycles += 19;
+        }
+    }
+    true
 }
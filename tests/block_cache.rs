@@ -0,0 +1,144 @@
+// Differential tests of the block cache: programs are run through the cache in
+// differential mode, which checks every timeslice against z80::run.
+
+extern crate z80emu;
+
+use z80emu::block_cache;
+use z80emu::bus_trace;
+use z80emu::z80::{self, StopReason, Z80};
+
+// Instructions the CPU supports, excluding those that stop it.
+
+const INSTRUCTIONS : &[&[u8]] = &[
+    &[0x00], &[0x08], &[0x09], &[0x19], &[0x29], &[0x37, 0x5A], &[0x39],
+    &[0x01, 0x34, 0x12], &[0x06, 0x80], &[0x0E, 0xFF], &[0x11, 0x00, 0x80], &[0x16, 0x01],
+    &[0x1E, 0x7F], &[0x21, 0x00, 0x20], &[0x26, 0x30], &[0x2E, 0x0F], &[0x31, 0xFE, 0xFF], &[0x3E, 0x01],
+    &[0x80], &[0x81], &[0x82], &[0x83], &[0x84], &[0x85], &[0x86], &[0x87],
+    &[0x88], &[0x89], &[0x8A], &[0x8B], &[0x8C], &[0x8D], &[0x8E], &[0x8F],
+    &[0xA0], &[0xA1], &[0xA2], &[0xA3], &[0xA4], &[0xA5], &[0xA6], &[0xA7],
+    &[0xC6, 0x81], &[0xCE, 0x0F], &[0xE6, 0xF0],
+    &[0xCB, 0x46], &[0xCB, 0x5E], &[0xCB, 0x7E],
+    &[0xD9], &[0xEB], &[0xF3], &[0xFB],
+    &[0xDD, 0x09], &[0xDD, 0x29], &[0xDD, 0x86, 0x05], &[0xDD, 0x8E, 0xFE], &[0xDD, 0xA6, 0x10],
+    &[0xFD, 0x19], &[0xFD, 0x39], &[0xFD, 0x86, 0x7F], &[0xFD, 0x8E, 0x00], &[0xFD, 0xA6, 0x80],
+    &[0xED, 0x46], &[0xED, 0x56], &[0xED, 0x5E],
+    &[0xED, 0x4A], &[0xED, 0x5A], &[0xED, 0x6A], &[0xED, 0x7A],
+];
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+// A random program of `count` instructions at `start`, with conditional jumps
+// back to earlier instructions mixed in, ending with a jump to the start.
+
+fn random_program(cpu: &mut Z80, rng: &mut Rng, start: u16, count: usize)
+{
+    let mut pc = start as usize;
+    let mut starts = vec![];
+    for _ in 0..count {
+        starts.push(pc);
+        if rng.next() & 7 == 0 {
+            let jp_cc = [0xC2, 0xCA, 0xD2, 0xDA, 0xE2, 0xEA, 0xF2, 0xFA];
+            let target = starts[rng.next() as usize % starts.len()];
+            cpu.mem[pc] = jp_cc[rng.next() as usize % jp_cc.len()];
+            cpu.mem[pc + 1] = target as u8;
+            cpu.mem[pc + 2] = (target >> 8) as u8;
+            pc += 3;
+        } else {
+            let insn = INSTRUCTIONS[rng.next() as usize % INSTRUCTIONS.len()];
+            cpu.mem[pc..pc + insn.len()].copy_from_slice(insn);
+            pc += insn.len();
+        }
+    }
+    cpu.mem[pc..pc + 3].copy_from_slice(&[0xC3, start as u8, (start >> 8) as u8]);
+}
+
+#[test]
+fn random_programs()
+{
+    let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+    for _ in 0..50 {
+        let mut cpu = z80::make(0x100);
+        for b in cpu.mem.iter_mut() {
+            *b = rng.next() as u8;
+        }
+        cpu.b = rng.next() as u8;
+        cpu.c = rng.next() as u8;
+        cpu.h = rng.next() as u8;
+        cpu.ix = rng.next() as u16;
+        random_program(&mut cpu, &mut rng, 0x100, 200);
+
+        let mut cache = block_cache::make_differential();
+        for _ in 0..20 {
+            // Odd timeslices end in the middle of blocks
+            block_cache::run(&mut cpu, &mut cache, 1 + rng.next() as usize % 500);
+            assert_eq!(cpu.stop_reason, StopReason::Poll);
+        }
+    }
+}
+
+#[test]
+fn stops_like_the_interpreter()
+{
+    // LD A,41h; OUT (1),A; IN A,(2); HALT; then an illegal instruction
+    let mut cpu = z80::make(0);
+    cpu.mem[..8].copy_from_slice(&[0x37, 0x41, 0xD3, 0x01, 0xDB, 0x02, 0x76, 0xED]);
+    let mut cache = block_cache::make_differential();
+    for &reason in &[StopReason::Out, StopReason::In, StopReason::Halt, StopReason::Illegal] {
+        block_cache::run(&mut cpu, &mut cache, 1000);
+        assert_eq!(cpu.stop_reason, reason);
+    }
+
+    // LD A,1; a trap; HALT
+    let mut cpu = z80::make(0);
+    cpu.mem[..6].copy_from_slice(&[0x37, 0x01, 0xED, 0xFE, 0x07, 0x76]);
+    cpu.trap = Some(0xFE);
+    for &reason in &[StopReason::Trap(7), StopReason::Halt] {
+        block_cache::run(&mut cpu, &mut cache, 1000);
+        assert_eq!(cpu.stop_reason, reason);
+    }
+}
+
+#[test]
+fn rewritten_code_is_decoded_again()
+{
+    // 0000  LD A,1; JP 0000
+    let mut cpu = z80::make(0);
+    cpu.mem[..5].copy_from_slice(&[0x37, 0x01, 0xC3, 0x00, 0x00]);
+    let mut cache = block_cache::make_differential();
+    block_cache::run(&mut cpu, &mut cache, 100);
+    assert_eq!(cpu.a, 1);
+
+    // Patch the operand, as a device or another program would
+    cpu.mem[1] = 2;
+    block_cache::run(&mut cpu, &mut cache, 100);
+    assert_eq!(cpu.a, 2);
+}
+
+#[test]
+fn bus_trace_sees_every_access()
+{
+    // 0000  LD A,1; ADD A,(HL); JP 0000
+    let code = [0x37, 0x01, 0x86, 0xC3, 0x00, 0x00];
+    let trace = |cached: bool| {
+        let mut cpu = z80::make(0);
+        cpu.mem[..code.len()].copy_from_slice(&code);
+        cpu.h = 0x10;
+        cpu.bus_trace = Some(Box::new(bus_trace::make()));
+        if cached {
+            block_cache::run(&mut cpu, &mut block_cache::make(), 10);
+        } else {
+            z80::run(&mut cpu, 10);
+        }
+        cpu.bus_trace.unwrap().accesses().to_vec()
+    };
+    assert_eq!(trace(true), trace(false));
+}
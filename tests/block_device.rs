@@ -0,0 +1,150 @@
+// Tests of the file backed block device, driven through the BlockDevice
+// interface as the machine drives it.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use common::temp_file;
+use z80emu::devices::{BlockDevice, DeviceState, SpinningDiskStatus};
+use z80emu::file_backed_block_device::{self, BLOCK_SIZE};
+
+const SEEK : u8 = 0x00;
+const READ : u8 = 0x01;
+const WRITE : u8 = 0x02;
+const CLEAR : u8 = 0x03;
+
+// Select `count` blocks from `lba` and a DMA address, and perform `op` on them,
+// returning the status.
+
+fn transfer(dev: &mut dyn BlockDevice, op: u8, lba: u32, count: u8, dma: u16, mem: &mut [u8])
+            -> SpinningDiskStatus
+{
+    for (n, &b) in lba.to_le_bytes().iter().enumerate() {
+        dev.set_lba(n, b);
+    }
+    dev.set_count(count);
+    dev.set_dma_low(dma as u8);
+    dev.set_dma_high((dma >> 8) as u8);
+    dev.block_operation(CLEAR, mem);
+    dev.block_operation(SEEK, mem);
+    if dev.get_status() != SpinningDiskStatus::Done {
+        return dev.get_status();
+    }
+    dev.block_operation(CLEAR, mem);
+    dev.block_operation(op, mem);
+    dev.get_status()
+}
+
+// An image in which each block is filled with its LBA.
+
+fn numbered_image(blocks: usize) -> Vec<u8>
+{
+    (0..blocks).flat_map(|n| vec![n as u8; BLOCK_SIZE]).collect()
+}
+
+#[test]
+fn multi_block_read_and_write()
+{
+    let file = temp_file::make(&numbered_image(8));
+    let mut dev = file_backed_block_device::make(file.path(), 8);
+    let mut mem = vec![0; 65536];
+    assert_eq!(transfer(&mut dev, READ, 2, 3, 0x1000, &mut mem), SpinningDiskStatus::Done);
+    for n in 0..3 {
+        let block = &mem[0x1000 + n * BLOCK_SIZE..0x1000 + (n + 1) * BLOCK_SIZE];
+        assert!(block.iter().all(|&b| b == 2 + n as u8), "Block {}", n);
+    }
+    assert_eq!(dev.written(), Some((0x1000, 3 * BLOCK_SIZE)));
+
+    // Write them back two blocks further on
+    assert_eq!(transfer(&mut dev, WRITE, 4, 3, 0x1000, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(dev.written(), None);
+    let contents = file.contents();
+    let expected = [0, 1, 2, 3, 2, 3, 4, 7];
+    for (n, &e) in expected.iter().enumerate() {
+        assert!(contents[n * BLOCK_SIZE..(n + 1) * BLOCK_SIZE].iter().all(|&b| b == e), "Block {}", n);
+    }
+}
+
+#[test]
+fn lba_is_set_a_byte_at_a_time()
+{
+    let file = temp_file::make(&[]);
+    let mut dev = file_backed_block_device::make(file.path(), 0x10000);
+    let mut mem = vec![0; 65536];
+    mem[..BLOCK_SIZE].copy_from_slice(&[0xA5; BLOCK_SIZE]);
+    assert_eq!(transfer(&mut dev, WRITE, 0x0102, 1, 0, &mut mem), SpinningDiskStatus::Done);
+    let contents = file.contents();
+    assert_eq!(contents.len(), 0x0103 * BLOCK_SIZE);
+    assert!(contents[0x0102 * BLOCK_SIZE..].iter().all(|&b| b == 0xA5));
+    assert!(contents[..0x0102 * BLOCK_SIZE].iter().all(|&b| b == 0));
+
+    // Setting one byte keeps the others
+    dev.set_lba(0, 0x03);
+    dev.block_operation(CLEAR, &mut mem);
+    dev.block_operation(SEEK, &mut mem);
+    dev.block_operation(CLEAR, &mut mem);
+    dev.block_operation(WRITE, &mut mem);
+    assert_eq!(file.contents().len(), 0x0104 * BLOCK_SIZE);
+}
+
+#[test]
+fn transfers_must_fit_the_device()
+{
+    let file = temp_file::make(&[]);
+    let mut dev = file_backed_block_device::make(file.path(), 300);
+    let mut mem = vec![0; 65536];
+    assert_eq!(transfer(&mut dev, READ, 299, 1, 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(transfer(&mut dev, READ, 299, 2, 0, &mut mem), SpinningDiskStatus::SeekError);
+    assert_eq!(transfer(&mut dev, READ, 300, 1, 0, &mut mem), SpinningDiskStatus::SeekError);
+    assert_eq!(transfer(&mut dev, READ, 0x0100_0000, 1, 0, &mut mem), SpinningDiskStatus::SeekError);
+
+    // A count of 0 is 256 blocks
+    assert_eq!(transfer(&mut dev, READ, 44, 0, 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(dev.written(), Some((0, 256 * BLOCK_SIZE)));
+    assert_eq!(transfer(&mut dev, READ, 45, 0, 0, &mut mem), SpinningDiskStatus::SeekError);
+}
+
+#[test]
+fn read_past_the_end_is_zeroes()
+{
+    let file = temp_file::make(&numbered_image(1)[..100]);
+    let mut dev = file_backed_block_device::make(file.path(), 4);
+    let mut mem = vec![0xFF; 65536];
+    assert_eq!(transfer(&mut dev, READ, 0, 2, 0, &mut mem), SpinningDiskStatus::Done);
+    assert!(mem[..2 * BLOCK_SIZE].iter().all(|&b| b == 0));
+    assert_eq!(mem[2 * BLOCK_SIZE], 0xFF);
+}
+
+#[test]
+fn state_round_trips()
+{
+    let file = temp_file::make(&numbered_image(4));
+    let mut dev = file_backed_block_device::make(file.path(), 4);
+    let mut mem = vec![0; 65536];
+    transfer(&mut dev, SEEK, 2, 2, 0x0300, &mut mem);
+    let mut state = vec![];
+    dev.save_state(&mut state);
+
+    let mut restored = file_backed_block_device::make(file.path(), 4);
+    assert!(restored.restore_state(&state));
+    assert!(!restored.restore_state(&state[1..]));
+    restored.block_operation(CLEAR, &mut mem);
+    restored.block_operation(READ, &mut mem);
+    assert_eq!(restored.get_status(), SpinningDiskStatus::Done);
+    assert_eq!((mem[0x0300], mem[0x0300 + BLOCK_SIZE], mem[0x0300 + 2 * BLOCK_SIZE]), (2, 3, 0));
+}
+
+#[test]
+fn write_protected_device()
+{
+    let file = temp_file::make(&numbered_image(4));
+    let mut dev = file_backed_block_device::make(file.path(), 4);
+    let mut mem = vec![0; 65536];
+    dev.set_write_protect(true);
+    assert_eq!(transfer(&mut dev, WRITE, 1, 2, 0, &mut mem), SpinningDiskStatus::WriteProtected);
+    assert_eq!(transfer(&mut dev, READ, 3, 1, 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(file.contents(), numbered_image(4));
+}
@@ -0,0 +1,105 @@
+// End-to-end tests: boot rom.bin, which loads a_drive.bin and runs it, and
+// check what the guest prints.  See common/harness.rs.  These need the `std`
+// feature, for the machine.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use std::fs;
+
+use common::harness;
+use z80emu::z80::StopReason;
+
+// The program on a_drive.bin is loaded at 0100h and ends with a HALT.
+
+const DISK_HALT : u16 = 0x0138;
+
+#[test]
+fn boot_prints_hello()
+{
+    let run = harness::boot().run();
+    assert!(run.output().contains("Hello, world!"), "Output: {:?}", run.output());
+    assert_eq!(run.halted_at(), DISK_HALT);
+}
+
+#[test]
+fn boot_output_matches_golden()
+{
+    harness::boot().run().assert_golden("tests/golden/boot.txt");
+}
+
+#[test]
+fn boot_with_block_cache()
+{
+    let run = harness::boot().block_cache().run();
+    assert_eq!(run.halted_at(), DISK_HALT);
+    run.assert_golden("tests/golden/boot.txt");
+}
+
+#[test]
+fn cycle_limit_stops_the_run()
+{
+    let run = harness::boot().cycle_limit(100).run();
+    assert_eq!(run.stop, StopReason::Poll);
+    assert!(run.cpu.cycles >= 100);
+    assert!(!run.output().contains("Hello"));
+}
+
+#[test]
+fn without_a_disk()
+{
+    // LD A,'?'; OUT (0),A; HALT
+    let run = harness::guest(&[0x37, b'?', 0xD3, 0x00, 0x76]).run();
+    assert_eq!(run.output(), "?");
+    assert_eq!(run.halted_at(), harness::ROM_ADDR as u16 + 4);
+}
+
+#[test]
+fn echo_input()
+{
+    // Echo input until none is available, then halt:
+    //
+    //   loop: IN A,(1); AND A; JP Z,done; IN A,(0); OUT (0),A; JP loop
+    //   done: HALT
+    let rom = harness::ROM_ADDR as u16;
+    let done = rom + 16;
+    let code = [
+        0xDB, 0x01, 0xA7, 0xCA, done as u8, (done >> 8) as u8,
+        0xDB, 0x00, 0xD3, 0x00, 0xC3, rom as u8, (rom >> 8) as u8,
+        0x00, 0x00, 0x00, 0x76,
+    ];
+    let run = harness::guest(&code).input(b"abc").run();
+    assert_eq!(run.output(), "abc");
+    assert_eq!(run.halted_at(), done);
+}
+
+#[test]
+fn boot_from_random_power_on_state()
+{
+    for seed in 0..8 {
+        let run = harness::boot().power_on(seed, true).run();
+        assert_eq!(run.halted_at(), DISK_HALT, "Seed {}", seed);
+        run.assert_golden("tests/golden/boot.txt");
+    }
+}
+
+#[test]
+fn boot_runs_only_loaded_code()
+{
+    let run = harness::boot().exec_check().run();
+    assert_eq!(run.halted_at(), DISK_HALT);
+    assert_eq!(run.findings, vec![]);
+}
+
+#[test]
+fn boot_from_a_ram_disk()
+{
+    let image = fs::read("a_drive.bin").unwrap();
+    let run = harness::guest(&fs::read("rom.bin").unwrap()).ram_drive(0, &image, 1, 1, 1).run();
+    assert_eq!(run.halted_at(), DISK_HALT);
+    run.assert_golden("tests/golden/boot.txt");
+    assert_eq!(run.image(0), &image[..]);
+}
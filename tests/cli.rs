@@ -0,0 +1,472 @@
+// Tests of the z80emu front end, run as a program in a temporary directory
+// that holds its rom.bin and disk images.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use std::fs;
+use std::process::{Command, Output, Stdio};
+
+use common::temp_file::{self, TempDir};
+
+// A directory with the boot ROM and disk A: of the repo.
+
+fn boot_dir() -> TempDir
+{
+    let dir = temp_file::make_dir();
+    dir.write("rom.bin", &fs::read("rom.bin").unwrap());
+    dir.write("a_drive.bin", &fs::read("a_drive.bin").unwrap());
+    dir
+}
+
+fn run(dir: &TempDir, args: &[&str]) -> Output
+{
+    Command::new(env!("CARGO_BIN_EXE_z80emu")).args(args).current_dir(dir.path())
+        .stdin(Stdio::null()).output().unwrap()
+}
+
+#[test]
+fn boots()
+{
+    let out = run(&boot_dir(), &[]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Hello, world!"));
+}
+
+#[test]
+fn short_boot_rom_is_an_error()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &fs::read("rom.bin").unwrap()[..100]);
+    let out = run(&dir, &[]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Could not read `rom.bin`"));
+}
+
+// Disk A: cut short just after the HALT of its program.  The rest of the boot
+// sector reads as zeroes.
+
+#[test]
+fn short_disk_image_boots()
+{
+    let dir = boot_dir();
+    dir.write("a_drive.bin", &fs::read("a_drive.bin").unwrap()[..0x39]);
+    let out = run(&dir, &[]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(String::from_utf8_lossy(&out.stdout).ends_with("Hello, world!\n"));
+}
+
+// A boot ROM that runs `code` and halts.
+
+fn rom(code: &[u8]) -> Vec<u8>
+{
+    let mut rom = code.to_vec();
+    rom.push(0x76);                             // HALT
+    rom.resize(128, 0x00);
+    rom
+}
+
+// Code that sets the flags with `setup`, and prints T if the JP cc,nn with
+// opcode `jp` is taken and N if not.  `at` is the address of the code.
+
+fn branch(at: u16, jp: u8, setup: &[u8]) -> Vec<u8>
+{
+    let mut code = setup.to_vec();
+    let out = at + code.len() as u16 + 7;
+    code.extend_from_slice(&[0x37, b'T',                    // LD A,'T'
+                             jp, out as u8, (out >> 8) as u8, // JP cc,out
+                             0x37, b'N',                    // LD A,'N'
+                             0xD3, 0x00]);                  // out: OUT (0),A
+    code
+}
+
+#[test]
+fn conditional_jumps()
+{
+    const ZERO : &[u8] = &[0x37, 0x00, 0xA7];   // LD A,0; AND A
+    const ONE : &[u8] = &[0x37, 0x01, 0xA7];    // LD A,1; AND A
+    const NEG : &[u8] = &[0x37, 0x80, 0xA7];    // LD A,80h; AND A
+    const CARRY : &[u8] = &[0x37, 0x80, 0x87];  // LD A,80h; ADD A,A
+    const NO_CARRY : &[u8] = &[0x37, 0x01, 0x87]; // LD A,1; ADD A,A
+
+    // The opcode, and flags that make it jump and flags that do not.  AND A
+    // sets the parity flag for the even parity of 0 and clears it for 1.
+    let cases : &[(u8, &[&[u8]], &str)] = &[
+        (0xC2, &[ONE, ZERO], "TN"), (0xCA, &[ZERO, ONE], "TN"),
+        (0xD2, &[NO_CARRY, CARRY], "TN"), (0xDA, &[CARRY, NO_CARRY], "TN"),
+        (0xE2, &[ONE, ZERO], "TN"), (0xEA, &[ZERO, ONE], "TN"),
+        (0xF2, &[ONE, NEG], "TN"), (0xFA, &[NEG, ONE], "TN"),
+    ];
+    for &(jp, setups, expected) in cases {
+        let mut code = vec![];
+        for setup in setups {
+            let at = 0xFF80 + code.len() as u16;
+            code.extend(branch(at, jp, setup));
+        }
+        let dir = boot_dir();
+        dir.write("rom.bin", &rom(&code));
+        let out = run(&dir, &[]);
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), expected, "JP cc with opcode {:02X}h", jp);
+    }
+}
+
+// Coverage of a ROM with a branch that is taken, through two source maps, the
+// second of which moves the last instruction to another file.
+
+#[test]
+fn coverage_report()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &rom(&[0x37, 0x00,             // FF80  LD A,0
+                                0xA7,                   // FF82  AND A
+                                0xCA, 0x89, 0xFF,       // FF83  JP Z,FF89h
+                                0x37, b'N',             // FF86  LD A,'N'
+                                0xD3, 0x00]));          // FF88  OUT (0),A
+                                                        // FF8A  HALT
+    dir.write("rom.map", b"ff80\t1\trom.asm\nff82\t2\trom.asm\nff83\t3\trom.asm\n\
+                          ff86\t4\trom.asm\nff88\t5\trom.asm\nff8a\t6\trom.asm\n");
+    dir.write("halt.map", b"ff8a\t1\thalt.asm\n");
+    let out = run(&dir, &["--coverage", "rom.lcov", "--source-map", "rom.map", "--source-map", "halt.map"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&dir.read("rom.lcov")),
+               "TN:\n\
+                SF:halt.asm\nDA:1,1\nLF:1\nLH:1\nend_of_record\n\
+                SF:rom.asm\nBRDA:3,65411,0,1\nBRDA:3,65411,1,0\nBRF:2\nBRH:1\n\
+                DA:1,1\nDA:2,1\nDA:3,1\nDA:4,0\nDA:5,0\nLF:5\nLH:3\nend_of_record\n");
+}
+
+#[test]
+fn bad_source_map_is_an_error()
+{
+    let dir = boot_dir();
+    dir.write("bad.map", b"ff80\t1\trom.asm\nff8x\t2\trom.asm\n");
+    let out = run(&dir, &["--coverage", "rom.lcov", "--source-map", "bad.map"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Bad source map line in `bad.map`: ff8x"));
+}
+
+// A boot ROM that prints "a", seeks disk A: to its boot sector with the DMA
+// address at 0100h, and halts.  Resumed after the HALT, it reads the sector
+// and jumps to it.
+
+fn seek_then_halt_rom() -> Vec<u8>
+{
+    rom(&[0x37, b'a', 0xD3, 0x00,               // LD A,'a'; OUT (0),A
+          0x37, 0x00, 0xD3, 0x10, 0xD3, 0x11,   // LD A,0; OUT (SET_HEAD),A; OUT (SET_TRACK),A
+          0xD3, 0x12, 0xD3, 0x13,               // OUT (SET_SECTOR),A; OUT (SET_DMA_LOW),A
+          0x37, 0x01, 0xD3, 0x14,               // LD A,1; OUT (SET_DMA_HIGH),A
+          0x37, 0x03, 0xD3, 0x15,               // CLEAR
+          0x37, 0x00, 0xD3, 0x15,               // SEEK
+          0x76,                                 // HALT
+          0x37, 0x03, 0xD3, 0x15,               // CLEAR
+          0x37, 0x01, 0xD3, 0x15,               // READ
+          0xC3, 0x00, 0x01])                    // JP 0100h
+}
+
+#[test]
+fn save_state_resumes()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &seek_then_halt_rom());
+    let out = run(&dir, &["--save-state", "halted.state"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "a");
+    let state = dir.read("halted.state");
+    assert!(state.starts_with(b"Z80EMUST\x07\x00"));
+
+    // The disk's seek and DMA address are restored with the CPU, so the boot
+    // sector is read and run
+    let out = run(&dir, &["--load-state", "halted.state", "--save-state", "done.state"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "Hello, world!\n");
+    assert_eq!(dir.read("done.state").len(), state.len());
+}
+
+// `state` in an older format.  Older states lack the fields that follow the
+// port address: I, R, IM, IFF1 and IFF2, added in version 2, the cycle count,
+// added in version 3, and the trap number, added in version 4.  Version 5 only
+// added a stop reason.  Before version 6 there were only the TTY and disk A:,
+// and no selected disk, and before version 7 there was no block device.
+
+fn old_state(state: &[u8], version: u8) -> Vec<u8>
+{
+    let mut old = state.to_vec();
+    old[8] = version;
+    let mut end = 50 + 65536;
+    for _ in 0..if version < 6 { 2 } else { 17 } {
+        end += 4 + u32::from_le_bytes([old[end], old[end + 1], old[end + 2], old[end + 3]]) as usize;
+    }
+    old.truncate(end);
+    if version == 6 {
+        old.push(*state.last().unwrap());
+    }
+    old.drain(match version { 1 => 36..50, 2 => 41..50, 3 => 49..50, _ => 50..50 });
+    old
+}
+
+#[test]
+fn older_save_states_resume()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &seek_then_halt_rom());
+    run(&dir, &["--save-state", "halted.state"]);
+    for version in 1..7 {
+        dir.write("old.state", &old_state(&dir.read("halted.state"), version));
+        let out = run(&dir, &["--load-state", "old.state"]);
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "Hello, world!\n", "version {}", version);
+    }
+}
+
+#[test]
+fn bad_save_states_are_errors()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &seek_then_halt_rom());
+    run(&dir, &["--save-state", "good.state"]);
+    let good = dir.read("good.state");
+
+    let mut trailing = good.clone();
+    trailing.push(0);
+    let mut version = good.clone();
+    version[8] = 99;
+    for &(contents, error) in &[(&b"Z80EMUSX\x01\x00"[..], "not a save state"),
+                                (&version[..], "unsupported version 99"),
+                                (&good[..good.len() - 1], "is truncated"),
+                                (&trailing[..], "trailing data")] {
+        dir.write("bad.state", contents);
+        let out = run(&dir, &["--load-state", "bad.state"]);
+        assert!(!out.status.success());
+        assert!(String::from_utf8_lossy(&out.stderr).contains(error), "{}", error);
+    }
+}
+
+// Snapshots written when a ROM halts resume after the HALT.
+
+#[test]
+fn snapshots_resume()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &rom(&[0x37, b'a', 0xD3, 0x00, 0x76,     // LD A,'a'; OUT (0),A; HALT
+                                0x37, b'b', 0xD3, 0x00]));        // LD A,'b'; OUT (0),A
+    for name in &["halted.sna", "halted.z80"] {
+        let out = run(&dir, &["--save-snapshot", name]);
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "a");
+        let out = run(&dir, &["--load-snapshot", name]);
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "b", "{}", name);
+    }
+    assert_eq!(dir.read("halted.sna").len(), 27 + 0xC000);
+}
+
+// A compressed version 1 .Z80 file with a program at 8000h.
+
+#[test]
+fn compressed_z80_snapshot_runs()
+{
+    let mut z80 = vec![0; 30];
+    z80[7] = 0x80;                              // PC
+    z80[12] = 0x20;                             // Compressed
+    let zeroes = |z80: &mut Vec<u8>, mut n: usize| {
+        while n > 0 {
+            let run = n.min(255);
+            z80.extend_from_slice(&[0xED, 0xED, run as u8, 0x00]);
+            n -= run;
+        }
+    };
+    zeroes(&mut z80, 0x4000);
+    let code = [0x37, b'z', 0xD3, 0x00, 0x76];  // LD A,'z'; OUT (0),A; HALT
+    z80.extend_from_slice(&code);
+    zeroes(&mut z80, 0x8000 - code.len());
+    z80.extend_from_slice(&[0x00, 0xED, 0xED, 0x00]);
+
+    let dir = boot_dir();
+    dir.write("prog.z80", &z80);
+    let out = run(&dir, &["--load-snapshot", "prog.z80"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "z");
+
+    z80.truncate(z80.len() - 8);
+    dir.write("short.z80", &z80);
+    let out = run(&dir, &["--load-snapshot", "short.z80"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("wrong amount of memory"));
+
+    dir.write("prog.tap", &[]);
+    let out = run(&dir, &["--load-snapshot", "prog.tap"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Unknown snapshot type"));
+}
+
+// Record the boot, and replay it with a blank disk: the boot sector comes from
+// the recording.  The replay prints nothing, but ends in the same state.
+
+#[test]
+fn replay_gives_the_same_run()
+{
+    let dir = boot_dir();
+    let recorded = run(&dir, &["--record", "boot.log", "--save-snapshot", "recorded.z80"]);
+    assert!(recorded.status.success(), "{}", String::from_utf8_lossy(&recorded.stderr));
+    assert!(dir.read("boot.log").starts_with(b"Z80EMURR\x01\x00"));
+
+    dir.write("a_drive.bin", &[0; 128]);
+    let replayed = run(&dir, &["--replay", "boot.log", "--save-snapshot", "replayed.z80"]);
+    assert!(replayed.status.success(), "{}", String::from_utf8_lossy(&replayed.stderr));
+    assert!(replayed.stdout.is_empty());
+    assert_eq!(dir.read("replayed.z80"), dir.read("recorded.z80"));
+}
+
+// A run that stops on an illegal instruction writes its whole recording, and
+// a replay that stops there checks that it used the whole recording.
+
+#[test]
+fn recordings_are_finished_on_an_illegal_instruction()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &rom(&[0x3E, b'x', 0xD3, 0x00, 0x02]));   // LD A,'x'; OUT (0),A; not implemented
+    let recorded = run(&dir, &["--record", "stop.log"]);
+    assert!(String::from_utf8_lossy(&recorded.stderr).contains("Illegal instruction"));
+    let mut log = dir.read("stop.log");
+    assert_eq!(log.len(), 8 + 2 + 8 + 1 + 8 + 2);    // Header and the OUT
+
+    let replayed = run(&dir, &["--replay", "stop.log"]);
+    let stderr = String::from_utf8_lossy(&replayed.stderr);
+    assert!(stderr.contains("Illegal instruction") && !stderr.contains("Replay"), "{}", stderr);
+
+    // Another OUT that the replay does not reach
+    log.extend_from_within(18..29);
+    dir.write("longer.log", &log);
+    let replayed = run(&dir, &["--replay", "longer.log"]);
+    assert!(String::from_utf8_lossy(&replayed.stderr)
+            .contains("Replay diverged at event #1: expected OUT 78h to port 00h at cycle 18, got stop at cycle"));
+}
+
+#[test]
+fn replay_reports_divergence()
+{
+    let dir = boot_dir();
+    run(&dir, &["--record", "boot.log"]);
+
+    // The first OUT prints "B"
+    let mut log = dir.read("boot.log");
+    let at = 8 + 2 + 8;
+    assert_eq!(&log[at..at + 1], &[1]);
+    assert_eq!(&log[at + 9..at + 11], &[0x00, b'B']);
+    log[at + 10] = b'b';
+    dir.write("tampered.log", &log);
+    let out = run(&dir, &["--replay", "tampered.log"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr)
+            .contains("Replay diverged at event #0: expected OUT 62h to port 00h at cycle 18, got OUT 42h"));
+
+    // A different boot ROM is a different initial state
+    let mut rom = fs::read("rom.bin").unwrap();
+    rom[1] = b'b';
+    dir.write("rom.bin", &rom);
+    let out = run(&dir, &["--replay", "boot.log"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("different initial state"));
+}
+
+// A boot ROM that writes itself to sector 0 of disk A: and then executes an
+// illegal instruction.
+
+fn write_rom_then_stop() -> Vec<u8>
+{
+    let mut rom = vec![
+        0x3E, 0x00, 0xD3, 0x10, 0xD3, 0x11,     // LD A,0; OUT (SET_HEAD),A; OUT (SET_TRACK),A
+        0xD3, 0x12,                             // OUT (SET_SECTOR),A
+        0x3E, 0x80, 0xD3, 0x13,                 // LD A,80h; OUT (SET_DMA_LOW),A
+        0x3E, 0xFF, 0xD3, 0x14,                 // LD A,FFh; OUT (SET_DMA_HIGH),A
+        0x3E, 0x03, 0xD3, 0x15,                 // CLEAR
+        0x3E, 0x00, 0xD3, 0x15,                 // SEEK
+        0x3E, 0x03, 0xD3, 0x15,                 // CLEAR
+        0x3E, 0x02, 0xD3, 0x15,                 // WRITE
+        0x02,                                   // Not implemented
+    ];
+    rom.resize(128, 0x00);
+    rom
+}
+
+#[test]
+fn read_only_disks_are_not_written()
+{
+    let rom = write_rom_then_stop();
+    let dir = temp_file::make_dir();
+    dir.write("rom.bin", &rom);
+    dir.write("a_drive.bin", &[0xE5; 128]);
+    dir.write("b_drive.bin", &[0xE5; 128]);
+
+    let out = run(&dir, &["--read-only", "A"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Illegal instruction"));
+    assert_eq!(dir.read("a_drive.bin"), vec![0xE5; 128]);
+
+    let out = run(&dir, &["--disk", "A:b_drive.bin,1,1,1", "--read-only", "a"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Illegal instruction"));
+    assert_eq!(dir.read("b_drive.bin"), vec![0xE5; 128]);
+
+    let out = run(&dir, &[]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Illegal instruction"));
+    assert_eq!(dir.read("a_drive.bin"), rom);
+}
+
+#[test]
+fn missing_disk_image_is_an_error()
+{
+    let dir = temp_file::make_dir();
+    dir.write("rom.bin", &rom(&[]));
+    let out = run(&dir, &["--read-only", "A"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Could not open `a_drive.bin`"));
+}
+
+#[test]
+fn ram_disks_are_saved_on_an_illegal_instruction()
+{
+    let rom = write_rom_then_stop();
+    let dir = temp_file::make_dir();
+    dir.write("rom.bin", &rom);
+    dir.write("ram.bin", &[0xE5; 128]);
+
+    let out = run(&dir, &["--ram-disk", "A:ram.bin,1,1,1"]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Illegal instruction"));
+    assert_eq!(dir.read("ram.bin"), vec![0xE5; 128]);
+
+    let out = run(&dir, &["--ram-disk", "A:ram.bin,1,1,1", "--save-ram-disks"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Illegal instruction"));
+    assert_eq!(dir.read("ram.bin"), rom);
+}
+
+// The emulator has no trap services, so a trap is an error.
+
+#[test]
+fn traps_are_errors()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &rom(&[0x00, 0xED, 0xFE, 0x07]));   // NOP; trap 7
+    let out = run(&dir, &["--trap", "254"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Unhandled trap 7 at FF81h"));
+
+    // Without --trap the opcode is illegal
+    let out = run(&dir, &[]);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Illegal instruction"));
+}
+
+#[test]
+fn exec_check_stop_is_an_error()
+{
+    let dir = boot_dir();
+    dir.write("rom.bin", &rom(&[0xC3, 0x00, 0x01]));   // JP 0100h, which was never written
+    let out = run(&dir, &["--exec-check-stop"]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("Executed unwritten memory at 0100h"), "{}", stderr);
+    assert!(stderr.contains("Stopped by the exec check"), "{}", stderr);
+}
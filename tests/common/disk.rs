@@ -0,0 +1,40 @@
+// Driving a SpinningDisk through its interface as the machine drives it.
+
+use z80emu::devices::{SpinningDisk, SpinningDiskStatus};
+
+pub const SEEK : u8 = 0x00;
+pub const READ : u8 = 0x01;
+pub const WRITE : u8 = 0x02;
+pub const CLEAR : u8 = 0x03;
+pub const READ_MULTI : u8 = 0x04;
+pub const WRITE_MULTI : u8 = 0x05;
+
+// Select a sector and a DMA address, and perform `op` on it, returning the
+// status.
+
+pub fn transfer(dsk: &mut dyn SpinningDisk, op: u8, (head, track, sector): (u8, u16, u16), dma: u16,
+                mem: &mut [u8]) -> SpinningDiskStatus
+{
+    dsk.set_head(head);
+    dsk.set_track(track as u8);
+    dsk.set_track_high((track >> 8) as u8);
+    dsk.set_sector(sector as u8);
+    dsk.set_sector_high((sector >> 8) as u8);
+    dsk.set_dma_low(dma as u8);
+    dsk.set_dma_high((dma >> 8) as u8);
+    dsk.disk_operation(CLEAR, mem);
+    dsk.disk_operation(SEEK, mem);
+    if dsk.get_status() != SpinningDiskStatus::Done {
+        return dsk.get_status();
+    }
+    dsk.disk_operation(CLEAR, mem);
+    dsk.disk_operation(op, mem);
+    dsk.get_status()
+}
+
+// `len` bytes that differ from their neighbours, and for each `seed`.
+
+pub fn pattern(seed: u8, len: usize) -> Vec<u8>
+{
+    (0..len).map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed)).collect()
+}
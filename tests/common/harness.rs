@@ -0,0 +1,309 @@
+// Run a guest program end to end on a Machine and capture what it prints.
+//
+// A guest is a boot ROM in high memory, as in main.rs, and optionally disk
+// images.  The images are copied to temporary files, or to RAM disks, so the
+// guest cannot change the originals; their final contents are part of the
+// result.  The machine runs until the CPU halts, executes an illegal
+// instruction, or reaches the cycle limit:
+//
+//   let run = harness::boot().run();
+//   assert_eq!(run.stop, StopReason::Halt);
+//   run.assert_golden("tests/golden/boot.txt");
+//
+// Golden files hold the exact expected output.  Set Z80_UPDATE_GOLDEN to
+// rewrite them from the actual output instead of comparing.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+
+use z80emu::block_cache;
+use z80emu::exec_check::{self, Finding};
+use z80emu::devices::{ByteReader, ByteWriter, DeviceState, TTY};
+use z80emu::{file_backed_block_device, file_backed_spinning_disk};
+use z80emu::file_backed_spinning_disk::Timing;
+use z80emu::machine;
+use z80emu::ram_disk;
+use z80emu::record_replay;
+use z80emu::rng;
+use z80emu::z80::{self, StopReason, Z80};
+
+use super::temp_file;
+
+pub const ROM_SIZE : usize = 128;
+pub const ROM_ADDR : usize = 0x10000 - ROM_SIZE;
+
+// A TTY that records its output and reads its input from a buffer.  Input
+// past the end of the buffer is not available.
+
+pub struct CaptureTty
+{
+    pub output: Vec<u8>,
+    input:      VecDeque<u8>,
+}
+
+pub fn capture_tty(input: &[u8]) -> CaptureTty
+{
+    CaptureTty { output: vec![], input: input.iter().cloned().collect() }
+}
+
+impl ByteReader for CaptureTty {
+    fn poll_nonblocking(&mut self) -> u8 {
+        if self.input.is_empty() { 0x00 } else { 0xFF }
+    }
+
+    fn get_nonblocking(&mut self) -> u8 {
+        self.input.pop_front().unwrap_or(0)
+    }
+}
+
+impl ByteWriter for CaptureTty {
+    fn put_nonblocking(&mut self, c: u8) {
+        self.output.push(c);
+    }
+}
+
+impl DeviceState for CaptureTty {}
+
+impl TTY for CaptureTty {}
+
+pub struct Guest
+{
+    rom:         Vec<u8>,
+    disks:       Vec<(usize, Vec<u8>, u8, u8, u8)>,  // Drive, image, heads, tracks, sectors
+    ram_disks:   Vec<(usize, Vec<u8>, u8, u8, u8)>,  //   as RAM disks
+    block_device: Option<(Vec<u8>, u32)>,   // Image, capacity
+    disk_timing: Option<Timing>,
+    memory:      Vec<(u16, Vec<u8>)>,       // Address, contents
+    input:       Vec<u8>,
+    cycle_limit: u64,
+    block_cache: bool,
+    power_on:    Option<(u64, bool)>,       // Seed, randomize memory
+    exec_check:  bool,
+    record:      Option<String>,            // Recording to write
+    replay:      Option<String>,            // Recording to replay
+}
+
+// The result of a run: why it stopped, the final CPU, and the output.
+
+pub struct Run
+{
+    pub stop:     StopReason,
+    pub cpu:      Z80,
+    pub output:   Vec<u8>,
+    pub findings: Vec<Finding>,     // See Guest::exec_check()
+    pub images:   Vec<(usize, Vec<u8>)>,  // Drive, final image
+    pub block_image: Option<Vec<u8>>,      // Final block device image
+}
+
+// A guest booting from `rom`, without a disk or input, from zeroed registers
+// and memory, limited to a million cycles.
+
+pub fn guest(rom: &[u8]) -> Guest
+{
+    assert!(rom.len() <= ROM_SIZE, "ROM is too large");
+    Guest { rom: rom.to_vec(), disks: vec![], ram_disks: vec![], block_device: None, disk_timing: None,
+            memory: vec![], input: vec![], cycle_limit: 1_000_000, block_cache: false,
+            power_on: None, exec_check: false, record: None, replay: None }
+}
+
+// The machine main.rs runs: rom.bin with a_drive.bin as a single sector disk.
+
+pub fn boot() -> Guest
+{
+    guest(&read("rom.bin")).disk(&read("a_drive.bin"), 1, 1, 1)
+}
+
+impl Guest
+{
+    // Attach `image` as disk A:.
+    pub fn disk(self, image: &[u8], heads: u8, tracks: u8, sectors: u8) -> Guest {
+        self.drive(0, image, heads, tracks, sectors)
+    }
+
+    // Attach `image` as disk `drive`, 0-15 for A: to P:.
+    pub fn drive(mut self, drive: usize, image: &[u8], heads: u8, tracks: u8, sectors: u8) -> Guest {
+        self.disks.push((drive, image.to_vec(), heads, tracks, sectors));
+        self
+    }
+
+    // Attach `image` as the block device, with `capacity` blocks.
+    pub fn block_device(mut self, image: &[u8], capacity: u32) -> Guest {
+        self.block_device = Some((image.to_vec(), capacity));
+        self
+    }
+
+    // Make the operations of all disks take time.
+    pub fn disk_timing(mut self, timing: Timing) -> Guest {
+        self.disk_timing = Some(timing);
+        self
+    }
+
+    // Load `contents` at `addr` in RAM before booting.
+    pub fn memory(mut self, addr: u16, contents: &[u8]) -> Guest {
+        self.memory.push((addr, contents.to_vec()));
+        self
+    }
+
+    // Attach a RAM disk holding `image` as disk `drive`.
+    pub fn ram_drive(mut self, drive: usize, image: &[u8], heads: u8, tracks: u8, sectors: u8) -> Guest {
+        self.ram_disks.push((drive, image.to_vec(), heads, tracks, sectors));
+        self
+    }
+
+    pub fn input(mut self, input: &[u8]) -> Guest {
+        self.input = input.to_vec();
+        self
+    }
+
+    pub fn cycle_limit(mut self, cycle_limit: u64) -> Guest {
+        self.cycle_limit = cycle_limit;
+        self
+    }
+
+    // Run through a block cache, checked against the interpreter.
+    pub fn block_cache(mut self) -> Guest {
+        self.block_cache = true;
+        self
+    }
+
+    // Start from the random power-on state for `seed`, see z80::power_on().
+    pub fn power_on(mut self, seed: u64, randomize_memory: bool) -> Guest {
+        self.power_on = Some((seed, randomize_memory));
+        self
+    }
+
+    // Check for self-modifying code and execution of unwritten memory, with
+    // the ROM, the memory loaded before booting, and the disk's DMA transfers
+    // counting as written.
+    pub fn exec_check(mut self) -> Guest {
+        self.exec_check = true;
+        self
+    }
+
+    // Record the run's input to `path`, see record_replay.
+    pub fn record(mut self, path: &str) -> Guest {
+        self.record = Some(path.to_string());
+        self
+    }
+
+    // Replay the input recorded in `path` instead of using the devices.  The
+    // run panics if the replay diverges from the recording.
+    pub fn replay(mut self, path: &str) -> Guest {
+        self.replay = Some(path.to_string());
+        self
+    }
+
+    pub fn run(&self) -> Run {
+        let mut cpu = match self.power_on {
+            Some((seed, randomize_memory)) => z80::power_on(&mut rng::make(seed), randomize_memory),
+            None => z80::make(0)
+        };
+        cpu.pc = ROM_ADDR as u16;
+        if self.exec_check {
+            let mut chk = exec_check::make();
+            chk.load(ROM_ADDR as u16, ROM_SIZE);
+            for (addr, contents) in &self.memory {
+                chk.load(*addr, contents.len());
+            }
+            cpu.exec_check = Some(Box::new(chk));
+        }
+        for (addr, contents) in &self.memory {
+            cpu.mem[*addr as usize..*addr as usize + contents.len()].copy_from_slice(contents);
+        }
+        cpu.mem[ROM_ADDR..ROM_ADDR + self.rom.len()].copy_from_slice(&self.rom);
+
+        let mut tty = capture_tty(&self.input);
+        let files = self.disks.iter().map(|(_, image, _, _, _)| temp_file::make(image)).collect::<Vec<_>>();
+        let mut disks = self.disks.iter().zip(&files).map(|(&(drive, _, heads, tracks, sectors), file)| {
+            let mut dsk = file_backed_spinning_disk::make(file.path(), heads, tracks, sectors);
+            if let Some(timing) = self.disk_timing {
+                dsk.set_timing(timing);
+            }
+            (drive, dsk)
+        }).collect::<Vec<_>>();
+        let mut ram_disks = self.ram_disks.iter().map(|&(drive, ref image, heads, tracks, sectors)| {
+            (drive, ram_disk::make_from_image(image, heads, tracks as u16, sectors as u16, 128))
+        }).collect::<Vec<_>>();
+        let block_file = self.block_device.as_ref().map(|(image, _)| temp_file::make(image));
+        let mut block_device = self.block_device.as_ref().zip(block_file.as_ref())
+            .map(|((_, capacity), file)| file_backed_block_device::make(file.path(), *capacity));
+
+        let stop = {
+            let mut builder = machine::builder().tty(&mut tty);
+            for &mut (drive, ref mut dsk) in disks.iter_mut() {
+                builder = builder.disk(drive, dsk);
+            }
+            for &mut (drive, ref mut dsk) in ram_disks.iter_mut() {
+                builder = builder.disk(drive, dsk);
+            }
+            if let Some(ref mut dev) = block_device {
+                builder = builder.block_device(dev);
+            }
+            if self.block_cache {
+                builder = builder.block_cache(block_cache::make_differential());
+            }
+            let mut m = builder.build();
+            if let Some(ref path) = self.record {
+                m.record(record_replay::make_recorder(path, &cpu)
+                         .unwrap_or_else(|e| panic!("Could not create `{}`: {}", path, e)));
+            }
+            if let Some(ref path) = self.replay {
+                m.replay(record_replay::make_replayer(path, &cpu)
+                         .unwrap_or_else(|e| panic!("Could not load `{}`: {}", path, e)));
+            }
+            let stop = m.run(&mut cpu, self.cycle_limit).unwrap_or_else(|e| panic!("{}", e));
+            m.finish(&cpu).unwrap_or_else(|e| panic!("{}", e));
+            stop
+        };
+
+        drop(disks);
+        drop(block_device);
+        let block_image = block_file.map(|file| file.contents());
+        let mut images = self.disks.iter().zip(&files).map(|(&(drive, _, _, _, _), file)| (drive, file.contents()))
+            .collect::<Vec<_>>();
+        images.extend(ram_disks.iter().map(|&(drive, ref dsk)| (drive, dsk.contents().to_vec())));
+        let findings = cpu.exec_check.as_ref().map_or(vec![], |chk| chk.findings().to_vec());
+        Run { stop, cpu, output: tty.output, findings, images, block_image }
+    }
+}
+
+impl Run
+{
+    pub fn output(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    // The final contents of the image of disk `drive`.
+    pub fn image(&self, drive: usize) -> &[u8] {
+        &self.images.iter().find(|&&(d, _)| d == drive).expect("No such disk").1
+    }
+
+    // The address of the HALT the CPU stopped at.
+    pub fn halted_at(&self) -> u16 {
+        assert_eq!(self.stop, StopReason::Halt, "The CPU did not halt");
+        self.cpu.pc.wrapping_sub(1)
+    }
+
+    pub fn assert_golden(&self, path: &str) {
+        assert_golden(path, &self.output);
+    }
+}
+
+// Compare `actual` with the golden file at `path`, or rewrite the file if
+// Z80_UPDATE_GOLDEN is set.
+
+pub fn assert_golden(path: &str, actual: &[u8])
+{
+    if env::var_os("Z80_UPDATE_GOLDEN").is_some() {
+        fs::write(path, actual).unwrap_or_else(|e| panic!("Could not write `{}`: {}", path, e));
+        return;
+    }
+    let expected = fs::read(path).unwrap_or_else(|e| panic!("Could not read `{}`: {}", path, e));
+    assert_eq!(String::from_utf8_lossy(actual), String::from_utf8_lossy(&expected), "Output differs from `{}`", path);
+}
+
+fn read(path: &str) -> Vec<u8>
+{
+    fs::read(path).unwrap_or_else(|e| panic!("Could not read `{}`: {}", path, e))
+}
@@ -0,0 +1,193 @@
+// A small JSON reader, enough for test vectors.  Numbers must be integers.
+
+use std::collections::BTreeMap;
+
+#[derive(Debug)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(i64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    // The member `key` of an object; panics if there is none.
+    pub fn get(&self, key: &str) -> &Value {
+        self.try_get(key).unwrap_or_else(|| panic!("No member `{}`", key))
+    }
+
+    pub fn try_get(&self, key: &str) -> Option<&Value> {
+        match *self {
+            Value::Object(ref members) => members.get(key),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> &[Value] {
+        match *self {
+            Value::Array(ref elements) => elements,
+            _ => panic!("Not an array: {:?}", self)
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            Value::String(ref s) => s,
+            _ => panic!("Not a string: {:?}", self)
+        }
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        match *self {
+            Value::Number(n) if n >= 0 => n as u64,
+            _ => panic!("Not an unsigned number: {:?}", self)
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut p = Parser { text: text.as_bytes(), pos: 0 };
+    let value = p.value()?;
+    p.skip_space();
+    if p.pos != p.text.len() {
+        return Err(p.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_space();
+        match self.peek() {
+            Some(b'{') => self.object(),
+            Some(b'[') => self.array(),
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) if self.keyword("null") => Ok(Value::Null),
+            Some(_) if self.keyword("true") => Ok(Value::Bool(true)),
+            Some(_) if self.keyword("false") => Ok(Value::Bool(false)),
+            _ => Err(self.error("expected a value"))
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        let mut members = BTreeMap::new();
+        self.pos += 1;
+        self.skip_space();
+        if self.eat(b'}') {
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_space();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a member name"));
+            }
+            let key = self.string()?;
+            self.skip_space();
+            if !self.eat(b':') {
+                return Err(self.error("expected `:`"));
+            }
+            let value = self.value()?;
+            members.insert(key, value);
+            self.skip_space();
+            if self.eat(b'}') {
+                return Ok(Value::Object(members));
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected `,` or `}`"));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        let mut elements = vec![];
+        self.pos += 1;
+        self.skip_space();
+        if self.eat(b']') {
+            return Ok(Value::Array(elements));
+        }
+        loop {
+            elements.push(self.value()?);
+            self.skip_space();
+            if self.eat(b']') {
+                return Ok(Value::Array(elements));
+            }
+            if !self.eat(b',') {
+                return Err(self.error("expected `,` or `]`"));
+            }
+        }
+    }
+
+    // Strings in test vectors are plain ASCII; escapes are kept as they are
+    // except for \" and \\.
+    fn string(&mut self) -> Result<String, String> {
+        let mut s = String::new();
+        self.pos += 1;
+        loop {
+            match self.peek() {
+                None => { return Err(self.error("unterminated string")); }
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                Some(b'\\') if self.text.get(self.pos + 1).is_some_and(|&c| c == b'"' || c == b'\\') => {
+                    s.push(self.text[self.pos + 1] as char);
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    s.push(c as char);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        self.eat(b'-');
+        while let Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+        let digits = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        digits.parse().map(Value::Number).map_err(|_| self.error("bad number"))
+    }
+
+    fn keyword(&mut self, word: &str) -> bool {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).cloned()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, msg: &str) -> String {
+        format!("JSON error at offset {}: {}", self.pos, msg)
+    }
+}
@@ -0,0 +1,10 @@
+// Code shared by the integration tests.  Not every test uses all of it.
+
+#![allow(dead_code)]
+
+pub mod disk;
+#[cfg(feature = "std")]
+pub mod harness;
+pub mod json;
+#[cfg(feature = "std")]
+pub mod temp_file;
@@ -0,0 +1,90 @@
+// Temporary files and directories, removed when dropped.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct TempFile
+{
+    path: PathBuf,
+}
+
+// A new temporary file holding `contents`, with a name unique to this process
+// and call.
+
+pub fn make(contents: &[u8]) -> TempFile
+{
+    static COUNT : AtomicUsize = AtomicUsize::new(0);
+    let n = COUNT.fetch_add(1, Ordering::SeqCst);
+    let path = env::temp_dir().join(format!("z80emu-test-{}-{}.bin", process::id(), n));
+    fs::write(&path, contents).unwrap_or_else(|e| panic!("Could not write `{}`: {}", path.display(), e));
+    TempFile { path }
+}
+
+impl TempFile
+{
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        fs::read(&self.path).unwrap_or_else(|e| panic!("Could not read `{}`: {}", self.path.display(), e))
+    }
+}
+
+impl Drop for TempFile
+{
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// A file that cannot be opened for writing, and its contents.  A file without
+// write permission will not do, as root can write to it, so this is a sysctl
+// that is read-only even to root.
+
+#[cfg(target_os = "linux")]
+pub const READ_ONLY_FILE : (&str, &[u8]) = ("/proc/sys/kernel/ostype", b"Linux\n");
+
+// A temporary directory, removed with its contents when dropped.
+
+pub struct TempDir
+{
+    path: PathBuf,
+}
+
+pub fn make_dir() -> TempDir
+{
+    static COUNT : AtomicUsize = AtomicUsize::new(0);
+    let n = COUNT.fetch_add(1, Ordering::SeqCst);
+    let path = env::temp_dir().join(format!("z80emu-test-{}-dir-{}", process::id(), n));
+    fs::create_dir(&path).unwrap_or_else(|e| panic!("Could not create `{}`: {}", path.display(), e));
+    TempDir { path }
+}
+
+impl TempDir
+{
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Write `contents` to the file `name` in the directory.
+    pub fn write(&self, name: &str, contents: &[u8]) {
+        let path = self.path.join(name);
+        fs::write(&path, contents).unwrap_or_else(|e| panic!("Could not write `{}`: {}", path.display(), e));
+    }
+
+    pub fn read(&self, name: &str) -> Vec<u8> {
+        let path = self.path.join(name);
+        fs::read(&path).unwrap_or_else(|e| panic!("Could not read `{}`: {}", path.display(), e))
+    }
+}
+
+impl Drop for TempDir
+{
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,72 @@
+// Tests of lcov reports and source maps.  Execution and branch counting are
+// tested with the CPU, in cpu.rs.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use common::{harness, temp_file};
+use z80emu::coverage;
+use z80emu::z80;
+
+// LD A,0; AND A; JP Z,7; HALT; JP NZ,0Bh; HALT
+
+const CODE : &[u8] = &[0x3E, 0x00, 0xA7, 0xCA, 0x07, 0x00, 0x76, 0xC2, 0x0B, 0x00, 0x76];
+
+// The first two instructions share a line, the HALT at 0006h is never
+// executed, and the final HALT is in another file.
+
+const SOURCE_MAP : &str = "0000\t10\tprog.rs\n\
+                           0002\t10\tprog.rs\n\
+                           0003\t11\tprog.rs\n\
+                           0006\t12\tprog.rs\n\
+                           0007\t13\tprog.rs\n\
+                           000A\t4\tlib.rs\n";
+
+#[test]
+fn lcov_report()
+{
+    let mut cpu = z80::make(0);
+    cpu.mem[..CODE.len()].copy_from_slice(CODE);
+    cpu.coverage = Some(Box::new(coverage::make()));
+    z80::run(&mut cpu, 1000);
+
+    let file = temp_file::make(SOURCE_MAP.as_bytes());
+    let mut map = coverage::make_source_map();
+    map.load(file.path()).unwrap();
+    let mut out = vec![];
+    cpu.coverage.as_ref().unwrap().write_lcov(&mut out, &map).unwrap();
+    harness::assert_golden("tests/golden/coverage.lcov", &out);
+}
+
+#[test]
+fn later_source_map_entries_replace_earlier_ones()
+{
+    let mut cpu = z80::make(0);
+    cpu.mem[..CODE.len()].copy_from_slice(CODE);
+    cpu.coverage = Some(Box::new(coverage::make()));
+    z80::run(&mut cpu, 1000);
+
+    let first = temp_file::make(b"0000\t1\ta.rs\n0006\t2\ta.rs\n");
+    let second = temp_file::make(b"0006\t7\tb.rs\n");
+    let mut map = coverage::make_source_map();
+    map.load(first.path()).unwrap();
+    map.load(second.path()).unwrap();
+    let mut out = vec![];
+    cpu.coverage.as_ref().unwrap().write_lcov(&mut out, &map).unwrap();
+    assert_eq!(String::from_utf8(out).unwrap(),
+               "TN:\nSF:a.rs\nDA:1,1\nLF:1\nLH:1\nend_of_record\nSF:b.rs\nDA:7,0\nLF:1\nLH:0\nend_of_record\n");
+}
+
+#[test]
+fn bad_source_map()
+{
+    for contents in &["0000\t1\n", "XYZ\t1\tprog.rs\n", "0000\tone\tprog.rs\n"] {
+        let file = temp_file::make(contents.as_bytes());
+        let err = coverage::make_source_map().load(file.path()).unwrap_err();
+        assert!(err.to_string().contains("Bad source map line"), "{}", err);
+    }
+    assert!(coverage::make_source_map().load("no-such-source-map").is_err());
+}
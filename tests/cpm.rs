@@ -0,0 +1,70 @@
+// Tests of the CP/M runner.  The instruction exercisers are not in the repo;
+// to run them, use examples/cpm.rs.
+
+extern crate z80emu;
+
+use z80emu::cpm::{self, Exit};
+use z80emu::devices::ByteWriter;
+
+struct Capture(Vec<u8>);
+
+impl ByteWriter for Capture {
+    fn put_nonblocking(&mut self, c: u8) {
+        self.0.push(c);
+    }
+}
+
+fn run(program: &[u8], cycle_limit: u64) -> (Exit, String)
+{
+    let mut cpu = cpm::make(program);
+    let mut out = Capture(vec![]);
+    let exit = cpm::run(&mut cpu, cycle_limit, &mut out);
+    (exit, String::from_utf8_lossy(&out.0).into_owned())
+}
+
+#[test]
+fn console_output()
+{
+    let program = [
+        0x11, 0x10, 0x01,       // 0100  LD DE,0110h
+        0x0E, 0x09,             // 0103  LD C,9
+        0xCD, 0x05, 0x00,       // 0105  CALL 5
+        0x1E, b'!',             // 0108  LD E,'!'
+        0x0E, 0x02,             // 010A  LD C,2
+        0xCD, 0x05, 0x00,       // 010C  CALL 5
+        0xC9,                   // 010F  RET, to 0000h
+    ];
+    let mut program = program.to_vec();
+    program.extend_from_slice(b"Hello, CP/M$");
+    assert_eq!(run(&program, 10000), (Exit::WarmBoot, "Hello, CP/M!".to_string()));
+}
+
+#[test]
+fn unsupported_bdos_function()
+{
+    // LD C,1; CALL 5
+    assert_eq!(run(&[0x0E, 0x01, 0xCD, 0x05, 0x00], 10000).0, Exit::UnsupportedBdos(1));
+}
+
+#[test]
+fn cycle_limit()
+{
+    // JP 0100h
+    assert_eq!(run(&[0xC3, 0x00, 0x01], 100000).0, Exit::CycleLimit);
+}
+
+#[test]
+fn unterminated_string()
+{
+    // Nothing in memory is a '$', so the output stops after 64 KB.
+    let program = [
+        0x11, 0x00, 0x02,       // 0100  LD DE,0200h
+        0x0E, 0x09,             // 0103  LD C,9
+        0xCD, 0x05, 0x00,       // 0105  CALL 5
+        0xC9,                   // 0108  RET, to 0000h
+    ];
+    let mut cpu = cpm::make(&program);
+    let mut out = Capture(vec![]);
+    assert_eq!(cpm::run(&mut cpu, 10000, &mut out), Exit::WarmBoot);
+    assert_eq!(out.0.len(), 65536);
+}
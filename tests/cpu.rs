@@ -0,0 +1,297 @@
+// Tests of the CPU core.  These use only what is available without the `std`
+// feature, and are run both with and without it:
+//
+//   cargo test
+//   cargo test --no-default-features
+
+extern crate z80emu;
+
+use z80emu::bus_trace::{self, Access, Kind};
+use z80emu::coverage;
+use z80emu::rng;
+use z80emu::z80::{self, StopReason, Z80};
+
+// Make a CPU with `code` at address 0 and run it until it stops.
+
+fn run_code(code: &[u8]) -> Z80
+{
+    let mut cpu = z80::make(0);
+    cpu.mem[..code.len()].copy_from_slice(code);
+    z80::run(&mut cpu, 1000);
+    cpu
+}
+
+#[test]
+fn add_and_halt()
+{
+    // LD A,5; ADD A,A; HALT
+    let cpu = run_code(&[0x37, 0x05, 0x87, 0x76]);
+    assert_eq!(cpu.stop_reason, StopReason::Halt);
+    assert_eq!(cpu.a, 10);
+    assert_eq!(cpu.cycles, 7 + 4 + 4);
+}
+
+#[test]
+fn add_sets_flags()
+{
+    // LD A,80h; ADD A,A; HALT
+    let cpu = run_code(&[0x37, 0x80, 0x87, 0x76]);
+    assert_eq!(cpu.a, 0);
+    assert_eq!(cpu.f & 0x41, 0x41);     // Zero and carry
+}
+
+#[test]
+fn add_sets_half_carry_and_overflow()
+{
+    // LD A,7Fh; ADC A,(HL) with (HL) = 01h; HALT
+    let mut cpu = z80::make(0);
+    cpu.mem[..4].copy_from_slice(&[0x37, 0x7F, 0x8E, 0x76]);
+    cpu.h = 0x10;
+    cpu.mem[0x1000] = 0x01;
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.a, 0x80);
+    assert_eq!(cpu.f & 0xD7, 0x94);     // Sign, half carry, overflow
+}
+
+#[test]
+fn and_sets_parity()
+{
+    // LD A,0Fh; AND 03h; HALT
+    let cpu = run_code(&[0x37, 0x0F, 0xE6, 0x03, 0x76]);
+    assert_eq!(cpu.a, 0x03);
+    assert_eq!(cpu.f & 0xD7, 0x14);     // Half carry, parity even
+}
+
+#[test]
+fn add_hl_sets_half_carry()
+{
+    // ADD HL,BC; HALT, with the zero flag set
+    let mut cpu = z80::make(0);
+    cpu.mem[..2].copy_from_slice(&[0x09, 0x76]);
+    cpu.h = 0x0F;
+    cpu.l = 0xFF;
+    cpu.c = 0x01;
+    cpu.f = 0x40;
+    z80::run(&mut cpu, 1000);
+    assert_eq!((cpu.h, cpu.l), (0x10, 0x00));
+    assert_eq!(cpu.f & 0xD7, 0x50);     // Zero kept, half carry
+}
+
+#[test]
+fn adc_hl_sets_half_carry_and_overflow()
+{
+    // ADC HL,BC; HALT
+    let mut cpu = z80::make(0);
+    cpu.mem[..3].copy_from_slice(&[0xED, 0x4A, 0x76]);
+    cpu.h = 0x7F;
+    cpu.l = 0xFF;
+    cpu.c = 0x01;
+    z80::run(&mut cpu, 1000);
+    assert_eq!((cpu.h, cpu.l), (0x80, 0x00));
+    assert_eq!(cpu.f & 0xD7, 0x94);     // Sign, half carry, overflow
+}
+
+#[test]
+fn conditional_jump()
+{
+    // LD A,0; ADD A,A; JP Z,8; HALT; HALT; LD A,1; HALT
+    let cpu = run_code(&[0x37, 0x00, 0x87, 0xCA, 0x08, 0x00, 0x76, 0x76, 0x37, 0x01, 0x76]);
+    assert_eq!(cpu.stop_reason, StopReason::Halt);
+    assert_eq!(cpu.a, 1);
+}
+
+#[test]
+fn flags_keep_the_unused_bits()
+{
+    // LD A,1; ADD A,A; HALT, with the unused flag bits 3 and 5 set and clear
+    for &f in &[0x28, 0x00] {
+        let mut cpu = z80::make(0);
+        cpu.mem[..4].copy_from_slice(&[0x37, 0x01, 0x87, 0x76]);
+        cpu.f = f;
+        z80::run(&mut cpu, 1000);
+        assert_eq!(cpu.a, 2);
+        assert_eq!(cpu.f & 0x28, f);
+    }
+}
+
+#[test]
+fn all_conditional_jumps()
+{
+    // JP cc,1234h for each condition, with its flag set and clear
+    let conditions = [(0xC2, 0x40, false), (0xCA, 0x40, true), (0xD2, 0x01, false), (0xDA, 0x01, true),
+                      (0xE2, 0x04, false), (0xEA, 0x04, true), (0xF2, 0x80, false), (0xFA, 0x80, true)];
+    for &(opcode, flag, jump_if_set) in &conditions {
+        for &set in &[true, false] {
+            let mut cpu = z80::make(0);
+            cpu.mem[..3].copy_from_slice(&[opcode, 0x34, 0x12]);
+            cpu.f = if set { flag } else { !flag };
+            z80::step(&mut cpu);
+            let expected = if set == jump_if_set { 0x1234 } else { 3 };
+            assert_eq!(cpu.pc, expected, "Opcode {:02X}h with F={:02X}h", opcode, cpu.f);
+            assert_eq!(cpu.cycles, 10);
+        }
+    }
+}
+
+#[test]
+fn out_stops_the_cpu()
+{
+    // LD A,41h; OUT (7),A
+    let cpu = run_code(&[0x37, 0x41, 0xD3, 0x07]);
+    assert_eq!(cpu.stop_reason, StopReason::Out);
+    assert_eq!(cpu.port_addr, 7);
+    assert_eq!(cpu.a, 0x41);
+    assert_eq!(cpu.pc, 4);
+}
+
+#[test]
+fn timeslice_expires()
+{
+    // The timeslice counts down before each instruction, so a timeslice of n
+    // executes n-1 instructions.
+    let mut cpu = z80::make(0);
+    z80::run(&mut cpu, 11);
+    assert_eq!(cpu.stop_reason, StopReason::Poll);
+    assert_eq!(cpu.pc, 10);
+    assert_eq!(cpu.cycles, 40);
+    assert_eq!(cpu.r, 10);
+}
+
+#[test]
+fn interrupt_mode_1()
+{
+    let mut cpu = z80::make(0x1234);
+    cpu.sp = 0x8000;
+    assert!(!z80::interrupt(&mut cpu, 0xFF));
+
+    cpu.iff1 = true;
+    cpu.im = 1;
+    assert!(z80::interrupt(&mut cpu, 0xFF));
+    assert_eq!(cpu.pc, 0x0038);
+    assert_eq!(cpu.sp, 0x7FFE);
+    assert_eq!(cpu.mem[0x7FFE], 0x34);
+    assert_eq!(cpu.mem[0x7FFF], 0x12);
+    assert!(!cpu.iff1);
+}
+
+#[test]
+fn bus_trace_records_interrupts()
+{
+    // Mode 2 pushes pc, high byte first, and reads the vector at I*256+data
+    let mut cpu = z80::make(0x1234);
+    cpu.sp = 0x8000;
+    cpu.iff1 = true;
+    cpu.im = 2;
+    cpu.i = 0x40;
+    cpu.mem[0x40FE..0x4100].copy_from_slice(&[0x00, 0x20]);
+    cpu.bus_trace = Some(Box::new(bus_trace::make()));
+    assert!(z80::interrupt(&mut cpu, 0xFE));
+    assert_eq!(cpu.pc, 0x2000);
+    assert_eq!(cpu.bus_trace.as_ref().unwrap().accesses(), &[
+        Access { kind: Kind::Write, addr: 0x7FFF, data: 0x12 },
+        Access { kind: Kind::Write, addr: 0x7FFE, data: 0x34 },
+        Access { kind: Kind::Read, addr: 0x40FE, data: 0x00 },
+        Access { kind: Kind::Read, addr: 0x40FF, data: 0x20 },
+    ]);
+}
+
+#[test]
+fn coverage_counts_execution()
+{
+    let mut cpu = z80::make(0);
+    cpu.mem[..4].copy_from_slice(&[0x37, 0x00, 0x87, 0x76]);
+    cpu.coverage = Some(Box::new(coverage::make()));
+    z80::run(&mut cpu, 1000);
+    let cov = cpu.coverage.as_ref().unwrap();
+    assert_eq!(cov.hits(0), 1);
+    assert_eq!(cov.hits(1), 0);
+    assert_eq!(cov.hits(3), 1);
+}
+
+#[test]
+fn coverage_counts_branches()
+{
+    // LD A,0; AND A; JP Z,7; HALT; JP NZ,0Bh; HALT
+    let mut cpu = z80::make(0);
+    cpu.mem[..11].copy_from_slice(&[0x3E, 0x00, 0xA7, 0xCA, 0x07, 0x00, 0x76, 0xC2, 0x0B, 0x00, 0x76]);
+    cpu.coverage = Some(Box::new(coverage::make()));
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.pc, 11);
+    let cov = cpu.coverage.as_ref().unwrap();
+    assert_eq!(cov.branch_counts(3), Some((1, 0)));
+    assert_eq!(cov.branch_counts(7), Some((0, 1)));
+    assert_eq!(cov.branch_counts(0), None);
+    assert_eq!(cov.hits(6), 0);
+}
+
+#[test]
+fn power_on_is_reproducible()
+{
+    let a = z80::power_on(&mut rng::make(42), true);
+    let b = z80::power_on(&mut rng::make(42), true);
+    let c = z80::power_on(&mut rng::make(43), true);
+    let regs = |cpu: &Z80| (cpu.sp, cpu.ix, cpu.iy, cpu.a, cpu.f, cpu.b, cpu.c, cpu.h_alt, cpu.l_alt);
+    assert_eq!(regs(&a), regs(&b));
+    assert!(a.mem[..] == b.mem[..]);
+    assert_ne!(regs(&a), regs(&c));
+    assert!(a.mem[..] != c.mem[..]);
+
+    let d = z80::power_on(&mut rng::make(42), false);
+    assert_eq!(regs(&a), regs(&d));
+    assert!(d.mem.iter().all(|&b| b == 0));
+}
+
+#[test]
+fn power_on_clears_control_registers()
+{
+    let cpu = z80::power_on(&mut rng::make(7), true);
+    assert_eq!((cpu.pc, cpu.i, cpu.r, cpu.im, cpu.iff1, cpu.iff2), (0, 0, 0, 0, false, false));
+    assert_eq!(cpu.cycles, 0);
+}
+
+#[test]
+fn reset_keeps_registers_and_memory()
+{
+    // LD A,5; IM 1; EI; HALT
+    let mut cpu = run_code(&[0x37, 0x05, 0xED, 0x56, 0xFB, 0x76]);
+    assert_eq!(cpu.stop_reason, StopReason::Halt);
+    z80::reset(&mut cpu);
+    assert_eq!((cpu.pc, cpu.r, cpu.im, cpu.iff1, cpu.iff2), (0, 0, 0, false, false));
+    assert_eq!(cpu.a, 5);
+    assert_eq!(cpu.mem[0], 0x37);
+    assert!(cpu.cycles > 0);
+}
+
+#[test]
+fn trap_stops_and_resumes()
+{
+    // LD A,1; ED FE 2A; LD A,2; HALT
+    let code = [0x3E, 0x01, 0xED, 0xFE, 0x2A, 0x3E, 0x02, 0x76];
+    let cpu = run_code(&code);
+    assert_eq!(cpu.stop_reason, StopReason::Illegal);
+
+    let mut cpu = z80::make(0);
+    cpu.mem[..code.len()].copy_from_slice(&code);
+    cpu.trap = Some(0xFE);
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::Trap(0x2A));
+    assert_eq!((cpu.pc, cpu.a, cpu.cycles), (5, 1, 7 + 11));
+
+    // The host services the trap and resumes
+    cpu.a = 0x55;
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::Halt);
+    assert_eq!(cpu.a, 2);
+}
+
+#[test]
+fn implemented_opcodes_are_not_traps()
+{
+    // IM 1; HALT
+    let mut cpu = z80::make(0);
+    cpu.mem[..3].copy_from_slice(&[0xED, 0x56, 0x76]);
+    cpu.trap = Some(0x56);
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::Halt);
+    assert_eq!(cpu.im, 1);
+}
@@ -0,0 +1,370 @@
+// Tests of the file backed spinning disk, driven through the SpinningDisk
+// interface as the machine drives it.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use std::fs;
+
+use common::disk::{pattern, transfer, CLEAR, READ, READ_MULTI, SEEK, WRITE, WRITE_MULTI};
+use common::temp_file;
+use z80emu::devices::{SpinningDisk, SpinningDiskStatus};
+use z80emu::file_backed_spinning_disk::{self, Timing};
+
+#[test]
+fn write_then_read_round_trips()
+{
+    let file = temp_file::make(&[0xE5; 4 * 128]);
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 1, 1, 4);
+    let mut mem = vec![0; 65536];
+    mem[0x1000..0x1080].copy_from_slice(&pattern(1, 128));
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 2), 0x1000, &mut mem), SpinningDiskStatus::Done);
+
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 2), 0x2000, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&mem[0x2000..0x2080], &pattern(1, 128)[..]);
+
+    // Only the sector written has changed
+    let contents = file.contents();
+    assert_eq!(contents.len(), 4 * 128);
+    assert!(contents[..256].iter().chain(&contents[384..]).all(|&b| b == 0xE5));
+    assert_eq!(&contents[256..384], &pattern(1, 128)[..]);
+}
+
+#[test]
+fn write_wraps_around_memory()
+{
+    let file = temp_file::make(&[0; 128]);
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 1, 1, 1);
+    let mut mem = vec![0; 65536];
+    let data = pattern(2, 128);
+    mem[0xFFC0..].copy_from_slice(&data[..64]);
+    mem[..64].copy_from_slice(&data[64..]);
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 0), 0xFFC0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(file.contents(), data);
+
+    let mut mem = vec![0; 65536];
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 0), 0xFFC0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&mem[0xFFC0..], &data[..64]);
+    assert_eq!(&mem[..64], &data[64..]);
+}
+
+#[test]
+fn write_past_the_end_extends_the_file()
+{
+    let file = temp_file::make(&[0xAA; 100]);
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 1, 1, 4);
+    let mut mem = vec![0; 65536];
+    mem[..128].copy_from_slice(&pattern(3, 128));
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 3), 0, &mut mem), SpinningDiskStatus::Done);
+
+    let contents = file.contents();
+    assert_eq!(contents.len(), 4 * 128);
+    assert!(contents[..100].iter().all(|&b| b == 0xAA));
+    assert!(contents[100..384].iter().all(|&b| b == 0));
+    assert_eq!(&contents[384..], &pattern(3, 128)[..]);
+
+    // The gap reads as zeroes, including the part of the sector that was past
+    // the end of the original file
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 0), 0x100, &mut mem), SpinningDiskStatus::Done);
+    assert!(mem[0x100..0x164].iter().all(|&b| b == 0xAA));
+    assert!(mem[0x164..0x180].iter().all(|&b| b == 0));
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 3), 0x100, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&mem[0x100..0x180], &pattern(3, 128)[..]);
+}
+
+#[test]
+fn read_past_the_end_is_zeroes()
+{
+    let file = temp_file::make(&[]);
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 1, 1, 2);
+    let mut mem = vec![0xFF; 65536];
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 1), 0, &mut mem), SpinningDiskStatus::Done);
+    assert!(mem[..128].iter().all(|&b| b == 0));
+    assert_eq!(file.contents().len(), 0);
+}
+
+#[test]
+fn write_requires_ready()
+{
+    let file = temp_file::make(&[0; 128]);
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 1, 1, 1);
+    let mut mem = vec![0x55; 65536];
+    dsk.disk_operation(WRITE, &mut mem);
+    assert_eq!(dsk.get_status(), SpinningDiskStatus::OpError);
+    assert_eq!(file.contents(), vec![0; 128]);
+}
+
+// An image in which each sector is filled with its index in the file.
+
+fn numbered_image(sectors: usize, sector_size: usize) -> Vec<u8>
+{
+    (0..sectors).flat_map(|n| vec![n as u8; sector_size]).collect()
+}
+
+#[test]
+fn geometry_maps_head_track_and_sector()
+{
+    let (heads, tracks, sectors) = (2, 3, 4);
+    let file = temp_file::make(&numbered_image(2 * 3 * 4, 128));
+    let mut dsk = file_backed_spinning_disk::make(file.path(), heads, tracks, sectors);
+    let mut mem = vec![0; 65536];
+    for head in 0..heads {
+        for track in 0..tracks {
+            for sector in 0..sectors {
+                let chs = (head, track as u16, sector as u16);
+                assert_eq!(transfer(&mut dsk, READ, chs, 0, &mut mem), SpinningDiskStatus::Done);
+                let n = (head * tracks + track) * sectors + sector;
+                assert!(mem[..128].iter().all(|&b| b == n), "Head {} track {} sector {}", head, track, sector);
+            }
+        }
+    }
+}
+
+#[test]
+fn out_of_range_parameters_fail_the_seek()
+{
+    let file = temp_file::make(&[]);
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 2, 3, 4);
+    let mut mem = vec![0; 65536];
+    for &chs in &[(2, 0, 0), (0, 3, 0), (0, 0, 4)] {
+        assert_eq!(transfer(&mut dsk, READ, chs, 0, &mut mem), SpinningDiskStatus::SeekError);
+    }
+}
+
+#[test]
+fn sector_sizes()
+{
+    for &size in &file_backed_spinning_disk::SECTOR_SIZES {
+        let file = temp_file::make(&numbered_image(2 * 2 * 2, size));
+        let mut dsk = file_backed_spinning_disk::make_with_sector_size(file.path(), 2, 2, 2, size);
+        let mut mem = vec![0; 65536];
+
+        // Sector 6 is head 1, track 1, sector 0; read it across the top of memory
+        let dma = (0x10000 - size / 2) as u16;
+        assert_eq!(transfer(&mut dsk, READ, (1, 1, 0), dma, &mut mem), SpinningDiskStatus::Done);
+        assert!(mem[dma as usize..].iter().chain(&mem[..size / 2]).all(|&b| b == 6), "Size {}", size);
+        assert_eq!(mem[size / 2], 0);
+
+        // Write it to sector 1 and read it back
+        assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 1), dma, &mut mem), SpinningDiskStatus::Done);
+        let contents = file.contents();
+        assert_eq!(contents.len(), 8 * size);
+        assert!(contents[size..2 * size].iter().all(|&b| b == 6));
+        assert!(contents[2 * size..3 * size].iter().all(|&b| b == 2));
+    }
+}
+
+#[test]
+#[should_panic(expected = "Bad sector size")]
+fn bad_sector_size()
+{
+    let file = temp_file::make(&[]);
+    file_backed_spinning_disk::make_with_sector_size(file.path(), 1, 1, 1, 100);
+}
+
+#[test]
+#[should_panic(expected = "Bad geometry")]
+fn empty_geometry()
+{
+    let file = temp_file::make(&[]);
+    file_backed_spinning_disk::make(file.path(), 1, 0, 1);
+}
+
+// A 10 MB hard disk, with more than 256 tracks and sectors per track.
+
+#[test]
+fn hard_disk_has_16_bit_tracks_and_sectors()
+{
+    let (tracks, sectors) = (300, 260);
+    let file = temp_file::make(&[]);
+    let mut dsk = file_backed_spinning_disk::make_hard_disk(file.path(), 1, tracks, sectors, 128);
+    let mut mem = vec![0; 65536];
+    mem[..128].copy_from_slice(&pattern(3, 128));
+    mem[128..256].copy_from_slice(&pattern(4, 128));
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 299, 259), 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0x100, 1), 128, &mut mem), SpinningDiskStatus::Done);
+
+    let contents = file.contents();
+    assert_eq!(contents.len(), tracks as usize * sectors as usize * 128);
+    assert_eq!(&contents[contents.len() - 128..], &pattern(3, 128)[..]);
+    let offset = (0x100 * sectors as usize + 1) * 128;
+    assert_eq!(&contents[offset..offset + 128], &pattern(4, 128)[..]);
+    assert!(contents[128..256].iter().all(|&b| b == 0), "Track 0 was written");
+
+    // Setting only the low byte keeps the high byte
+    assert_eq!(transfer(&mut dsk, READ, (0, 0x100, 1), 0x1000, &mut mem), SpinningDiskStatus::Done);
+    dsk.set_sector(1);
+    dsk.disk_operation(CLEAR, &mut mem);
+    dsk.disk_operation(SEEK, &mut mem);
+    dsk.disk_operation(CLEAR, &mut mem);
+    dsk.disk_operation(READ, &mut mem);
+    assert_eq!(&mem[0x1000..0x1080], &pattern(4, 128)[..]);
+
+    for &chs in &[(0, tracks, 0), (0, 0, sectors), (1, 0, 0)] {
+        assert_eq!(transfer(&mut dsk, READ, chs, 0, &mut mem), SpinningDiskStatus::SeekError);
+    }
+}
+
+#[test]
+fn read_multi_crosses_tracks_and_heads()
+{
+    let file = temp_file::make(&numbered_image(2 * 3 * 4, 128));
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 2, 3, 4);
+    let mut mem = vec![0; 65536];
+
+    // Head 0, track 2, sector 3 is sector 11; read 11 to 15
+    dsk.set_sector_count(5);
+    assert_eq!(transfer(&mut dsk, READ_MULTI, (0, 2, 3), 0x1000, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(dsk.written(), Some((0x1000, 5 * 128)));
+    for n in 0..5 {
+        let sector = &mem[0x1000 + n * 128..0x1000 + (n + 1) * 128];
+        assert!(sector.iter().all(|&b| b == 11 + n as u8), "Sector {}", n);
+    }
+    assert_eq!(mem[0x1000 + 5 * 128], 0);
+}
+
+#[test]
+fn write_multi()
+{
+    let file = temp_file::make(&[]);
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 2, 1, 4);
+    let mut mem = vec![0; 65536];
+    for n in 0..3 {
+        mem[0x2000 + n * 128..0x2000 + (n + 1) * 128].copy_from_slice(&pattern(n as u8, 128));
+    }
+    dsk.set_sector_count(3);
+    assert_eq!(transfer(&mut dsk, WRITE_MULTI, (0, 0, 3), 0x2000, &mut mem), SpinningDiskStatus::Done);
+    let contents = file.contents();
+    assert_eq!(contents.len(), 6 * 128);
+    assert!(contents[..3 * 128].iter().all(|&b| b == 0));
+    assert_eq!(&contents[3 * 128..], &mem[0x2000..0x2000 + 3 * 128]);
+}
+
+#[test]
+fn multi_transfers_must_fit_the_disk()
+{
+    let file = temp_file::make(&numbered_image(2 * 3 * 4, 128));
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 2, 3, 4);
+    let mut mem = vec![0; 65536];
+    dsk.set_sector_count(3);
+    assert_eq!(transfer(&mut dsk, READ_MULTI, (1, 2, 1), 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(transfer(&mut dsk, READ_MULTI, (1, 2, 2), 0, &mut mem), SpinningDiskStatus::SeekError);
+    assert_eq!(transfer(&mut dsk, WRITE_MULTI, (1, 2, 2), 0, &mut mem), SpinningDiskStatus::SeekError);
+    assert_eq!(transfer(&mut dsk, READ, (1, 2, 2), 0, &mut mem), SpinningDiskStatus::Done);
+
+    // A count of 0 is 256 sectors
+    dsk.set_sector_count(0);
+    assert_eq!(transfer(&mut dsk, READ_MULTI, (0, 0, 0), 0, &mut mem), SpinningDiskStatus::SeekError);
+    assert_eq!(file.contents(), numbered_image(2 * 3 * 4, 128));
+}
+
+#[test]
+fn timed_operations_are_busy_until_they_complete()
+{
+    let file = temp_file::make(&numbered_image(10 * 4, 128));
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 1, 10, 4);
+    dsk.set_timing(Timing { step: 100, rotation: 1000, transfer: 50, interrupt: Some(0xCF) });
+    let mut mem = vec![0; 65536];
+
+    // Stepping 3 tracks takes 300 cycles, and commands are ignored meanwhile
+    dsk.clock(0);
+    dsk.set_track(3);
+    dsk.set_sector(2);
+    dsk.disk_operation(CLEAR, &mut mem);
+    dsk.disk_operation(SEEK, &mut mem);
+    dsk.disk_operation(CLEAR, &mut mem);
+    dsk.clock(299);
+    assert_eq!(dsk.get_status(), SpinningDiskStatus::Busy);
+    assert_eq!(dsk.interrupt(), None);
+    dsk.clock(300);
+    assert_eq!(dsk.interrupt(), Some(0xCF));
+    assert_eq!(dsk.get_status(), SpinningDiskStatus::Done);
+    assert_eq!(dsk.interrupt(), None);
+
+    // Sector 2 passes the heads at 500 cycles into each revolution, and then
+    // takes 50 cycles to read
+    dsk.disk_operation(CLEAR, &mut mem);
+    dsk.disk_operation(READ, &mut mem);
+    assert!(mem[..128].iter().all(|&b| b == 3 * 4 + 2));
+    dsk.clock(549);
+    assert_eq!(dsk.get_status(), SpinningDiskStatus::Busy);
+    dsk.clock(550);
+    assert_eq!(dsk.interrupt(), Some(0xCF));
+
+    // Another command clears the interrupt, and the sector comes around again
+    // in the next revolution
+    dsk.clock(1600);
+    dsk.disk_operation(CLEAR, &mut mem);
+    assert_eq!(dsk.interrupt(), None);
+    dsk.disk_operation(READ, &mut mem);
+    dsk.clock(2549);
+    assert_eq!(dsk.get_status(), SpinningDiskStatus::Busy);
+    dsk.clock(2550);
+    assert_eq!(dsk.get_status(), SpinningDiskStatus::Done);
+
+    // Errors are reported at once
+    dsk.set_track(10);
+    dsk.disk_operation(CLEAR, &mut mem);
+    dsk.disk_operation(SEEK, &mut mem);
+    assert_eq!(dsk.get_status(), SpinningDiskStatus::SeekError);
+    assert_eq!(dsk.interrupt(), None);
+}
+
+#[test]
+fn write_protected_disk()
+{
+    let file = temp_file::make(&numbered_image(4, 128));
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 1, 1, 4);
+    let mut mem = vec![0; 65536];
+    dsk.set_write_protect(true);
+    assert!(dsk.is_write_protected());
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 1), 0, &mut mem), SpinningDiskStatus::WriteProtected);
+    dsk.set_sector_count(2);
+    assert_eq!(transfer(&mut dsk, WRITE_MULTI, (0, 0, 1), 0, &mut mem), SpinningDiskStatus::WriteProtected);
+    assert_eq!(SpinningDiskStatus::WriteProtected as u8, 0xFB);
+    assert_eq!(file.contents(), numbered_image(4, 128));
+
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 3), 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(mem[0], 3);
+
+    dsk.set_write_protect(false);
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 1), 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(file.contents()[128], 3);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn read_only_file_is_write_protected()
+{
+    let (path, contents) = temp_file::READ_ONLY_FILE;
+    let mut dsk = file_backed_spinning_disk::make(path, 1, 1, 2);
+    let mut mem = vec![0xFF; 65536];
+    assert!(dsk.is_read_only());
+    dsk.set_write_protect(false);
+    assert!(dsk.is_write_protected());
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 0), 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&mem[..contents.len()], contents);
+    assert!(mem[contents.len()..128].iter().all(|&b| b == 0));
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 1), 0, &mut mem), SpinningDiskStatus::WriteProtected);
+    assert_eq!(fs::read(path).unwrap(), contents);
+}
+
+#[test]
+fn overlay_keeps_the_image_unmodified()
+{
+    let file = temp_file::make(&numbered_image(4, 128));
+    let mut dsk = file_backed_spinning_disk::make(file.path(), 1, 1, 4);
+    let mut mem = vec![0; 65536];
+    dsk.image().add_overlay();
+    mem[..128].copy_from_slice(&pattern(5, 128));
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 1), 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 1), 0x1000, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&mem[0x1000..0x1080], &pattern(5, 128)[..]);
+    assert_eq!(file.contents(), numbered_image(4, 128));
+
+    dsk.image().commit().unwrap();
+    assert_eq!(&file.contents()[128..256], &pattern(5, 128)[..]);
+}
@@ -0,0 +1,129 @@
+// Tests of disk images and their copy-on-write overlays.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use std::fs;
+use std::io;
+
+use common::temp_file;
+use z80emu::disk_image;
+
+fn read(image: &mut disk_image::DiskImage, offset: u64, len: usize) -> Vec<u8>
+{
+    let mut buf = vec![0xFF; len];
+    image.read_at(offset, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn reads_past_the_end_are_zeroes()
+{
+    let file = temp_file::make(&[1, 2, 3]);
+    let mut image = disk_image::open(file.path()).unwrap();
+    assert_eq!(read(&mut image, 1, 4), vec![2, 3, 0, 0]);
+    assert_eq!(read(&mut image, 1000, 2), vec![0, 0]);
+}
+
+#[test]
+fn overlay_keeps_the_file_unmodified()
+{
+    let contents = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+    let file = temp_file::make(&contents);
+    let mut image = disk_image::open(file.path()).unwrap();
+    image.add_overlay();
+    assert!(!image.is_modified());
+
+    // Unaligned, across chunks, and past the end of the file
+    image.write_at(100, &[0xAA; 200]).unwrap();
+    image.write_at(990, &[0xBB; 20]).unwrap();
+    assert!(image.is_modified());
+    assert_eq!(file.contents(), contents);
+
+    let mut expected = contents.clone();
+    expected[100..300].copy_from_slice(&[0xAA; 200]);
+    expected[990..].copy_from_slice(&[0xBB; 10]);
+    expected.extend_from_slice(&[0xBB; 10]);
+    assert_eq!(read(&mut image, 0, 1010), expected);
+    assert_eq!(read(&mut image, 250, 100), expected[250..350].to_vec());
+
+    image.discard();
+    assert!(!image.is_modified());
+    assert_eq!(read(&mut image, 0, 1010)[..1000], contents[..]);
+}
+
+#[test]
+fn overlay_commits_to_the_file()
+{
+    let file = temp_file::make(&[0; 256]);
+    let mut image = disk_image::open(file.path()).unwrap();
+    image.add_overlay();
+    image.write_at(130, &[1, 2, 3]).unwrap();
+    image.write_at(300, &[4]).unwrap();
+    image.commit().unwrap();
+    assert!(!image.is_modified() && image.has_overlay());
+
+    // The file is extended to the end of the last chunk written
+    let contents = file.contents();
+    assert_eq!(contents.len(), 384);
+    assert_eq!(&contents[129..134], &[0, 1, 2, 3, 0]);
+    assert_eq!(contents[300], 4);
+    assert_eq!(contents.iter().map(|&b| b as usize).sum::<usize>(), 10);
+}
+
+#[test]
+fn writable_file_is_opened_read_write()
+{
+    let file = temp_file::make(&[7; 128]);
+    let mut image = disk_image::open(file.path()).unwrap();
+    assert!(!image.is_read_only() && image.is_writable());
+    image.write_at(0, &[8; 2]).unwrap();
+    assert_eq!(&file.contents()[..3], &[8, 8, 7]);
+}
+
+#[test]
+fn writable_file_can_be_opened_read_only()
+{
+    let file = temp_file::make(&[7; 128]);
+    let mut image = disk_image::open_read_only(file.path()).unwrap();
+    assert!(image.is_read_only() && !image.is_writable());
+    assert!(image.write_at(0, &[8; 2]).is_err());
+    assert_eq!(file.contents(), vec![7; 128]);
+}
+
+#[test]
+fn missing_file_is_an_error()
+{
+    let dir = temp_file::make_dir();
+    let path = dir.path().join("missing.bin");
+    let path = path.to_str().unwrap();
+    assert_eq!(disk_image::open(path).err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+    assert_eq!(disk_image::open_read_only(path).err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+}
+
+// Opening the file for writing fails, so it is opened read-only.
+
+#[cfg(target_os = "linux")]
+#[test]
+fn overlay_makes_a_read_only_file_writable()
+{
+    let (path, contents) = temp_file::READ_ONLY_FILE;
+    let mut image = disk_image::open(path).unwrap();
+    assert!(image.is_read_only() && !image.is_writable());
+    assert_eq!(read(&mut image, 0, contents.len()), contents);
+    assert!(image.write_at(0, &[8; 128]).is_err());
+
+    image.add_overlay();
+    assert!(image.is_writable());
+    image.write_at(0, &[8; 128]).unwrap();
+    assert_eq!(read(&mut image, 0, 128), vec![8; 128]);
+    assert!(image.commit().is_err());
+    assert_eq!(fs::read(path).unwrap(), contents);
+
+    // The delta is kept
+    assert!(image.is_modified());
+    assert_eq!(read(&mut image, 0, 128), vec![8; 128]);
+}
@@ -0,0 +1,139 @@
+// Tests of the detection of self-modifying code and of execution from memory
+// that was never written.
+
+extern crate z80emu;
+
+use z80emu::coverage;
+use z80emu::exec_check::{self, ExecCheck, Finding, Kind};
+use z80emu::z80::{self, StopReason, Z80};
+
+// Make a CPU with `code` loaded at address 0 and `chk` attached.
+
+fn make_cpu(code: &[u8], chk: ExecCheck) -> Z80
+{
+    let mut cpu = z80::make(0);
+    cpu.mem[..code.len()].copy_from_slice(code);
+    let mut chk = chk;
+    chk.load(0, code.len());
+    cpu.exec_check = Some(Box::new(chk));
+    cpu
+}
+
+fn findings(cpu: &Z80) -> Vec<Finding>
+{
+    cpu.exec_check.as_ref().unwrap().findings().to_vec()
+}
+
+// The return address of the CALL overwrites the operand of the JP, which has
+// been executed, and then the JP is executed again:
+//
+//   0000  JP 0010h
+//   0010  LD SP,0003h
+//   0013  CALL 0020h   ; pushes 0016h to 0001h
+//   0016  HALT
+//   0020  JP 0000h     ; the JP at 0000h now goes to 0016h
+
+fn self_modifying_code() -> Vec<u8>
+{
+    let mut code = vec![0; 0x23];
+    code[0x00..0x03].copy_from_slice(&[0xC3, 0x10, 0x00]);
+    code[0x10..0x13].copy_from_slice(&[0x31, 0x03, 0x00]);
+    code[0x13..0x16].copy_from_slice(&[0xCD, 0x20, 0x00]);
+    code[0x16] = 0x76;
+    code[0x20..0x23].copy_from_slice(&[0xC3, 0x00, 0x00]);
+    code
+}
+
+#[test]
+fn detects_self_modifying_code()
+{
+    let mut cpu = make_cpu(&self_modifying_code(), exec_check::make());
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::Halt);
+    assert_eq!(cpu.pc, 0x0017);
+    assert_eq!(findings(&cpu), vec![Finding { kind: Kind::SelfModified, pc: 0x0000, addr: 0x0001 }]);
+}
+
+#[test]
+fn stops_after_the_modified_instruction()
+{
+    let mut cpu = make_cpu(&self_modifying_code(), exec_check::make_stopping());
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::ExecCheck);
+    assert_eq!(cpu.pc, 0x0016);
+
+    // Resuming continues
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::Halt);
+    assert_eq!(findings(&cpu).len(), 1);
+}
+
+// Stopping before an instruction does not count it for coverage; it is
+// counted when it runs after resuming.
+
+#[test]
+fn stopping_does_not_count_coverage_twice()
+{
+    let mut cpu = make_cpu(&self_modifying_code(), exec_check::make_stopping());
+    cpu.coverage = Some(Box::new(coverage::make()));
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::ExecCheck);
+    assert_eq!(cpu.coverage.as_ref().unwrap().hits(0x0016), 0);
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::Halt);
+    let cov = cpu.coverage.as_ref().unwrap();
+    assert_eq!((cov.hits(0x0000), cov.hits(0x0013), cov.hits(0x0016)), (2, 1, 1));
+}
+
+#[test]
+fn code_loaded_before_running_is_not_modified()
+{
+    // LD A,1; JP 0000h, reloaded before it runs again
+    let code = [0x37, 0x01, 0xC3, 0x00, 0x00];
+    let mut cpu = make_cpu(&code, exec_check::make());
+    z80::run(&mut cpu, 10);
+    assert_eq!(findings(&cpu), vec![]);
+
+    cpu.mem[1] = 2;
+    cpu.exec_check.as_mut().unwrap().load(1, 1);
+    z80::run(&mut cpu, 10);
+    assert_eq!(findings(&cpu), vec![Finding { kind: Kind::SelfModified, pc: 0x0000, addr: 0x0001 }]);
+}
+
+#[test]
+fn detects_running_into_unwritten_memory_once()
+{
+    // JP 0100h, into zeroed memory
+    let mut cpu = make_cpu(&[0xC3, 0x00, 0x01], exec_check::make());
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::Poll);
+    assert_eq!(findings(&cpu), vec![Finding { kind: Kind::NeverWritten, pc: 0x0100, addr: 0x0100 }]);
+
+    let mut cpu = make_cpu(&[0xC3, 0x00, 0x01], exec_check::make_stopping());
+    z80::run(&mut cpu, 1000);
+    assert_eq!(cpu.stop_reason, StopReason::ExecCheck);
+    assert_eq!(cpu.pc, 0x0101);
+}
+
+#[test]
+fn unwritten_operands_are_detected()
+{
+    // LD A,n with n past the loaded code
+    let mut cpu = make_cpu(&[0x37], exec_check::make());
+    z80::step(&mut cpu);
+    assert_eq!(findings(&cpu), vec![Finding { kind: Kind::NeverWritten, pc: 0x0000, addr: 0x0001 }]);
+}
+
+#[test]
+fn stack_writes_count_as_written()
+{
+    // LD SP,0010h; CALL 0008h; then at 0008h: JP 000Eh, the pushed return
+    // address, which is 0006h = 06 00, ie LD B,0 ... as code
+    let code = [0x31, 0x10, 0x00, 0xCD, 0x08, 0x00, 0x76, 0x00, 0xC3, 0x0E, 0x00];
+    let mut cpu = make_cpu(&code, exec_check::make());
+    z80::run(&mut cpu, 4);
+    assert_eq!(cpu.pc, 0x000E);
+    z80::step(&mut cpu);
+    assert_eq!((cpu.pc, cpu.b), (0x0010, 0));
+    assert_eq!(findings(&cpu), vec![]);
+}
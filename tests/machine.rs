@@ -0,0 +1,233 @@
+// Tests of the machine's port map, with guest programs in ROM.  See
+// common/harness.rs.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use common::{harness, temp_file};
+use z80emu::{file_backed_spinning_disk, machine, save_state, z80};
+use z80emu::devices::SpinningDiskStatus;
+use z80emu::file_backed_spinning_disk::Timing;
+
+// Select disk 1 (B:), check it is present, read its sector 0 to 0100h, and
+// print the first byte.  Then select disk 2 (C:), which is not present, and
+// print its presence and the selected disk number.
+
+const READ_DISK_B : &[u8] = &[
+    0x3E, 0x01, 0xD3, 0x16,                     // LD A,1; OUT (SELECT_DISK),A
+    0xDB, 0x11, 0xD3, 0x00,                     // IN A,(DISK_PRESENT); OUT (0),A
+    0x3E, 0x00, 0xD3, 0x10, 0xD3, 0x11,         // LD A,0; OUT (SET_HEAD),A; OUT (SET_TRACK),A
+    0xD3, 0x12, 0xD3, 0x13,                     // OUT (SET_SECTOR),A; OUT (SET_DMA_LOW),A
+    0x3E, 0x01, 0xD3, 0x14,                     // LD A,1; OUT (SET_DMA_HIGH),A
+    0x3E, 0x03, 0xD3, 0x15,                     // CLEAR
+    0x3E, 0x00, 0xD3, 0x15,                     // SEEK
+    0x3E, 0x03, 0xD3, 0x15,                     // CLEAR
+    0x3E, 0x01, 0xD3, 0x15,                     // READ
+    0x21, 0x00, 0x01, 0x3E, 0x00, 0x86,         // LD HL,0100h; LD A,0; ADD A,(HL)
+    0xD3, 0x00,                                 // OUT (0),A
+    0x3E, 0x02, 0xD3, 0x16,                     // LD A,2; OUT (SELECT_DISK),A
+    0xDB, 0x11, 0xD3, 0x00,                     // IN A,(DISK_PRESENT); OUT (0),A
+    0xDB, 0x16, 0xD3, 0x00,                     // IN A,(SELECTED_DISK); OUT (0),A
+    0x76,                                       // HALT
+];
+
+#[test]
+fn select_disk()
+{
+    let run = harness::guest(READ_DISK_B)
+        .disk(&[b'A'; 128], 1, 1, 1)
+        .drive(1, &[b'B'; 128], 1, 1, 1)
+        .run();
+    assert_eq!(run.output, vec![0xFF, b'B', 0x00, 0x02]);
+    assert_eq!(run.halted_at(), harness::ROM_ADDR as u16 + 58);
+}
+
+#[test]
+fn disks_keep_their_parameters()
+{
+    // Read sector 0 of B: as above, then select A: and read with the
+    // parameters it was given before
+    let mut rom = vec![
+        0x3E, 0x00, 0xD3, 0x10, 0xD3, 0x11, 0xD3, 0x13,   // A: head, track, DMA low 0
+        0x3E, 0x01, 0xD3, 0x12,                           // A: sector 1
+        0x3E, 0x02, 0xD3, 0x14,                           // A: DMA 0200h
+    ];
+    rom.extend_from_slice(&READ_DISK_B[..46]);
+    rom.extend_from_slice(&[
+        0x3E, 0x00, 0xD3, 0x16,                           // Select A:
+        0x3E, 0x03, 0xD3, 0x15, 0x3E, 0x00, 0xD3, 0x15,   // CLEAR, SEEK
+        0x3E, 0x03, 0xD3, 0x15, 0x3E, 0x01, 0xD3, 0x15,   // CLEAR, READ
+        0x21, 0x00, 0x02, 0x3E, 0x00, 0x86, 0xD3, 0x00,   // Print the byte at 0200h
+        0x76,
+    ]);
+    let mut image_a = vec![b'0'; 128];
+    image_a.extend_from_slice(&[b'1'; 128]);
+    let run = harness::guest(&rom)
+        .disk(&image_a, 1, 1, 2)
+        .drive(1, &[b'B'; 128], 1, 1, 1)
+        .run();
+    assert_eq!(run.output, vec![0xFF, b'B', b'1']);
+}
+
+#[test]
+fn save_state_keeps_the_selected_disk()
+{
+    let image_a = temp_file::make(&[0; 128]);
+    let image_b = temp_file::make(&[0; 128]);
+    let state = temp_file::make(&[]);
+    let mut a = file_backed_spinning_disk::make(image_a.path(), 1, 1, 1);
+    let mut b = file_backed_spinning_disk::make(image_b.path(), 1, 1, 1);
+    let mut tty = harness::capture_tty(&[]);
+
+    // LD A,1; OUT (SELECT_DISK),A; HALT; IN A,(SELECTED_DISK); OUT (0),A; HALT
+    let mut cpu = z80::make(0);
+    cpu.mem[..10].copy_from_slice(&[0x3E, 0x01, 0xD3, 0x16, 0x76, 0xDB, 0x16, 0xD3, 0x00, 0x76]);
+    {
+        let mut m = machine::builder().tty(&mut tty).disk(0, &mut a).disk(1, &mut b).build();
+        m.run(&mut cpu, 1000).unwrap();
+        save_state::save(state.path(), &cpu, &m).unwrap();
+    }
+
+    let mut cpu = z80::make(0);
+    let mut m = machine::builder().tty(&mut tty).disk(0, &mut a).disk(1, &mut b).build();
+    save_state::restore(state.path(), &mut cpu, &mut m).unwrap();
+    m.run(&mut cpu, 1000).unwrap();
+    drop(m);
+    assert_eq!(tty.output, vec![0x01]);
+}
+
+#[test]
+fn selected_disk_carries_over()
+{
+    let mut tty = harness::capture_tty(&[]);
+
+    // LD A,3; OUT (SELECT_DISK),A; HALT
+    let mut cpu = z80::make(0);
+    cpu.mem[..5].copy_from_slice(&[0x3E, 0x03, 0xD3, 0x16, 0x76]);
+    let selected = {
+        let mut m = machine::builder().tty(&mut tty).build();
+        m.run(&mut cpu, 1000).unwrap();
+        m.selected_disk()
+    };
+    assert_eq!(selected, 3);
+
+    // IN A,(SELECTED_DISK); OUT (0),A; HALT
+    let mut cpu = z80::make(0);
+    cpu.mem[..5].copy_from_slice(&[0xDB, 0x16, 0xD3, 0x00, 0x76]);
+    let mut m = machine::builder().tty(&mut tty).select_disk(selected).build();
+    m.run(&mut cpu, 1000).unwrap();
+    drop(m);
+    assert_eq!(tty.output, vec![0x03]);
+}
+
+// Read blocks 1 and 2 of the block device to 0100h and print the first byte of
+// each and the result.
+
+#[test]
+fn block_device()
+{
+    let rom = [
+        0x3E, 0x01, 0xD3, 0x20,                     // LD A,1; OUT (SET_LBA_0),A
+        0x3E, 0x00, 0xD3, 0x21, 0xD3, 0x22, 0xD3, 0x23,  // LD A,0; OUT (SET_LBA_1..3),A
+        0xD3, 0x25,                                 // OUT (SET_BLOCK_DMA_LOW),A
+        0x3E, 0x01, 0xD3, 0x26,                     // LD A,1; OUT (SET_BLOCK_DMA_HIGH),A
+        0x3E, 0x02, 0xD3, 0x24,                     // LD A,2; OUT (SET_BLOCK_COUNT),A
+        0x3E, 0x03, 0xD3, 0x27,                     // CLEAR
+        0x3E, 0x00, 0xD3, 0x27,                     // SEEK
+        0x3E, 0x03, 0xD3, 0x27,                     // CLEAR
+        0x3E, 0x01, 0xD3, 0x27,                     // READ
+        0x21, 0x00, 0x01, 0x3E, 0x00, 0x86,         // LD HL,0100h; LD A,0; ADD A,(HL)
+        0xD3, 0x00,                                 // OUT (0),A
+        0x21, 0x00, 0x03, 0x3E, 0x00, 0x86,         // LD HL,0300h; LD A,0; ADD A,(HL)
+        0xD3, 0x00,                                 // OUT (0),A
+        0xDB, 0x20, 0xD3, 0x00,                     // IN A,(BLOCK_RESULT); OUT (0),A
+        0x76,                                       // HALT
+    ];
+    let image = (0..4).flat_map(|n| vec![b'0' + n; 512]).collect::<Vec<u8>>();
+    let run = harness::guest(&rom).block_device(&image, 4).exec_check().run();
+    assert_eq!(run.output, b"12\x01");
+    assert_eq!(run.findings, vec![]);
+    assert_eq!(run.block_image.as_ref().unwrap(), &image);
+}
+
+// Seek disk A: to track 3 and start polling for the result, printing each
+// status.
+
+const SEEK_TRACK_3 : &[u8] = &[
+    0x3E, 0x00, 0xD3, 0x10, 0xD3, 0x12,         // LD A,0; OUT (SET_HEAD),A; OUT (SET_SECTOR),A
+    0x3E, 0x03, 0xD3, 0x11,                     // LD A,3; OUT (SET_TRACK),A
+    0x3E, 0x03, 0xD3, 0x15,                     // CLEAR
+    0x3E, 0x00, 0xD3, 0x15,                     // SEEK
+];
+
+const TIMING : Timing = Timing { step: 1000, rotation: 10000, transfer: 100, interrupt: None };
+
+#[test]
+fn disk_is_busy_while_seeking()
+{
+    let mut rom = SEEK_TRACK_3.to_vec();
+    rom.extend_from_slice(&[
+        0xDB, 0x10, 0xD3, 0x00,                 // IN A,(DISK_RESULT); OUT (0),A
+        0xE6, 0x02,                             // AND 02h
+        0xC2, 0x92, 0xFF,                       // JP NZ,FF92h
+        0x76,                                   // HALT
+    ]);
+    let run = harness::guest(&rom).disk(&[0; 4 * 128], 1, 4, 1).disk_timing(TIMING).run();
+
+    // 3000 cycles at 39 cycles a poll
+    let (last, polls) = run.output.split_last().unwrap();
+    assert_eq!(*last, 0x01);
+    assert!(polls.iter().all(|&b| b == 0x02));
+    assert!((75..=78).contains(&polls.len()), "{} polls", polls.len());
+}
+
+#[test]
+fn disk_interrupts_on_completion()
+{
+    let mut rom = vec![
+        0xED, 0x56, 0xFB,                       // IM 1; EI
+    ];
+    rom.extend_from_slice(SEEK_TRACK_3);
+    rom.extend_from_slice(&[
+        0xC3, 0x95, 0xFF,                       // JP FF95h
+    ]);
+    let isr = [
+        0xDB, 0x10, 0xD3, 0x00,                 // IN A,(DISK_RESULT); OUT (0),A
+        0x76,                                   // HALT
+    ];
+    let timing = Timing { interrupt: Some(0xFF), ..TIMING };
+    let run = harness::guest(&rom).memory(0x0038, &isr).disk(&[0; 4 * 128], 1, 4, 1).disk_timing(timing).run();
+    assert_eq!(run.output, vec![0x01]);
+    assert_eq!(run.halted_at(), 0x003C);
+
+    // The SEEK is issued at cycle 95 and takes 3000 cycles, a whole number of
+    // JPs, so the interrupt is taken as the seek completes.  Then 13 cycles to
+    // take it and 26 for the handler.
+    assert_eq!(run.cpu.cycles, 95 + 3000 + 13 + 26);
+}
+
+#[test]
+fn disk_interrupts_on_failure()
+{
+    let mut rom = vec![
+        0xED, 0x56, 0xFB,                       // IM 1; EI
+    ];
+    rom.extend_from_slice(SEEK_TRACK_3);
+    rom.extend_from_slice(&[
+        0xC3, 0x95, 0xFF,                       // JP FF95h
+    ]);
+    let isr = [
+        0xDB, 0x10, 0xD3, 0x00,                 // IN A,(DISK_RESULT); OUT (0),A
+        0x76,                                   // HALT
+    ];
+
+    // Track 3 is past the end of the disk, so the SEEK fails at once and the
+    // interrupt is taken straight after it
+    let timing = Timing { interrupt: Some(0xFF), ..TIMING };
+    let run = harness::guest(&rom).memory(0x0038, &isr).disk(&[0; 2 * 128], 1, 2, 1).disk_timing(timing).run();
+    assert_eq!(run.output, vec![SpinningDiskStatus::SeekError as u8]);
+    assert_eq!(run.cpu.cycles, 95 + 13 + 26);
+}
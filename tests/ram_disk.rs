@@ -0,0 +1,197 @@
+// Tests of the RAM disk, driven through the SpinningDisk interface as the
+// machine drives it.  Only loading and saving images need the `std` feature.
+
+extern crate z80emu;
+
+mod common;
+
+use common::disk::{pattern, transfer, READ, READ_MULTI, SEEK, WRITE, WRITE_MULTI};
+#[cfg(feature = "std")]
+use common::temp_file;
+use z80emu::devices::{DeviceState, SpinningDisk, SpinningDiskStatus};
+#[cfg(feature = "std")]
+use z80emu::file_backed_spinning_disk;
+use z80emu::ram_disk;
+
+#[test]
+fn starts_zeroed()
+{
+    let dsk = ram_disk::make(2, 3, 4, 256);
+    assert_eq!(dsk.contents(), &vec![0; 2 * 3 * 4 * 256][..]);
+}
+
+#[test]
+fn image_is_zero_filled()
+{
+    let dsk = ram_disk::make_from_image(&[0xE5; 100], 1, 1, 2, 128);
+    assert_eq!(dsk.contents().len(), 256);
+    assert!(dsk.contents()[..100].iter().all(|&b| b == 0xE5));
+    assert!(dsk.contents()[100..].iter().all(|&b| b == 0));
+}
+
+#[test]
+#[should_panic(expected = "Image is larger than the disk")]
+fn image_larger_than_the_disk()
+{
+    ram_disk::make_from_image(&[0; 129], 1, 1, 1, 128);
+}
+
+#[test]
+#[should_panic(expected = "Bad geometry")]
+fn empty_geometry()
+{
+    ram_disk::make(1, 0, 1, 128);
+}
+
+#[test]
+fn write_then_read_round_trips()
+{
+    let mut dsk = ram_disk::make(2, 2, 4, 128);
+    let mut mem = vec![0; 65536];
+    mem[0x1000..0x1080].copy_from_slice(&pattern(1, 128));
+    assert_eq!(transfer(&mut dsk, WRITE, (1, 0, 2), 0x1000, &mut mem), SpinningDiskStatus::Done);
+
+    assert_eq!(transfer(&mut dsk, READ, (1, 0, 2), 0x2000, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&mem[0x2000..0x2080], &pattern(1, 128)[..]);
+    assert_eq!(dsk.written(), Some((0x2000, 128)));
+
+    // Head 1, track 0, sector 2 is sector 10 of the disk
+    let offset = 10 * 128;
+    assert_eq!(&dsk.contents()[offset..offset + 128], &pattern(1, 128)[..]);
+    assert!(dsk.contents()[..offset].iter().chain(&dsk.contents()[offset + 128..]).all(|&b| b == 0));
+}
+
+#[test]
+fn transfers_wrap_around_memory()
+{
+    let mut dsk = ram_disk::make(1, 1, 1, 128);
+    let mut mem = vec![0; 65536];
+    let data = pattern(2, 128);
+    mem[0xFFC0..].copy_from_slice(&data[..64]);
+    mem[..64].copy_from_slice(&data[64..]);
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 0), 0xFFC0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(dsk.contents(), &data[..]);
+}
+
+#[test]
+fn multi_transfers()
+{
+    let image = pattern(3, 2 * 2 * 2 * 128);
+    let mut dsk = ram_disk::make_from_image(&image, 2, 2, 2, 128);
+    let mut mem = vec![0; 65536];
+
+    // Three sectors from the last sector of head 0, track 1, across the head
+    dsk.set_sector_count(3);
+    assert_eq!(transfer(&mut dsk, READ_MULTI, (0, 1, 1), 0x4000, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&mem[0x4000..0x4180], &image[3 * 128..6 * 128]);
+    assert_eq!(dsk.written(), Some((0x4000, 3 * 128)));
+
+    mem[0x4000..0x4180].copy_from_slice(&pattern(4, 3 * 128));
+    assert_eq!(transfer(&mut dsk, WRITE_MULTI, (0, 0, 0), 0x4000, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&dsk.contents()[..3 * 128], &pattern(4, 3 * 128)[..]);
+
+    // A transfer past the end of the disk fails without transferring
+    let before = dsk.contents().to_vec();
+    assert_eq!(transfer(&mut dsk, WRITE_MULTI, (1, 1, 0), 0x4000, &mut mem), SpinningDiskStatus::SeekError);
+    assert_eq!(dsk.contents(), &before[..]);
+}
+
+#[test]
+fn out_of_range_parameters_fail_the_seek()
+{
+    let mut dsk = ram_disk::make(1, 2, 3, 128);
+    let mut mem = vec![0; 65536];
+    for &chs in &[(1, 0, 0), (0, 2, 0), (0, 0, 3), (0, 0x100, 0)] {
+        assert_eq!(transfer(&mut dsk, READ, chs, 0, &mut mem), SpinningDiskStatus::SeekError);
+    }
+}
+
+#[test]
+fn write_protected_disk()
+{
+    let mut dsk = ram_disk::make_from_image(&[0xE5; 128], 1, 1, 1, 128);
+    dsk.set_write_protect(true);
+    assert!(dsk.is_write_protected());
+    let mut mem = vec![0; 65536];
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 0), 0, &mut mem), SpinningDiskStatus::WriteProtected);
+    assert_eq!(dsk.contents(), &[0xE5; 128][..]);
+    assert_eq!(transfer(&mut dsk, READ, (0, 0, 0), 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(&mem[..128], &[0xE5; 128][..]);
+}
+
+#[test]
+fn state_round_trips()
+{
+    let mut dsk = ram_disk::make(1, 0x200, 0x100, 128);
+    let mut mem = vec![0; 65536];
+    mem[..128].copy_from_slice(&pattern(7, 128));
+    dsk.set_sector_count(7);
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0x1FF, 0xFE), 0, &mut mem), SpinningDiskStatus::Done);
+    assert_eq!(transfer(&mut dsk, SEEK, (0, 0x1FF, 0xFF), 0x1234, &mut mem), SpinningDiskStatus::Done);
+    let mut state = vec![];
+    dsk.save_state(&mut state);
+
+    let mut other = ram_disk::make(1, 0x200, 0x100, 128);
+    assert!(other.restore_state(&state));
+    assert_eq!(other.contents(), dsk.contents());
+    let mut restored = vec![];
+    other.save_state(&mut restored);
+    assert_eq!(restored, state);
+
+    // The offset must be on the disk, and the contents the size of the disk
+    let mut small = ram_disk::make(1, 1, 1, 128);
+    assert!(!small.restore_state(&state));
+    assert!(!small.restore_state(&state[..14]));
+    assert!(!other.restore_state(&state[..state.len() - 1]));
+}
+
+// The state is a file backed disk's followed by the contents.  A file backed
+// disk restores the state without them, and a RAM disk given that keeps its
+// contents.
+
+#[cfg(feature = "std")]
+#[test]
+fn file_backed_disk_restores_the_state()
+{
+    let mut dsk = ram_disk::make_from_image(&[0xE5; 128], 1, 4, 4, 128);
+    let mut mem = vec![0; 65536];
+    dsk.set_sector_count(3);
+    assert_eq!(transfer(&mut dsk, SEEK, (0, 2, 3), 0x1234, &mut mem), SpinningDiskStatus::Done);
+    let mut state = vec![];
+    dsk.save_state(&mut state);
+    assert_eq!(state.len(), 28 + 16 * 128);
+    assert_eq!(&state[28..], dsk.contents());
+
+    let file = temp_file::make(&[]);
+    let mut other = file_backed_spinning_disk::make(file.path(), 1, 4, 4);
+    assert!(!other.restore_state(&state));
+    assert!(other.restore_state(&state[..28]));
+    let mut restored = vec![];
+    other.save_state(&mut restored);
+    assert_eq!(restored, &state[..28]);
+
+    let mut blank = ram_disk::make(1, 4, 4, 128);
+    assert!(blank.restore_state(&state[..28]));
+    assert!(blank.contents().iter().all(|&b| b == 0));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn load_and_save()
+{
+    let file = temp_file::make(&pattern(5, 200));
+    let mut dsk = ram_disk::load(file.path(), 1, 1, 2, 128).unwrap();
+    assert_eq!(&dsk.contents()[..200], &pattern(5, 200)[..]);
+
+    let mut mem = vec![0; 65536];
+    mem[..128].copy_from_slice(&pattern(6, 128));
+    assert_eq!(transfer(&mut dsk, WRITE, (0, 0, 1), 0, &mut mem), SpinningDiskStatus::Done);
+
+    // The file is untouched until the disk is saved
+    assert_eq!(file.contents(), pattern(5, 200));
+    dsk.save(file.path()).unwrap();
+    let contents = file.contents();
+    assert_eq!(contents.len(), 256);
+    assert_eq!(&contents[..128], &pattern(5, 128)[..]);
+    assert_eq!(&contents[128..], &pattern(6, 128)[..]);
+}
@@ -0,0 +1,150 @@
+// Tests of recording a guest's input and replaying it.  See
+// common/harness.rs.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use common::{harness, temp_file};
+use z80emu::file_backed_spinning_disk::Timing;
+
+// Point the DMA at 0100h, seek disk A: to track 3, and wait for the disk's
+// interrupt.  The interrupt handler prints the disk status and a character of
+// input, reads the sector, and prints its first byte.
+
+const ROM : &[u8] = &[
+    0xED, 0x56, 0xFB,                           // IM 1; EI
+    0x3E, 0x01, 0xD3, 0x14,                     // LD A,1; OUT (SET_DMA_HIGH),A
+    0x3E, 0x00, 0xD3, 0x10, 0xD3, 0x12,         // LD A,0; OUT (SET_HEAD),A; OUT (SET_SECTOR),A
+    0x3E, 0x03, 0xD3, 0x11,                     // LD A,3; OUT (SET_TRACK),A
+    0x3E, 0x03, 0xD3, 0x15,                     // CLEAR
+    0x3E, 0x00, 0xD3, 0x15,                     // SEEK
+    0xC3, 0x99, 0xFF,                           // JP FF99h
+];
+
+const ISR : &[u8] = &[
+    0xDB, 0x10, 0xD3, 0x00,                     // IN A,(DISK_RESULT); OUT (0),A
+    0xDB, 0x00, 0xD3, 0x00,                     // IN A,(CHAR_IN); OUT (0),A
+    0x3E, 0x03, 0xD3, 0x15,                     // CLEAR
+    0x3E, 0x01, 0xD3, 0x15,                     // READ
+    0x21, 0x00, 0x01, 0x3E, 0x00, 0x86,         // LD HL,0100h; LD A,0; ADD A,(HL)
+    0xD3, 0x00,                                 // OUT (0),A
+    0x76,                                       // HALT
+];
+
+const TIMING : Timing = Timing { step: 1000, rotation: 10000, transfer: 100, interrupt: Some(0xFF) };
+
+fn image() -> Vec<u8>
+{
+    (0..4).flat_map(|n| vec![b'0' + n; 128]).collect()
+}
+
+fn guest(image: &[u8], input: &[u8]) -> harness::Guest
+{
+    harness::guest(ROM).memory(0x0038, ISR).disk(image, 1, 4, 1).disk_timing(TIMING).input(input)
+}
+
+// A replay of the recording in `path`.  It has no disk and no input, as the
+// replay does not use the devices.
+
+fn replay(path: &str) -> harness::Run
+{
+    harness::guest(ROM).memory(0x0038, ISR).replay(path).run()
+}
+
+// The offsets of the events in a recording, see record_replay.
+
+fn events(log: &[u8]) -> Vec<usize>
+{
+    let mut offsets = vec![];
+    let mut i = 8 + 2 + 8;
+    while i < log.len() {
+        offsets.push(i);
+        i += match log[i] {
+            0 | 1 => 1 + 8 + 2,
+            2 => 1 + 2 + 2 + (log[i + 3] as usize | (log[i + 4] as usize) << 8),
+            _ => 1 + 8 + 1,
+        };
+    }
+    offsets
+}
+
+// A recording with its interrupt, and everything after it, moved `by` cycles
+// later.
+
+fn shift_interrupt(log: &[u8], by: u64) -> Vec<u8>
+{
+    let mut data = log.to_vec();
+    let events = events(log);
+    let first = events.iter().position(|&i| log[i] == 3).expect("No interrupt");
+    for &i in events[first..].iter().filter(|&&i| log[i] != 2) {
+        let mut cycles = [0; 8];
+        cycles.copy_from_slice(&data[i + 1..i + 9]);
+        data[i + 1..i + 9].copy_from_slice(&(u64::from_le_bytes(cycles) + by).to_le_bytes());
+    }
+    data
+}
+
+// Record a run, and replay it without input and without a disk: the disk's
+// memory writes and the interrupt come from the recording, and the output goes
+// nowhere.
+
+#[test]
+fn replay_gives_the_same_run()
+{
+    let log = temp_file::make(&[]);
+    let recorded = guest(&image(), b"q").record(log.path()).run();
+    assert_eq!(recorded.output, b"\x01q3");
+    assert_eq!(recorded.halted_at(), 0x0038 + ISR.len() as u16 - 1);
+
+    let replayed = replay(log.path());
+    assert!(replayed.output.is_empty());
+    assert_eq!(replayed.cpu.cycles, recorded.cpu.cycles);
+    assert_eq!(replayed.halted_at(), recorded.halted_at());
+    assert!(replayed.cpu.mem[..] == recorded.cpu.mem[..]);
+}
+
+// The replay stops the CPU at the cycle of each recorded interrupt, wherever
+// the recording's timeslices ended.
+
+#[test]
+fn interrupts_replay_at_their_cycle()
+{
+    let log = temp_file::make(&[]);
+    let recorded = guest(&image(), b"q").record(log.path()).run();
+
+    // One more time round the JP loop
+    let shifted = temp_file::make(&shift_interrupt(&log.contents(), 10));
+    let replayed = replay(shifted.path());
+    assert_eq!(replayed.halted_at(), recorded.halted_at());
+    assert_eq!(replayed.cpu.cycles, recorded.cpu.cycles + 10);
+}
+
+#[test]
+#[should_panic(expected = "got no interrupt by cycle")]
+fn interrupt_between_instructions_diverges()
+{
+    let log = temp_file::make(&[]);
+    guest(&image(), b"q").record(log.path()).run();
+    let shifted = temp_file::make(&shift_interrupt(&log.contents(), 3));
+    replay(shifted.path());
+}
+
+// A recording whose input is changed makes the guest print something else,
+// which does not match the recorded output.
+
+#[test]
+#[should_panic(expected = "Replay diverged at event")]
+fn tampered_recording_diverges()
+{
+    let log = temp_file::make(&[]);
+    guest(&image(), b"q").record(log.path()).run();
+    let mut data = log.contents();
+    let at = events(&data).into_iter().find(|&i| data[i] == 0 && data[i + 9] == 0x00).expect("No CHAR_IN");
+    assert_eq!(data[at + 10], b'q');
+    data[at + 10] = b'r';
+    let tampered = temp_file::make(&data);
+    replay(tampered.path());
+}
@@ -0,0 +1,276 @@
+// Tests of machine save states: round trips, loading each older version of
+// the format, and rejecting bad files.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use common::{harness, temp_file};
+use z80emu::devices::{DeviceState, SpinningDisk, SpinningDiskStatus};
+use z80emu::z80::{self, StopReason, Z80};
+use z80emu::{file_backed_spinning_disk, machine, ram_disk, rng, save_state};
+
+// A CPU in which every register differs from zero and from the others.
+
+fn busy_cpu() -> Z80
+{
+    let mut cpu = z80::power_on(&mut rng::make(7), true);
+    cpu.pc = 0x1234;
+    cpu.i = 0x3F;
+    cpu.r = 0x45;
+    cpu.im = 2;
+    cpu.iff1 = true;
+    cpu.iff2 = true;
+    cpu.cycles = 0x0102_0304_0506;
+    cpu.stop_reason = StopReason::Trap(0x2A);
+    cpu.port_addr = 0x15;
+    cpu
+}
+
+fn registers(cpu: &Z80) -> Vec<u64>
+{
+    vec![cpu.pc as u64, cpu.sp as u64, cpu.ix as u64, cpu.iy as u64,
+         cpu.a as u64, cpu.f as u64, cpu.b as u64, cpu.c as u64, cpu.d as u64, cpu.e as u64, cpu.h as u64,
+         cpu.l as u64, cpu.a_alt as u64, cpu.f_alt as u64, cpu.b_alt as u64, cpu.c_alt as u64,
+         cpu.d_alt as u64, cpu.e_alt as u64, cpu.h_alt as u64, cpu.l_alt as u64,
+         cpu.port_addr as u64, cpu.i as u64, cpu.r as u64, cpu.im as u64, cpu.iff1 as u64, cpu.iff2 as u64,
+         cpu.cycles]
+}
+
+// Save a CPU with a TTY and a disk A: seeked to a sector, and restore it into
+// a fresh machine.
+
+#[test]
+fn round_trip()
+{
+    let image = temp_file::make(&[0; 4 * 128]);
+    let state = temp_file::make(&[]);
+    let cpu = busy_cpu();
+    let mut dsk = file_backed_spinning_disk::make(image.path(), 1, 1, 4);
+    let mut mem = vec![0; 65536];
+    dsk.set_sector(3);
+    dsk.set_dma_high(0x40);
+    dsk.disk_operation(0x03, &mut mem);
+    dsk.disk_operation(0x00, &mut mem);
+    let mut tty = harness::capture_tty(&[]);
+    {
+        let m = machine::builder().tty(&mut tty).disk_a(&mut dsk).build();
+        save_state::save(state.path(), &cpu, &m).unwrap();
+    }
+
+    let mut restored = z80::make(0);
+    let mut dsk2 = file_backed_spinning_disk::make(image.path(), 1, 1, 4);
+    {
+        let mut m = machine::builder().tty(&mut tty).disk_a(&mut dsk2).build();
+        save_state::restore(state.path(), &mut restored, &mut m).unwrap();
+    }
+    assert_eq!(registers(&restored), registers(&cpu));
+    assert_eq!(restored.stop_reason, StopReason::Trap(0x2A));
+    assert!(restored.mem[..] == cpu.mem[..]);
+    assert_eq!(dsk2.get_status(), SpinningDiskStatus::Done);
+
+    // Saving the restored machine gives the same file
+    let again = temp_file::make(&[]);
+    {
+        let m = machine::builder().tty(&mut tty).disk_a(&mut dsk2).build();
+        save_state::save(again.path(), &restored, &m).unwrap();
+    }
+    assert_eq!(again.contents(), state.contents());
+}
+
+// A RAM disk's contents are in the state, so the guest's writes survive.
+
+#[test]
+fn ram_disk_contents_round_trip()
+{
+    let state = temp_file::make(&[]);
+    let mut dsk = ram_disk::make(1, 1, 2, 128);
+    let mut mem = vec![0; 65536];
+    mem[..128].copy_from_slice(&[0xA5; 128]);
+    dsk.set_sector(1);
+    dsk.disk_operation(0x03, &mut mem);
+    dsk.disk_operation(0x00, &mut mem);
+    dsk.disk_operation(0x03, &mut mem);
+    dsk.disk_operation(0x02, &mut mem);
+    {
+        let m = machine::builder().disk_a(&mut dsk).build();
+        save_state::save(state.path(), &busy_cpu(), &m).unwrap();
+    }
+
+    let mut restored = ram_disk::make(1, 1, 2, 128);
+    {
+        let mut m = machine::builder().disk_a(&mut restored).build();
+        save_state::restore(state.path(), &mut z80::make(0), &mut m).unwrap();
+    }
+    assert_eq!(restored.contents(), dsk.contents());
+    assert_eq!(&restored.contents()[128..], &[0xA5; 128][..]);
+}
+
+// A save state in the format of `version`, for a machine without devices.
+
+fn old_state(version: u16, cpu: &Z80) -> Vec<u8>
+{
+    let mut out = b"Z80EMUST".to_vec();
+    out.extend_from_slice(&version.to_le_bytes());
+    for r in &[cpu.pc, cpu.sp, cpu.ix, cpu.iy] {
+        out.extend_from_slice(&r.to_le_bytes());
+    }
+    out.extend_from_slice(&[cpu.a, cpu.f, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l]);
+    out.extend_from_slice(&[cpu.a_alt, cpu.f_alt, cpu.b_alt, cpu.c_alt,
+                            cpu.d_alt, cpu.e_alt, cpu.h_alt, cpu.l_alt]);
+    out.push(0);                            // Halt
+    out.push(cpu.port_addr);
+    if version >= 2 {
+        out.extend_from_slice(&[cpu.i, cpu.r, cpu.im, cpu.iff1 as u8, cpu.iff2 as u8]);
+    }
+    if version >= 3 {
+        out.extend_from_slice(&cpu.cycles.to_le_bytes());
+    }
+    if version >= 4 {
+        out.push(0);
+    }
+    out.extend_from_slice(&cpu.mem);
+    let sections = match version { 1..=5 => 2, 6 => 17, _ => 18 };
+    for _ in 0..sections {
+        out.extend_from_slice(&0u32.to_le_bytes());
+    }
+    if version >= 6 {
+        out.push(0);
+    }
+    out
+}
+
+fn restore(data: &[u8]) -> Result<Z80, String>
+{
+    let file = temp_file::make(data);
+    let mut cpu = z80::make(0);
+    let mut m = machine::builder().build();
+    save_state::restore(file.path(), &mut cpu, &mut m).map_err(|e| e.to_string())?;
+    Ok(cpu)
+}
+
+#[test]
+fn older_versions()
+{
+    let mut cpu = busy_cpu();
+    cpu.stop_reason = StopReason::Halt;
+    for version in 1..=7 {
+        let restored = restore(&old_state(version, &cpu)).unwrap_or_else(|e| panic!("Version {}: {}", version, e));
+        let mut expected = registers(&cpu);
+        if version < 2 {
+            for r in &mut expected[21..26] { *r = 0; }
+        }
+        if version < 3 {
+            expected[26] = 0;
+        }
+        assert_eq!(registers(&restored), expected, "Version {}", version);
+        assert_eq!(restored.stop_reason, StopReason::Halt);
+        assert!(restored.mem[..] == cpu.mem[..]);
+    }
+}
+
+#[test]
+fn current_version_is_the_latest_format()
+{
+    let cpu = busy_cpu();
+    let state = temp_file::make(&[]);
+    save_state::save(state.path(), &cpu, &machine::builder().build()).unwrap();
+    let mut expected = old_state(7, &cpu);
+    expected[34] = 5;                       // Trap
+    expected[34 + 2 + 5 + 8] = 0x2A;        //   and its number, after i to cycles
+    assert_eq!(state.contents(), expected);
+}
+
+#[test]
+fn bad_files()
+{
+    let state = old_state(7, &busy_cpu());
+    let error = |data: &[u8]| restore(data).err().expect("The state was accepted");
+
+    let mut bad_magic = state.clone();
+    bad_magic[0] = b'X';
+    assert!(error(&bad_magic).contains("not a save state"));
+
+    for &version in &[0u16, 8] {
+        let mut bad_version = state.clone();
+        bad_version[8..10].copy_from_slice(&version.to_le_bytes());
+        assert!(error(&bad_version).contains("unsupported version"));
+    }
+
+    let mut trailing = state.clone();
+    trailing.push(0);
+    assert!(error(&trailing).contains("trailing data"));
+
+    assert!(restore(&state[..state.len() - 1]).is_err());
+    assert!(restore(&state[..100]).is_err());
+
+    // A device state for a device the machine does not have
+    let mut extra = state.clone();
+    let tty = state.len() - 18 * 4 - 1;
+    extra[tty..tty + 4].copy_from_slice(&1u32.to_le_bytes());
+    extra.insert(tty + 4, 0);
+    assert!(error(&extra).contains("bad TTY state"));
+}
+
+// A bad file, whether the CPU state, a device state, or what follows is bad,
+// leaves the CPU and the devices as they were.
+
+#[test]
+fn bad_files_change_nothing()
+{
+    let image = temp_file::make(&[0; 4 * 128]);
+    let state = temp_file::make(&[]);
+    let mut mem = vec![0; 65536];
+    let mut dsk_a = file_backed_spinning_disk::make(image.path(), 1, 1, 4);
+    let mut dsk_b = file_backed_spinning_disk::make(image.path(), 1, 1, 4);
+    dsk_a.set_sector(3);
+    dsk_a.disk_operation(0x03, &mut mem);
+    dsk_a.disk_operation(0x00, &mut mem);
+    {
+        let m = machine::builder().disk(0, &mut dsk_a).disk(1, &mut dsk_b).build();
+        save_state::save(state.path(), &busy_cpu(), &m).unwrap();
+    }
+    let good = state.contents();
+    let disk_b = good.len() - 1 - 4 - 14 * 4 - 28;
+
+    let mut bad_stop_reason = good.clone();
+    bad_stop_reason[34] = 99;
+    let mut bad_disk_b = good.clone();
+    bad_disk_b[disk_b + 13] = 0x55;         // Status
+    let mut bad_selected_disk = good.clone();
+    *bad_selected_disk.last_mut().unwrap() = 16;
+    let mut trailing = good.clone();
+    trailing.push(0);
+
+    let cases = [(bad_stop_reason, "bad stop reason"), (bad_disk_b, "bad state for disk B:"),
+                 (bad_selected_disk, "bad selected disk"), (trailing, "trailing data")];
+    for &(ref bad, error) in &cases {
+        let file = temp_file::make(bad);
+        let mut cpu = z80::make(0x4000);
+        cpu.mem[0x4000] = 0x76;
+        let mut dsk_a = file_backed_spinning_disk::make(image.path(), 1, 1, 4);
+        let mut dsk_b = file_backed_spinning_disk::make(image.path(), 1, 1, 4);
+        let mut before = vec![];
+        dsk_a.save_state(&mut before);
+        {
+            let mut m = machine::builder().disk(0, &mut dsk_a).disk(1, &mut dsk_b).build();
+            let e = save_state::restore(file.path(), &mut cpu, &mut m).expect_err("The state was accepted");
+            assert!(e.to_string().contains(error), "{}", e);
+        }
+        assert_eq!(registers(&cpu), registers(&z80::make(0x4000)));
+        assert_eq!(cpu.mem[0x4000], 0x76);
+        let mut after = vec![];
+        dsk_a.save_state(&mut after);
+        assert_eq!(after, before);
+    }
+}
+
+#[test]
+fn missing_file()
+{
+    let mut cpu = z80::make(0);
+    let mut m = machine::builder().build();
+    assert!(save_state::restore("no-such-save-state", &mut cpu, &mut m).is_err());
+}
@@ -0,0 +1,287 @@
+// Tests of the .SNA and .Z80 snapshot formats: round trips through each
+// format, hand-built files, and rejecting bad files.
+
+#![cfg(feature = "std")]
+
+extern crate z80emu;
+
+mod common;
+
+use common::temp_file;
+use z80emu::snapshot::{self, Z80Version};
+use z80emu::z80::{self, Z80};
+use z80emu::rng;
+
+// A CPU with random registers and memory, with SP in RAM so that a 48K .SNA
+// can push PC.
+
+fn busy_cpu() -> Z80
+{
+    let mut cpu = z80::power_on(&mut rng::make(11), true);
+    cpu.pc = 0x8123;
+    cpu.sp = 0xC456;
+    cpu.i = 0x3F;
+    cpu.r = 0xC5;
+    cpu.im = 2;
+    cpu.iff1 = true;
+    cpu.iff2 = true;
+    cpu
+}
+
+fn registers(cpu: &Z80) -> Vec<u16>
+{
+    vec![cpu.pc, cpu.sp, cpu.ix, cpu.iy,
+         cpu.a as u16, cpu.f as u16, cpu.b as u16, cpu.c as u16, cpu.d as u16, cpu.e as u16,
+         cpu.h as u16, cpu.l as u16, cpu.a_alt as u16, cpu.f_alt as u16, cpu.b_alt as u16,
+         cpu.c_alt as u16, cpu.d_alt as u16, cpu.e_alt as u16, cpu.h_alt as u16, cpu.l_alt as u16,
+         cpu.i as u16, cpu.r as u16, cpu.im as u16, cpu.iff1 as u16, cpu.iff2 as u16]
+}
+
+// A .Z80 version 1 header for a CPU with PC 0x8000 and everything else zero.
+
+fn z80_v1_header(compressed: bool) -> Vec<u8>
+{
+    let mut h = vec![0; 30];
+    h[7] = 0x80;
+    h[12] = if compressed { 0x20 } else { 0x00 };
+    h
+}
+
+// A .Z80 version 3 header, with its extra header, for `hw_mode` with
+// `port_7ffd` last written to port 0x7FFD.
+
+fn z80_v3_header(hw_mode: u8, port_7ffd: u8) -> Vec<u8>
+{
+    let mut h = vec![0; 30];
+    h.extend_from_slice(&[54, 0]);
+    let mut ext = vec![0; 54];
+    ext[1] = 0x80;
+    ext[2] = hw_mode;
+    ext[3] = port_7ffd;
+    h.extend_from_slice(&ext);
+    h
+}
+
+fn page(number: u8, data: &[u8]) -> Vec<u8>
+{
+    let mut out = vec![data.len() as u8, (data.len() >> 8) as u8, number];
+    out.extend_from_slice(data);
+    out
+}
+
+fn uncompressed_page(number: u8, fill: u8) -> Vec<u8>
+{
+    let mut out = vec![0xFF, 0xFF, number];
+    out.extend_from_slice(&[fill; 0x4000]);
+    out
+}
+
+fn load_z80(data: &[u8]) -> Result<Z80, String>
+{
+    let mut cpu = z80::make(0);
+    snapshot::load_z80(data, &mut cpu).map_err(|e| e.to_string())?;
+    Ok(cpu)
+}
+
+#[test]
+fn sna48_round_trip()
+{
+    let cpu = busy_cpu();
+    let data = snapshot::save_sna48(&cpu);
+    assert_eq!(data.len(), 27 + 0xC000);
+    let mut loaded = z80::make(0);
+    snapshot::load_sna(&data, &mut loaded).unwrap();
+    assert_eq!(registers(&loaded), registers(&cpu));
+
+    // PC was pushed below SP in the image, but not in the CPU's memory
+    let sp = cpu.sp as usize;
+    assert_eq!(&loaded.mem[sp - 2..sp], &[0x23, 0x81]);
+    assert!(loaded.mem[0x4000..sp - 2] == cpu.mem[0x4000..sp - 2]);
+    assert!(loaded.mem[sp..] == cpu.mem[sp..]);
+    assert!(loaded.mem[..0x4000].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn sna128_round_trip()
+{
+    let cpu = busy_cpu();
+    let data = snapshot::save_sna128(&cpu);
+    assert_eq!(data.len(), 27 + 0xC000 + 4 + 5 * 0x4000);
+    let mut loaded = z80::make(0);
+    snapshot::load_sna(&data, &mut loaded).unwrap();
+    assert_eq!(registers(&loaded), registers(&cpu));
+    assert!(loaded.mem[0x4000..] == cpu.mem[0x4000..]);
+
+    // With all eight banks in the file
+    let mut all_banks = data.clone();
+    all_banks.extend_from_slice(&[0; 0x4000]);
+    let mut loaded = z80::make(0);
+    snapshot::load_sna(&all_banks, &mut loaded).unwrap();
+    assert_eq!(registers(&loaded), registers(&cpu));
+}
+
+#[test]
+fn z80_round_trips()
+{
+    let cpu = busy_cpu();
+    for &version in &[Z80Version::V1, Z80Version::V2, Z80Version::V3] {
+        let loaded = load_z80(&snapshot::save_z80(&cpu, version))
+            .unwrap_or_else(|e| panic!("{:?}: {}", version, e));
+        assert_eq!(registers(&loaded), registers(&cpu), "{:?}", version);
+        assert!(loaded.mem[0x4000..] == cpu.mem[0x4000..], "{:?}", version);
+    }
+}
+
+// Compression of runs of zeroes, EDs, and bytes following a single ED.
+
+#[test]
+fn z80_compression()
+{
+    // A version 1 file cannot have PC 0, which marks versions 2 and 3
+    let mut cpu = z80::make(0);
+    cpu.pc = 0x8000;
+    cpu.mem[0x4000] = 0xED;
+    cpu.mem[0x4001] = 0x01;
+    cpu.mem[0x4002..0x4004].copy_from_slice(&[0xED, 0xED]);
+    cpu.mem[0x5000..0x5010].copy_from_slice(&[0x42; 16]);
+    let data = snapshot::save_z80(&cpu, Z80Version::V1);
+    assert!(data.len() < 1000);
+    assert!(data.ends_with(&[0x00, 0xED, 0xED, 0x00]));
+    let loaded = load_z80(&data).unwrap();
+    assert!(loaded.mem[0x4000..] == cpu.mem[0x4000..]);
+}
+
+#[test]
+fn z80_v1_known_stream()
+{
+    // 01, a single ED followed by 05, and three 41s
+    let mut data = z80_v1_header(true);
+    data.extend_from_slice(&[0x01, 0xED, 0x05, 0xED, 0xED, 0x03, 0x41]);
+    let mut left = 0xC000 - 6;
+    while left > 0 {
+        let n = left.min(255);
+        data.extend_from_slice(&[0xED, 0xED, n as u8, 0x00]);
+        left -= n;
+    }
+    data.extend_from_slice(&[0x00, 0xED, 0xED, 0x00]);
+
+    let cpu = load_z80(&data).unwrap();
+    assert_eq!(cpu.pc, 0x8000);
+    assert_eq!(&cpu.mem[0x4000..0x4006], &[0x01, 0xED, 0x05, 0x41, 0x41, 0x41]);
+    assert!(cpu.mem[0x4006..].iter().all(|&b| b == 0));
+
+    // Without the end marker
+    assert!(load_z80(&data[..data.len() - 4]).is_ok());
+
+    // Uncompressed
+    let mut data = z80_v1_header(false);
+    data.extend_from_slice(&[0x77; 0xC000]);
+    let cpu = load_z80(&data).unwrap();
+    assert!(cpu.mem[0x4000..].iter().all(|&b| b == 0x77));
+}
+
+#[test]
+fn z80_128k_paging()
+{
+    // Bank 1 paged in at 0xC000; pages 3-10 hold banks 0-7
+    let mut data = z80_v3_header(4, 0x01);
+    for bank in 0..8 {
+        data.extend_from_slice(&uncompressed_page(bank + 3, bank));
+    }
+    let cpu = load_z80(&data).unwrap();
+    assert_eq!(cpu.pc, 0x8000);
+    assert!(cpu.mem[0x4000..0x8000].iter().all(|&b| b == 5));
+    assert!(cpu.mem[0x8000..0xC000].iter().all(|&b| b == 2));
+    assert!(cpu.mem[0xC000..].iter().all(|&b| b == 1));
+
+    // The same pages in 48K mode: 8, 4, and 5 are 0x4000, 0x8000, and 0xC000
+    let mut data = z80_v3_header(0, 0x00);
+    for bank in 0..8 {
+        data.extend_from_slice(&uncompressed_page(bank + 3, bank));
+    }
+    let cpu = load_z80(&data).unwrap();
+    assert!(cpu.mem[0x4000..0x8000].iter().all(|&b| b == 5));
+    assert!(cpu.mem[0x8000..0xC000].iter().all(|&b| b == 1));
+    assert!(cpu.mem[0xC000..].iter().all(|&b| b == 2));
+}
+
+#[test]
+fn bad_z80_files()
+{
+    let error = |data: &[u8]| load_z80(data).err().expect("The snapshot was accepted");
+
+    assert!(error(&[0; 29]).contains("too short"));
+
+    // Version 1 decompressing to too much or too little memory
+    let mut too_much = z80_v1_header(true);
+    for _ in 0..193 {
+        too_much.extend_from_slice(&[0xED, 0xED, 0xFF, 0x00]);
+    }
+    assert!(error(&too_much).contains("too much compressed data"));
+    let mut too_little = z80_v1_header(true);
+    too_little.extend_from_slice(&[0xED, 0xED, 0xFF, 0x00]);
+    assert!(error(&too_little).contains("wrong amount of memory"));
+    let mut truncated = z80_v1_header(true);
+    truncated.extend_from_slice(&[0xED, 0xED, 0x05]);
+    assert!(error(&truncated).contains("truncated compressed data"));
+
+    let mut unknown = z80_v3_header(0, 0);
+    unknown[30] = 40;
+    assert!(error(&unknown).contains("unknown version"));
+
+    // Pages that are cut short, or decompress to the wrong size
+    let mut data = z80_v3_header(0, 0);
+    data.extend_from_slice(&uncompressed_page(8, 0));
+    data.pop();
+    assert!(error(&data).contains("truncated page"));
+    let mut data = z80_v3_header(0, 0);
+    data.extend_from_slice(&page(8, &[0xED, 0xED, 0x10, 0x00]));
+    data.pop();
+    assert!(error(&data).contains("truncated page"));
+    let mut data = z80_v3_header(0, 0);
+    data.extend_from_slice(&[0x04, 0x00]);
+    assert!(error(&data).contains("truncated page"));
+    let mut data = z80_v3_header(0, 0);
+    data.extend_from_slice(&page(8, &[0xED, 0xED, 0x10, 0x00]));
+    assert!(error(&data).contains("wrong size"));
+    let mut data = z80_v3_header(0, 0);
+    data.extend_from_slice(&page(8, &[0xED, 0xED, 0xFF, 0x00].repeat(65)));
+    assert!(error(&data).contains("too much compressed data"));
+}
+
+#[test]
+fn bad_sna_files()
+{
+    let error = |data: &[u8]| {
+        let mut cpu = z80::make(0);
+        snapshot::load_sna(data, &mut cpu).expect_err("The snapshot was accepted").to_string()
+    };
+    let data = snapshot::save_sna128(&busy_cpu());
+    assert!(error(&data[..27 + 0xC000 - 1]).contains("too short"));
+    assert!(error(&data[..27 + 0xC000 + 3]).contains("bad size"));
+    assert!(error(&data[..data.len() - 1]).contains("bad size"));
+    assert!(error(&data[..data.len() - 0x4000]).contains("bad size"));
+}
+
+#[test]
+fn files_by_extension()
+{
+    let cpu = busy_cpu();
+    let dir = temp_file::make_dir();
+    let path = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+    for &(name, sna_128k, size) in &[("snap.sna", false, 27 + 0xC000),
+                                     ("SNAP.SNA", true, 27 + 0xC000 + 4 + 5 * 0x4000),
+                                     ("snap.z80", false, 0)] {
+        snapshot::save(&path(name), &cpu, sna_128k).unwrap();
+        if size != 0 {
+            assert_eq!(dir.read(name).len(), size, "{}", name);
+        }
+        let mut loaded = z80::make(0);
+        snapshot::load(&path(name), &mut loaded).unwrap();
+        assert_eq!(registers(&loaded), registers(&cpu), "{}", name);
+    }
+
+    dir.write("snap.tap", &[]);
+    assert!(snapshot::save(&path("snap.tap"), &cpu, false).is_err());
+    assert!(snapshot::load(&path("snap.tap"), &mut z80::make(0)).is_err());
+}
@@ -16,4 +16,5 @@ fn main()
     z.hlt();
 
     z.create_image("a_drive.bin");
+    z.create_source_map("a_drive.map");
 }
@@ -3,7 +3,7 @@ extern crate z80asm;
 use z80asm::*;
 
 const BANNER: &str = "Bleep firmware v0.1\n\n";
-const ORG: u16 = 0x0000;
+const ORG: u16 = 0xFF80;          // The ROM occupies the top 128 bytes of memory
 const LOADADDR: u16 = 0x100;
 
 fn main()
@@ -59,4 +59,5 @@ fn main()
 
     // Write ROM image
     z.create_image("rom.bin");
+    z.create_source_map("rom.map");
 }
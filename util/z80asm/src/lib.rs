@@ -1,8 +1,14 @@
 // Simple object-oriented assembler, assembles into an in-memory
 // buffer.
+//
+// The assembler also records the source location (in the Rust program that
+// drives it) of every instruction, and can write those out as a source map
+// for the emulator's coverage reports.
 
+use std::env;
 use std::fs::File;
 use std::io::Write;
+use std::panic::Location;
 
 // TTY output ports
 pub const CON_OUT:u8 = 0x00;
@@ -28,13 +34,13 @@ pub const A_DISK_OK:u8 = 0x00;
 pub const A_DISK_READY:u8 = 0x01;
 // Error codes are negative
         
-#[allow(dead_code)]             // For "org", at the moment
 pub struct Z80Buf
 {
     buf: Vec<u8>,
     org: u16,
     pos: usize,
     len: usize,
+    lines: Vec<(u16, &'static Location<'static>)>,
 }
 
 impl Z80Buf
@@ -43,7 +49,7 @@ impl Z80Buf
         let mut buf = vec![];
         let len = numsec * 128;
         buf.resize(len, 0);
-        Z80Buf { buf, org, pos: 0, len }
+        Z80Buf { buf, org, pos: 0, len, lines: vec![] }
     }
 
     pub fn create_image(&self, filename:&str) {
@@ -51,29 +57,56 @@ impl Z80Buf
             .write(&self.buf).expect("Failed to write");
     }
 
+    // One line per instruction: address (hex), line number, and source file,
+    // separated by tabs.  File names are made absolute so that the map can be
+    // used from anywhere.
+    pub fn create_source_map(&self, filename:&str) {
+        let cwd = env::current_dir().expect("Failed to get current directory");
+        let mut f = File::create(filename).expect("Failed to create");
+        for &(addr, loc) in &self.lines {
+            writeln!(f, "{:04X}\t{}\t{}", addr, loc.line(), cwd.join(loc.file()).display())
+                .expect("Failed to write");
+        }
+    }
+
     pub fn get_buf(&self) -> &[u8] {
         &self.buf
     }
 
+    #[track_caller]
     pub fn lda(&mut self, n:u8) {
+        self.insn();
         self.put(0x37);
         self.put(n);
     }
 
+    #[track_caller]
     pub fn outa(&mut self, n:u8) {
+        self.insn();
         self.put(0xD3);
         self.put(n);
     }
 
+    #[track_caller]
     pub fn jp(&mut self, n:u16) {
+        self.insn();
         self.put(0xC3);
         self.put16(n);
     }
 
+    #[track_caller]
     pub fn hlt(&mut self) {
+        self.insn();
         self.put(0x76);
     }
 
+    // Record the caller's location as the source of the next instruction.
+    #[track_caller]
+    fn insn(&mut self) {
+        let addr = self.org.wrapping_add(self.pos as u16);
+        self.lines.push((addr, Location::caller()));
+    }
+
     fn put(&mut self, n:u8) {
         if self.pos == self.len {
             panic!("Assembler buffer overflow");